@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use fastly::geo::{geo_lookup, Continent};
+use fastly::Request;
+
+use crate::config::{BackendStrategy, Config};
+use crate::fnv1a;
+
+/// Hosts in S3 that a request can be sent to
+pub struct Candidates<'a> {
+    pub primary_host: &'a String,
+    pub fallback_hosts: &'a [String],
+    /// Whether the primary host must be skipped, for example because the circuit breaker is open
+    pub skip_primary: bool,
+}
+
+/// Strategy for the order in which the hosts in S3 are tried
+///
+/// The request is sent to the first host, and to the following hosts one after another if the
+/// previous ones failed. Strategies must never return the primary host if it must be skipped.
+pub trait BackendSelector {
+    fn select<'a>(&self, request: &Request, candidates: &Candidates<'a>) -> Vec<&'a String>;
+}
+
+/// Get the selector for the configured strategy
+pub fn selector(config: &Config) -> Box<dyn BackendSelector + '_> {
+    match config.backend_strategy {
+        BackendStrategy::PrimaryWithFallback => Box::new(PrimaryWithFallback),
+        BackendStrategy::RotatingFallbacks => Box::new(RotatingFallbacks),
+        BackendStrategy::RegionAffinity => Box::new(RegionAffinity {
+            region_hosts: &config.region_hosts,
+        }),
+    }
+}
+
+fn primary<'a>(candidates: &Candidates<'a>) -> Option<&'a String> {
+    (!candidates.skip_primary).then_some(candidates.primary_host)
+}
+
+/// Try the primary host first, and then the fallback hosts in their configured order
+pub struct PrimaryWithFallback;
+
+impl BackendSelector for PrimaryWithFallback {
+    fn select<'a>(&self, _request: &Request, candidates: &Candidates<'a>) -> Vec<&'a String> {
+        primary(candidates)
+            .into_iter()
+            .chain(candidates.fallback_hosts)
+            .collect()
+    }
+}
+
+/// Try the primary host first, and then the fallback hosts starting at a different one per client
+///
+/// During an outage of the primary bucket, the first fallback would get all requests. Rotating
+/// the fallbacks spreads the requests across them. The first fallback is derived from the IP of the
+/// client, so that each client consistently gets the same one.
+pub struct RotatingFallbacks;
+
+impl BackendSelector for RotatingFallbacks {
+    fn select<'a>(&self, request: &Request, candidates: &Candidates<'a>) -> Vec<&'a String> {
+        rotate_fallbacks(candidates, request.get_client_ip_addr())
+    }
+}
+
+/// Order the hosts with the fallbacks rotated by the hash of the client's IP
+fn rotate_fallbacks<'a>(candidates: &Candidates<'a>, ip: Option<IpAddr>) -> Vec<&'a String> {
+    let fallback_hosts = candidates.fallback_hosts;
+
+    let offset = match ip {
+        Some(ip) if !fallback_hosts.is_empty() => {
+            let hash = match ip {
+                IpAddr::V4(ip) => fnv1a(&ip.octets()),
+                IpAddr::V6(ip) => fnv1a(&ip.octets()),
+            };
+            (hash % fallback_hosts.len() as u64) as usize
+        }
+        _ => 0,
+    };
+
+    primary(candidates)
+        .into_iter()
+        .chain(&fallback_hosts[offset..])
+        .chain(&fallback_hosts[..offset])
+        .collect()
+}
+
+/// Try the host in the region that is nearest to the client first
+///
+/// The continent of the client is looked up with Fastly's geolocation, and mapped to a host with
+/// the configured region hosts. The nearest host is tried first, and the other hosts afterwards in
+/// the order of `PrimaryWithFallback`. Clients whose continent is unknown or not mapped, and hosts
+/// that are not part of the candidates, for example for another domain, use the default order.
+pub struct RegionAffinity<'c> {
+    pub region_hosts: &'c HashMap<String, String>,
+}
+
+impl BackendSelector for RegionAffinity<'_> {
+    fn select<'a>(&self, request: &Request, candidates: &Candidates<'a>) -> Vec<&'a String> {
+        let continent = request
+            .get_client_ip_addr()
+            .and_then(geo_lookup)
+            .map(|geo| geo.continent());
+
+        self.prefer_nearest(PrimaryWithFallback.select(request, candidates), continent)
+    }
+}
+
+impl RegionAffinity<'_> {
+    /// Move the host in the region of the client's continent to the front
+    fn prefer_nearest<'a>(
+        &self,
+        mut hosts: Vec<&'a String>,
+        continent: Option<Continent>,
+    ) -> Vec<&'a String> {
+        let nearest_host = continent
+            .and_then(continent_code)
+            .and_then(|continent| self.region_hosts.get(continent));
+
+        if let Some(position) =
+            nearest_host.and_then(|nearest| hosts.iter().position(|host| *host == nearest))
+        {
+            let nearest_host = hosts.remove(position);
+            hosts.insert(0, nearest_host);
+        }
+
+        hosts
+    }
+}
+
+fn continent_code(continent: Continent) -> Option<&'static str> {
+    match continent {
+        Continent::Africa => Some("AF"),
+        Continent::Antarctica => Some("AN"),
+        Continent::Asia => Some("AS"),
+        Continent::Europe => Some("EU"),
+        Continent::NorthAmerica => Some("NA"),
+        Continent::Oceania => Some("OC"),
+        Continent::SouthAmerica => Some("SA"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts() -> (String, Vec<String>) {
+        (
+            "primary".into(),
+            vec![
+                "fallback-1".into(),
+                "fallback-2".into(),
+                "fallback-3".into(),
+            ],
+        )
+    }
+
+    fn request() -> Request {
+        Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+    }
+
+    #[test]
+    fn primary_with_fallback_keeps_configured_order() {
+        let (primary_host, fallback_hosts) = hosts();
+        let candidates = Candidates {
+            primary_host: &primary_host,
+            fallback_hosts: &fallback_hosts,
+            skip_primary: false,
+        };
+
+        assert_eq!(
+            PrimaryWithFallback.select(&request(), &candidates),
+            ["primary", "fallback-1", "fallback-2", "fallback-3"]
+        );
+    }
+
+    #[test]
+    fn primary_with_fallback_skips_primary() {
+        let (primary_host, fallback_hosts) = hosts();
+        let candidates = Candidates {
+            primary_host: &primary_host,
+            fallback_hosts: &fallback_hosts,
+            skip_primary: true,
+        };
+
+        assert_eq!(
+            PrimaryWithFallback.select(&request(), &candidates),
+            ["fallback-1", "fallback-2", "fallback-3"]
+        );
+    }
+
+    #[test]
+    fn rotating_fallbacks_are_consistent_per_client() {
+        let (primary_host, fallback_hosts) = hosts();
+        let candidates = Candidates {
+            primary_host: &primary_host,
+            fallback_hosts: &fallback_hosts,
+            skip_primary: false,
+        };
+        let ip = Some("192.0.2.1".parse().unwrap());
+
+        let selected = rotate_fallbacks(&candidates, ip);
+
+        assert_eq!(selected[0], "primary");
+        assert_eq!(selected, rotate_fallbacks(&candidates, ip));
+
+        // Every fallback is still tried, in their configured order shifted by an offset
+        let offset = fallback_hosts
+            .iter()
+            .position(|host| host == selected[1])
+            .unwrap();
+        for (index, host) in selected[1..].iter().enumerate() {
+            assert_eq!(
+                **host,
+                fallback_hosts[(offset + index) % fallback_hosts.len()]
+            );
+        }
+    }
+
+    #[test]
+    fn rotating_fallbacks_spread_clients() {
+        let (primary_host, fallback_hosts) = hosts();
+        let candidates = Candidates {
+            primary_host: &primary_host,
+            fallback_hosts: &fallback_hosts,
+            skip_primary: true,
+        };
+
+        let first_fallbacks: std::collections::HashSet<&String> = (0..=255u8)
+            .map(|octet| {
+                let ip = IpAddr::from([192, 0, 2, octet]);
+                rotate_fallbacks(&candidates, Some(ip))[0]
+            })
+            .collect();
+
+        assert_eq!(first_fallbacks.len(), fallback_hosts.len());
+    }
+
+    #[test]
+    fn rotating_fallbacks_keep_order_without_client_ip() {
+        let (primary_host, fallback_hosts) = hosts();
+        let candidates = Candidates {
+            primary_host: &primary_host,
+            fallback_hosts: &fallback_hosts,
+            skip_primary: false,
+        };
+
+        assert_eq!(
+            rotate_fallbacks(&candidates, None),
+            ["primary", "fallback-1", "fallback-2", "fallback-3"]
+        );
+    }
+
+    fn region_hosts() -> HashMap<String, String> {
+        HashMap::from([
+            ("EU".into(), "fallback-2".into()),
+            ("NA".into(), "primary".into()),
+            ("AS".into(), "other-domain".into()),
+        ])
+    }
+
+    fn nearest_first(continent: Option<Continent>) -> Vec<String> {
+        let (primary_host, fallback_hosts) = hosts();
+        let region_hosts = region_hosts();
+        let selector = RegionAffinity {
+            region_hosts: &region_hosts,
+        };
+        let hosts = vec![&primary_host, &fallback_hosts[0], &fallback_hosts[1]];
+
+        selector
+            .prefer_nearest(hosts, continent)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn region_affinity_prefers_host_of_known_region() {
+        assert_eq!(
+            nearest_first(Some(Continent::Europe)),
+            ["fallback-2", "primary", "fallback-1"]
+        );
+        assert_eq!(
+            nearest_first(Some(Continent::NorthAmerica)),
+            ["primary", "fallback-1", "fallback-2"]
+        );
+    }
+
+    #[test]
+    fn region_affinity_uses_default_order_for_unknown_regions() {
+        // Africa is not mapped, and Asia is mapped to a host that is not a candidate
+        for continent in [None, Some(Continent::Africa), Some(Continent::Asia)] {
+            assert_eq!(
+                nearest_first(continent),
+                ["primary", "fallback-1", "fallback-2"]
+            );
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::str::FromStr;
+
+/// Precedence between the `Cache-Control` header from S3 and the configured TTL
+///
+/// The TTL at Fastly's edge is set on the request, before the response from S3 is known. This
+/// version of the SDK cannot change it afterwards, so the TTL can either be overridden or be left
+/// to Fastly, but not be computed from both. Precedences like the shorter or longer of the two are
+/// therefore not supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CachePrecedence {
+    /// Let Fastly cache the response for the `max-age` from S3
+    OriginWins,
+    /// Always use the configured TTL
+    ConfigWins,
+}
+
+impl FromStr for CachePrecedence {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "origin_wins" => Ok(Self::OriginWins),
+            "config_wins" => Ok(Self::ConfigWins),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Get the maximum age from a `Cache-Control` header
+///
+/// `s-maxage` applies to shared caches like Fastly, and takes precedence over `max-age`. Returns
+/// `None` if the header has neither directive or if their values are malformed.
+pub fn max_age(cache_control: &str) -> Option<u32> {
+    let directive = |name: &str| {
+        cache_control
+            .split(',')
+            .filter_map(|directive| directive.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| value.trim().trim_matches('"').parse().ok())
+    };
+
+    directive("s-maxage").or_else(|| directive("max-age"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precedence_is_parsed() {
+        assert_eq!("origin_wins".parse(), Ok(CachePrecedence::OriginWins));
+        assert_eq!("config_wins".parse(), Ok(CachePrecedence::ConfigWins));
+        assert_eq!("origin".parse::<CachePrecedence>(), Err(()));
+    }
+
+    #[test]
+    fn unsupported_precedences_are_rejected() {
+        assert_eq!("min".parse::<CachePrecedence>(), Err(()));
+        assert_eq!("max".parse::<CachePrecedence>(), Err(()));
+    }
+
+    #[test]
+    fn max_age_is_read_from_cache_control() {
+        assert_eq!(max_age("public, max-age=600"), Some(600));
+        assert_eq!(max_age("Max-Age = \"600\""), Some(600));
+        assert_eq!(max_age("max-age=600, s-maxage=60"), Some(60));
+    }
+
+    #[test]
+    fn max_age_is_missing_without_a_valid_directive() {
+        assert_eq!(max_age("public, immutable"), None);
+        assert_eq!(max_age("max-age=forever"), None);
+        assert_eq!(max_age(""), None);
+    }
+}
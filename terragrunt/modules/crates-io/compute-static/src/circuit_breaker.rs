@@ -0,0 +1,213 @@
+use time::OffsetDateTime;
+
+use crate::config::Config;
+use crate::shared_state::{SharedState, Stored};
+
+// Key in the object store under which the state of the circuit breaker is saved
+const STATE_KEY: &str = "primary-host";
+
+/// State of the circuit breaker
+///
+/// The state is saved as a string in the format `<consecutive failures>,<unix timestamp when the
+/// breaker was opened>`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct State {
+    pub consecutive_failures: u32,
+    pub opened_at: Option<i64>,
+}
+
+impl Stored for State {
+    fn parse(value: &str) -> Option<Self> {
+        let (consecutive_failures, opened_at) = value.split_once(',')?;
+
+        let opened_at = match opened_at {
+            "" => None,
+            timestamp => Some(timestamp.parse().ok()?),
+        };
+
+        Some(Self {
+            consecutive_failures: consecutive_failures.parse().ok()?,
+            opened_at,
+        })
+    }
+
+    fn serialize(&self) -> String {
+        let opened_at = self
+            .opened_at
+            .map(|timestamp| timestamp.to_string())
+            .unwrap_or_default();
+
+        format!("{},{}", self.consecutive_failures, opened_at)
+    }
+}
+
+impl State {
+    /// Check if the breaker is open and the primary host should be skipped
+    pub fn is_open(&self, now: i64, cooldown: u32) -> bool {
+        self.opened_at
+            .is_some_and(|opened_at| now < opened_at + i64::from(cooldown))
+    }
+
+    /// Record a failed request to the primary host
+    ///
+    /// The breaker opens once the number of consecutive failures reaches the threshold. When the
+    /// first request after the cooldown fails again, the breaker is opened for another cooldown.
+    pub fn record_failure(self, now: i64, threshold: u32) -> Self {
+        let consecutive_failures = self.consecutive_failures.saturating_add(1);
+
+        let opened_at = if consecutive_failures >= threshold {
+            Some(now)
+        } else {
+            self.opened_at
+        };
+
+        Self {
+            consecutive_failures,
+            opened_at,
+        }
+    }
+}
+
+/// Circuit breaker for the primary host
+///
+/// During a sustained outage of the primary bucket, every request would first wait for the primary
+/// host to fail before it is sent to a fallback. The circuit breaker counts consecutive server
+/// errors from the primary host, and skips it for a cooldown period once a threshold is crossed.
+///
+/// The state is shared between all instances of the function, so the breaker might open or close a
+/// few requests late in other locations.
+pub struct CircuitBreaker {
+    store: SharedState,
+    threshold: u32,
+    cooldown: u32,
+    state: State,
+}
+
+impl CircuitBreaker {
+    /// Load the circuit breaker
+    ///
+    /// Returns `None` if the circuit breaker has not been configured, or if the object store cannot
+    /// be opened.
+    pub fn load(config: &Config) -> Option<Self> {
+        let store_name = config.circuit_breaker_store.as_ref()?;
+
+        if config.circuit_breaker_threshold == 0 {
+            return None;
+        }
+
+        let store = SharedState::open(store_name, "the circuit breaker")?;
+        let state = store.load(STATE_KEY).unwrap_or_default();
+
+        Some(Self {
+            store,
+            threshold: config.circuit_breaker_threshold,
+            cooldown: config.circuit_breaker_cooldown,
+            state,
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.is_open(now(), self.cooldown)
+    }
+
+    pub fn record_success(&mut self) {
+        // Avoid writing to the object store on every successful request
+        if self.state != State::default() {
+            self.save(State::default());
+        }
+    }
+
+    pub fn record_failure(&mut self) {
+        self.save(self.state.record_failure(now(), self.threshold));
+    }
+
+    fn save(&mut self, state: State) {
+        self.state = state;
+        self.store.save(STATE_KEY, &state);
+    }
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: u32 = 3;
+    const COOLDOWN: u32 = 30;
+
+    fn fail(state: State, times: u32, now: i64) -> State {
+        (0..times).fold(state, |state, _| state.record_failure(now, THRESHOLD))
+    }
+
+    #[test]
+    fn breaker_stays_closed_below_threshold() {
+        let state = fail(State::default(), THRESHOLD - 1, 100);
+
+        assert!(!state.is_open(100, COOLDOWN));
+    }
+
+    #[test]
+    fn breaker_opens_at_threshold() {
+        let state = fail(State::default(), THRESHOLD, 100);
+
+        assert_eq!(state.opened_at, Some(100));
+        assert!(state.is_open(100, COOLDOWN));
+        assert!(state.is_open(100 + i64::from(COOLDOWN) - 1, COOLDOWN));
+    }
+
+    #[test]
+    fn breaker_closes_after_cooldown() {
+        let state = fail(State::default(), THRESHOLD, 100);
+
+        assert!(!state.is_open(100 + i64::from(COOLDOWN), COOLDOWN));
+    }
+
+    #[test]
+    fn failure_after_cooldown_opens_breaker_again() {
+        let reopened_at = 100 + i64::from(COOLDOWN);
+        let state = fail(State::default(), THRESHOLD, 100).record_failure(reopened_at, THRESHOLD);
+
+        assert_eq!(state.opened_at, Some(reopened_at));
+        assert!(state.is_open(reopened_at, COOLDOWN));
+    }
+
+    #[test]
+    fn state_round_trips_through_store() {
+        for state in [
+            State::default(),
+            State {
+                consecutive_failures: 4,
+                opened_at: Some(1_700_000_000),
+            },
+        ] {
+            assert_eq!(State::parse(&state.serialize()), Some(state));
+        }
+    }
+
+    #[test]
+    fn malformed_state_is_ignored() {
+        for value in ["", "3", "a,1", "3,b", "-1,"] {
+            assert_eq!(State::parse(value), None, "value: {value:?}");
+        }
+    }
+
+    #[test]
+    fn breaker_is_shared_through_store() {
+        let config = Config::for_tests(&[
+            ("circuit-breaker-store", "circuit-breaker"),
+            ("circuit-breaker-threshold", "2"),
+        ]);
+
+        let mut circuit_breaker = CircuitBreaker::load(&config).unwrap();
+        circuit_breaker.record_failure();
+        circuit_breaker.record_failure();
+
+        assert!(CircuitBreaker::load(&config).unwrap().is_open());
+
+        CircuitBreaker::load(&config).unwrap().record_success();
+        assert!(!CircuitBreaker::load(&config).unwrap().is_open());
+    }
+}
@@ -1,73 +1,1720 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use fastly::config_store::OpenError;
+use fastly::http::{HeaderName, HeaderValue, Method, StatusCode};
 use fastly::ConfigStore;
 
+use crate::cache_policy::CachePrecedence;
+use crate::ip_network::IpNetwork;
+
 // Name of the dictionary. Must match the dictionary in `fastly-static.tf`.
 const DICTIONARY_NAME: &str = "compute_static";
 
+// Name of the dictionary item with the version of the dictionary's schema
+const CONFIG_VERSION: &str = "config-version";
+
+// Current version of the dictionary's schema
+const CURRENT_CONFIG_VERSION: u32 = 2;
+
+// Items that have been renamed, with the version that renamed them, the old name, and the new name
+const RENAMED_ITEMS: [(u32, &str, &str); 1] = [(2, "ttl", STATIC_TTL)];
+
 // Name of the dictionary item with the CloudFront URL
 const CLOUDFRONT_URL: &str = "cloudfront-url";
 
+// Name of the dictionary item with the comma-separated path suffixes that get redirected to
+// CloudFront
+const CLOUDFRONT_REDIRECT_SUFFIXES: &str = "cloudfront-redirect-suffixes";
+
 // Name of the dictionary item with the name of the primary host.
 const PRIMARY_HOST: &str = "s3-primary-host";
 
-// Name of the dictionary item with the name of the fallback host.
-const FALLBACK_HOST: &str = "s3-fallback-host";
+// Name of the dictionary item with the comma-separated names of the fallback hosts.
+const FALLBACK_HOSTS: &str = "s3-fallback-host";
 
 // Name of the dictionary item with the TTL for the static bucket
 const STATIC_TTL: &str = "static-ttl";
 
+// Name of the dictionary item with the TTL overrides for path prefixes
+const TTL_OVERRIDES: &str = "ttl-overrides";
+
+// Name of the dictionary item with the time in seconds that stale content is served while it is
+// being revalidated
+const STALE_WHILE_REVALIDATE: &str = "stale-while-revalidate";
+
+// Name of the dictionary item that used to set the time that stale content is served when S3 fails,
+// which is not supported by this version of the SDK
+const STALE_IF_ERROR: &str = "stale-if-error";
+
+// Name of the dictionary item with the paths that are requested most often
+const HOT_PATHS: &str = "hot-paths";
+
+// Name of the dictionary item with the TTL for the hot paths
+const HOT_PATH_TTL: &str = "hot-path-ttl";
+
+// Name of the dictionary item with the TTL for client errors from S3
+const NEGATIVE_TTL: &str = "negative-ttl";
+
+// Default TTL for client errors if the dictionary item is missing or malformed
+const DEFAULT_NEGATIVE_TTL: u32 = 10;
+
+// Default TTL for the static bucket if the dictionary item is missing or malformed
+const DEFAULT_STATIC_TTL: u32 = 86400;
+
 // Name of the directory item with the logging endpoint for requests
 const REQUEST_LOGS_ENDPOINT: &str = "request-logs-endpoint";
 
 // Name of the directory item with the logging endpoint for the worker
 const SERVICE_LOGS_ENDPOINT: &str = "service-logs-endpoint";
 
+// Name of the dictionary item with the comma-separated list of allowed CORS origins
+const CORS_ALLOWED_ORIGINS: &str = "cors-allowed-origins";
+
+// Name of the dictionary item with the version of the schema for request logs
+const LOG_SCHEMA_VERSION: &str = "log-schema-version";
+
+// Name of the dictionary item with the shared secret for purge requests
+const PURGE_TOKEN: &str = "purge-token";
+
+// Name of the dictionary item with the path of the health check
+const HEALTH_CHECK_PATH: &str = "health-check-path";
+
+// Name of the dictionary item with the status code for disallowed request methods
+const METHOD_NOT_ALLOWED_STATUS: &str = "method-not-allowed-status";
+
+// Name of the dictionary item with the maximum length of a request path
+const MAX_PATH_LENGTH: &str = "max-path-length";
+
+// Name of the dictionary item with the name of the object store for the circuit breaker
+const CIRCUIT_BREAKER_STORE: &str = "circuit-breaker-store";
+
+// Name of the dictionary item with the number of consecutive failures that open the circuit breaker
+const CIRCUIT_BREAKER_THRESHOLD: &str = "circuit-breaker-threshold";
+
+// Name of the dictionary item with the time in seconds that the circuit breaker stays open
+const CIRCUIT_BREAKER_COOLDOWN: &str = "circuit-breaker-cooldown";
+
+// Name of the dictionary item with the name of the object store for the fallback limiter
+const FALLBACK_LIMIT_STORE: &str = "fallback-limit-store";
+
+// Name of the dictionary item with the number of requests per window that can use a fallback host
+const FALLBACK_LIMIT: &str = "fallback-limit";
+
+// Name of the dictionary item with the length of the window of the fallback limit in seconds
+const FALLBACK_LIMIT_WINDOW: &str = "fallback-limit-window";
+
+// Name of the dictionary item that enables stripping query strings from requests
+const STRIP_QUERY_STRING: &str = "strip-query-string";
+
+// Name of the dictionary item with the value of the `Retry-After` header when all hosts failed
+const RETRY_AFTER: &str = "retry-after";
+
+// Name of the dictionary item that enables sending HEAD requests as GET requests to S3
+const HEAD_AS_GET: &str = "head-as-get";
+
+// Name of the dictionary item with the fraction of successful requests that are logged
+const LOG_SAMPLE_RATE: &str = "log-sample-rate";
+
+// Name of the dictionary item with the secret that is hashed with the request identifier when the
+// requests are sampled
+const LOG_SAMPLE_SALT: &str = "log-sample-salt";
+
+// Name of the dictionary item with the max-age of the `Strict-Transport-Security` header
+const HSTS_MAX_AGE: &str = "hsts-max-age";
+
+// Name of the dictionary item with the value of the `Referrer-Policy` header
+const REFERRER_POLICY: &str = "referrer-policy";
+
+// Name of the dictionary item with the map of paths that are redirected to other URLs
+const REDIRECT_MAP: &str = "redirect-map";
+
+// Name of the dictionary item that enables the custom body for HTTP 404 responses
+const CUSTOM_404: &str = "custom-404";
+
+// Name of the dictionary item with the comma-separated path prefixes of immutable files
+const IMMUTABLE_PREFIXES: &str = "immutable-prefixes";
+
+// Name of the dictionary item with the max-age in seconds for immutable files in browsers
+const IMMUTABLE_MAX_AGE: &str = "immutable-max-age";
+
+// Name of the dictionary item that enables the maintenance mode
+const MAINTENANCE_MODE: &str = "maintenance-mode";
+
+// Name of the dictionary item with the name of the object store for the latency stats
+const STATS_STORE: &str = "stats-store";
+
+// Name of the dictionary item with the number of requests that are aggregated in a stats line
+const STATS_INTERVAL: &str = "stats-interval";
+
+// Name of the dictionary item with the number of requests per sample that is recorded in the stats
+const STATS_SAMPLE_INTERVAL: &str = "stats-sample-interval";
+
+// Name of the dictionary item with the CloudFront URL that is used when the primary is unhealthy
+const SECONDARY_CLOUDFRONT_URL: &str = "secondary-cloudfront-url";
+
+// Name of the dictionary item that enables probing the primary CloudFront URL before redirecting
+const CLOUDFRONT_PROBE: &str = "cloudfront-probe";
+
+// Name of the dictionary item with the comma-separated response headers that are stripped from the
+// responses of S3. Entries that end with `-` are prefixes, all others are exact header names.
+const STRIPPED_RESPONSE_HEADERS: &str = "stripped-response-headers";
+
+// Name of the dictionary item with the files that are preloaded for path prefixes
+const PRELOAD_MAP: &str = "preload-map";
+
+// Name of the dictionary item with the comma-separated request headers that are part of the cache
+// key
+const CACHE_KEY_HEADERS: &str = "cache-key-headers";
+
+// Name of the dictionary item with the S3 hosts for additional domains
+const HOST_MAP: &str = "host-map";
+
+// Name of the dictionary item with the URL that bare paths are redirected to
+const ROOT_REDIRECT_URL: &str = "root-redirect-url";
+
+// Name of the dictionary item with the comma-separated paths that are redirected to the landing
+// page
+const ROOT_REDIRECT_PATHS: &str = "root-redirect-paths";
+
+// Name of the dictionary item with the maximum size of the request headers in bytes
+const MAX_HEADER_BYTES: &str = "max-header-bytes";
+
+// Name of the dictionary item with the comma-separated path patterns that can be purged
+const PURGE_ALLOWED_PATHS: &str = "purge-allowed-paths";
+
+// Name of the dictionary item with the number of times that transient errors from the primary host
+// are retried
+const PRIMARY_RETRIES: &str = "primary-retries";
+
+// Name of the dictionary item with the backoff in milliseconds between retries of the primary host
+const PRIMARY_RETRY_BACKOFF_MS: &str = "primary-retry-backoff-ms";
+
+// Name of the dictionary item with the content types for path suffixes
+const CONTENT_TYPES: &str = "content-types";
+
+// Name of the dictionary item that enables debug requests
+const DEBUG_ENABLED: &str = "debug-enabled";
+
+// Name of the dictionary item with the token that authorizes debug requests
+const DEBUG_TOKEN: &str = "debug-token";
+
+// Name of the dictionary item with the comma-separated HTTP methods that clients are allowed to use
+const ALLOWED_METHODS: &str = "allowed-methods";
+
+/// Policy for requests with cookies or credentials
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SensitiveHeaderPolicy {
+    /// Strip the headers before the request is forwarded
+    Strip,
+    /// Bypass the cache and forward the headers
+    Bypass,
+}
+
+// Name of the dictionary item with the policy for requests with cookies or credentials
+const SENSITIVE_HEADER_POLICY: &str = "sensitive-header-policy";
+
+// Name of the dictionary item with the timeout in milliseconds for requests to S3
+const BACKEND_TIMEOUT_MS: &str = "backend-timeout-ms";
+
+// Name of the dictionary item with the body of `robots.txt`
+const ROBOTS_TXT: &str = "robots-txt";
+
+// Name of the dictionary item with the size in bytes above which responses are logged as oversized
+const SIZE_WARN_THRESHOLD: &str = "size-warn-threshold";
+
+// Name of the dictionary item that enables synthesized ETags for responses without one
+const SYNTHESIZE_ETAG: &str = "synthesize-etag";
+
+/// Policy for trailing slashes in paths
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailingSlashPolicy {
+    /// Leave paths unchanged
+    Off,
+    /// Redirect paths with a trailing slash to the path without it
+    Strip,
+    /// Redirect directory-like paths without a trailing slash to the path with it
+    Keep,
+}
+
+/// Strategy for the order in which the hosts in S3 are tried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendStrategy {
+    /// Try the primary host, and then the fallbacks in their configured order
+    PrimaryWithFallback,
+    /// Try the primary host, and then the fallbacks starting at a different one per client
+    RotatingFallbacks,
+    /// Try the host in the region nearest to the client, and then the others
+    RegionAffinity,
+}
+
+/// Mode for client IPs in the logs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogIpMode {
+    /// Log the full address
+    Full,
+    /// Log the address with its host part zeroed
+    Masked,
+    /// Omit the address from the logs
+    None,
+}
+
+/// Rate limit for a class of paths, as the number of requests per window in seconds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub window: u32,
+}
+
+// Name of the dictionary item with the name of the object store for the rate limiter
+const RATE_LIMIT_STORE: &str = "rate-limit-store";
+
+// Name of the dictionary item with the rate limits for classes of paths
+const RATE_LIMITS: &str = "rate-limits";
+
+// Name of the dictionary item with the notice about upcoming maintenance
+const MAINTENANCE_NOTICE: &str = "maintenance-notice";
+
+// Name of the dictionary item that enables rejecting empty responses from S3
+const REJECT_EMPTY_RESPONSES: &str = "reject-empty-responses";
+
+// Name of the dictionary item with the policy for trailing slashes in paths
+const TRAILING_SLASH_POLICY: &str = "trailing-slash-policy";
+
+// Name of the dictionary item with the path prefixes that are exempt from the trailing slash policy
+const TRAILING_SLASH_EXEMPT_PREFIXES: &str = "trailing-slash-exempt-prefixes";
+
+// Name of the dictionary item with the logging endpoint for metrics
+const METRICS_ENDPOINT: &str = "metrics-endpoint";
+
+// Name of the dictionary item that enables rejecting GET and HEAD requests with a body
+const REJECT_BODIED_GETS: &str = "reject-bodied-gets";
+
+// Name of the dictionary item with the canonical host that alternate hosts are redirected to
+const CANONICAL_HOST: &str = "canonical-host";
+
+// Name of the dictionary item with the comma-separated request headers that are allowed in CORS
+// requests
+const CORS_ALLOWED_HEADERS: &str = "cors-allowed-headers";
+
+// Name of the dictionary item with the Host headers that are sent to backends
+const BACKEND_HOST_HEADERS: &str = "backend-host-headers";
+
+// Name of the dictionary item with the comma-separated IP addresses and ranges that are blocked
+const IP_DENY_LIST: &str = "ip-deny-list";
+
+// Name of the dictionary item with the mode for client IPs in the logs
+const LOG_IP_MODE: &str = "log-ip-mode";
+
+// Name of the dictionary item with the headers that are added to successful responses
+const RESPONSE_HEADERS: &str = "response-headers";
+
+// Name of the dictionary item with the path prefixes of large objects that advertise byte ranges
+const LARGE_OBJECT_PREFIXES: &str = "large-object-prefixes";
+
+// Name of the dictionary item that enables collapsing concurrent cache misses
+const REQUEST_COLLAPSING: &str = "request-collapsing";
+
+// Name of the dictionary item with the path of the error document for missing files in S3
+const ERROR_DOCUMENT_PATH: &str = "error-document-path";
+
+// Name of the dictionary item with the maximum size of the body of purge requests in bytes
+const MAX_PURGE_BODY_BYTES: &str = "max-purge-body-bytes";
+
+// Name of the dictionary item with the comma-separated status codes that fail over to a fallback
+const FAILOVER_STATUS_CODES: &str = "failover-status-codes";
+
+// Name of the dictionary item with the precedence between `Cache-Control` from S3 and the TTL
+const CACHE_PRECEDENCE: &str = "cache-precedence";
+
+// Name of the dictionary item that enables logging the `User-Agent` header
+const LOG_USER_AGENT: &str = "log-user-agent";
+
+// Name of the dictionary item with the comma-separated patterns of paths that have been removed
+const GONE_PATHS: &str = "gone-paths";
+
+// Name of the dictionary item with the TTL for responses for removed paths
+const GONE_TTL: &str = "gone-ttl";
+
+// Name of the dictionary item with the strategy for the order in which the hosts are tried
+const BACKEND_STRATEGY: &str = "backend-strategy";
+
+// Name of the dictionary item with the hosts in S3 for the continents of clients
+const REGION_HOSTS: &str = "region-hosts";
+
+// Name of the dictionary item with the comma-separated names of the files in `/.well-known/`
+const WELL_KNOWN_FILES: &str = "well-known-files";
+
+// Prefix of the dictionary items with the bodies of the files in `/.well-known/`
+const WELL_KNOWN_FILE_PREFIX: &str = "well-known-file-";
+
+/// S3 hosts for a domain in the host map
+#[derive(Clone, Debug)]
+pub struct BucketHosts {
+    pub primary_host: String,
+    pub fallback_hosts: Vec<String>,
+}
+
+/// Error for a configuration that cannot be loaded
+///
+/// If several items are invalid, all of them are reported at once so that they can be fixed
+/// together.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The dictionary cannot be opened, for example because it is missing in a new deployment
+    Unavailable(OpenError),
+    /// A required item is missing from the dictionary
+    MissingKey(String),
+    /// A required item is empty
+    EmptyValue(String),
+    /// A required item has a value that cannot be used
+    InvalidValue {
+        key: String,
+        value: String,
+        expected: &'static str,
+    },
+    /// Several items are missing or invalid
+    Multiple(Vec<ConfigError>),
+}
+
+impl ConfigError {
+    /// Combine the errors of all items into a single error
+    fn collect(mut errors: Vec<ConfigError>) -> Option<Self> {
+        match errors.len() {
+            0 => None,
+            1 => errors.pop(),
+            _ => Some(Self::Multiple(errors)),
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unavailable(error) => write!(f, "failed to open dictionary: {error}"),
+            Self::MissingKey(key) => write!(f, "missing dictionary item {key}"),
+            Self::EmptyValue(key) => write!(f, "empty dictionary item {key}"),
+            Self::InvalidValue {
+                key,
+                value,
+                expected,
+            } => write!(
+                f,
+                "invalid dictionary item {key}: expected {expected}, got {value}"
+            ),
+            Self::Multiple(errors) => {
+                let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "{}", errors.join("; "))
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Unavailable(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<OpenError> for ConfigError {
+    fn from(error: OpenError) -> Self {
+        Self::Unavailable(error)
+    }
+}
+
 #[derive(Debug)]
 pub struct Config {
     pub primary_host: String,
-    pub fallback_host: String,
+    pub fallback_hosts: Vec<String>,
     pub static_ttl: u32,
+    pub ttl_overrides: Vec<(String, u32)>,
+    pub hot_paths: Vec<String>,
+    pub hot_path_ttl: u32,
+    pub negative_ttl: u32,
+    pub stale_while_revalidate: u32,
     pub cloudfront_url: String,
+    pub cloudfront_redirect_suffixes: Vec<String>,
     pub request_logs_endpoint: String,
     pub service_logs_endpoint: String,
+    pub cors_allowed_origins: Vec<String>,
+    pub log_schema_version: u8,
+    pub purge_token: Option<String>,
+    pub health_check_path: String,
+    pub method_not_allowed_status: StatusCode,
+    pub max_path_length: usize,
+    pub circuit_breaker_store: Option<String>,
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown: u32,
+    pub fallback_limit_store: Option<String>,
+    pub fallback_limit: u32,
+    pub fallback_limit_window: u32,
+    pub strip_query_string: bool,
+    pub retry_after: u32,
+    pub head_as_get: bool,
+    pub log_sample_rate: f64,
+    pub log_sample_salt: String,
+    pub hsts_max_age: u32,
+    pub referrer_policy: Option<String>,
+    pub redirect_map: HashMap<String, String>,
+    pub custom_404: bool,
+    pub immutable_prefixes: Vec<String>,
+    pub immutable_max_age: u32,
+    pub maintenance_mode: bool,
+    pub stats_store: Option<String>,
+    pub stats_interval: u32,
+    pub stats_sample_interval: u32,
+    pub secondary_cloudfront_url: Option<String>,
+    pub cloudfront_probe: bool,
+    pub stripped_response_headers: Vec<String>,
+    pub preload_map: Vec<(String, Vec<String>)>,
+    pub cache_key_headers: Vec<String>,
+    pub host_map: HashMap<String, BucketHosts>,
+    pub root_redirect_url: Option<String>,
+    pub root_redirect_paths: Vec<String>,
+    pub max_header_bytes: usize,
+    pub purge_allowed_paths: Vec<String>,
+    pub primary_retries: u32,
+    pub primary_retry_backoff_ms: u32,
+    pub content_types: Vec<(String, String)>,
+    pub debug_enabled: bool,
+    pub debug_token: Option<String>,
+    pub allowed_methods: Vec<Method>,
+    pub sensitive_header_policy: SensitiveHeaderPolicy,
+    pub backend_timeout_ms: u32,
+    pub robots_txt: Option<String>,
+    pub size_warn_threshold: Option<usize>,
+    pub synthesize_etag: bool,
+    pub rate_limit_store: Option<String>,
+    pub rate_limits: HashMap<String, RateLimit>,
+    pub maintenance_notice: Option<String>,
+    pub reject_empty_responses: bool,
+    pub trailing_slash_policy: TrailingSlashPolicy,
+    pub trailing_slash_exempt_prefixes: Vec<String>,
+    pub metrics_endpoint: Option<String>,
+    pub reject_bodied_gets: bool,
+    pub canonical_host: Option<String>,
+    pub cors_allowed_headers: Vec<String>,
+    pub backend_host_headers: HashMap<String, String>,
+    pub ip_deny_list: Vec<IpNetwork>,
+    pub log_ip_mode: LogIpMode,
+    pub response_headers: Vec<(HeaderName, HeaderValue)>,
+    pub large_object_prefixes: Vec<String>,
+    pub request_collapsing: bool,
+    pub error_document_path: Option<String>,
+    pub max_purge_body_bytes: u64,
+    pub failover_status_codes: Vec<u16>,
+    pub cache_precedence: CachePrecedence,
+    pub log_user_agent: bool,
+    pub gone_paths: Vec<String>,
+    pub gone_ttl: u32,
+    pub backend_strategy: BackendStrategy,
+    pub region_hosts: HashMap<String, String>,
+    pub well_known_files: HashMap<String, String>,
+    pub warnings: Vec<String>,
 }
 
 impl Config {
-    pub fn from_dictionary() -> Self {
-        let dictionary = ConfigStore::open(DICTIONARY_NAME);
+    /// Load the configuration from the dictionary
+    ///
+    /// Returns an error if the dictionary does not exist, for example in a new deployment, or if
+    /// required items are missing or invalid. A TTL of zero is rejected as well. Optional items
+    /// that are malformed only record a warning.
+    pub fn from_dictionary() -> Result<Self, ConfigError> {
+        let store = ConfigStore::try_open(DICTIONARY_NAME)?;
+        Self::from_items(Box::new(move |key| store.get(key)))
+    }
+
+    /// Load the configuration from items that are looked up by their key
+    fn from_items(items: Items) -> Result<Self, ConfigError> {
+        let mut dictionary = Dictionary::new(items);
 
         // Look up S3 hosts for current environment
-        let primary_host = dictionary
-            .get(PRIMARY_HOST)
-            .expect("failed to get S3 primary host from dictionary");
-        let fallback_host = dictionary
-            .get(FALLBACK_HOST)
-            .expect("failed to get S3 fallback host from dictionary");
+        let primary_host = dictionary.required(PRIMARY_HOST);
+        let fallback_hosts = split_list(&dictionary.required(FALLBACK_HOSTS));
 
         // Look up time to cache crates
-        let static_ttl = dictionary
-            .get(STATIC_TTL)
-            .expect("failed to get TTL for the static bucket from dictionary")
-            .parse()
-            .expect("failed to parse TTL for the static bucket");
-        let cloudfront_url = dictionary
-            .get(CLOUDFRONT_URL)
-            .expect("failed to get CloudFront URL from dictionary");
+        let static_ttl = dictionary.parse(STATIC_TTL, DEFAULT_STATIC_TTL);
+        if static_ttl == 0 {
+            dictionary.errors.push(ConfigError::InvalidValue {
+                key: STATIC_TTL.into(),
+                value: static_ttl.to_string(),
+                expected: "a TTL greater than zero",
+            });
+        }
+
+        // Look up TTL overrides, which are formatted as `/prefix/=ttl,/other-prefix/=ttl`
+        let ttl_overrides = dictionary.parse_with(TTL_OVERRIDES, Vec::new(), |overrides| {
+            split_pairs(overrides)?
+                .into_iter()
+                .map(|(prefix, ttl)| Some((prefix, ttl.parse().ok()?)))
+                .collect()
+        });
+
+        // Look up the hot paths and their TTL, which is short so that they stay fresh
+        let hot_paths = dictionary.list(HOT_PATHS).unwrap_or_default();
+        let hot_path_ttl = dictionary.parse(HOT_PATH_TTL, 5);
+
+        // Look up time to cache client errors from S3
+        let negative_ttl = dictionary.parse(NEGATIVE_TTL, DEFAULT_NEGATIVE_TTL);
+
+        // Look up the time that stale content is served while it is being revalidated, which is
+        // disabled by default. Fastly cannot be told to serve stale content when S3 fails, so only
+        // a warning is recorded if that is configured.
+        let stale_while_revalidate = dictionary.parse(STALE_WHILE_REVALIDATE, 0);
+        if dictionary.string(STALE_IF_ERROR).is_some() {
+            dictionary.warn(format!(
+                "ignored dictionary item {STALE_IF_ERROR}, since only {STALE_WHILE_REVALIDATE} is \
+                 supported"
+            ));
+        }
+
+        let cloudfront_url = dictionary.required(CLOUDFRONT_URL);
+        let cloudfront_redirect_suffixes = dictionary
+            .list(CLOUDFRONT_REDIRECT_SUFFIXES)
+            .unwrap_or_else(|| vec!["db-dump.tar.gz".into()]);
 
         // Look up the endpoints for logging
-        let request_logs_endpoint = dictionary
-            .get(REQUEST_LOGS_ENDPOINT)
-            .expect("failed to get endpoint for request logs from dictionary");
-        let service_logs_endpoint = dictionary
-            .get(SERVICE_LOGS_ENDPOINT)
-            .expect("failed to get endpoint for service logs from dictionary");
-
-        Self {
+        let request_logs_endpoint = dictionary.required(REQUEST_LOGS_ENDPOINT);
+        let service_logs_endpoint = dictionary.required(SERVICE_LOGS_ENDPOINT);
+
+        // Look up the origins that are allowed to make cross-origin requests. An empty list allows
+        // all origins.
+        let cors_allowed_origins = dictionary.list(CORS_ALLOWED_ORIGINS).unwrap_or_default();
+
+        // Look up the schema version for request logs, which defaults to the first version
+        let log_schema_version = dictionary.parse(LOG_SCHEMA_VERSION, 1);
+
+        // Look up the token that authorizes purge requests. Without a token, all purge requests
+        // are rejected.
+        let purge_token = dictionary.string(PURGE_TOKEN);
+
+        // Look up the path that is answered by the health check
+        let health_check_path = dictionary
+            .string(HEALTH_CHECK_PATH)
+            .unwrap_or_else(|| "/_health".into());
+
+        // Look up the status code for disallowed request methods. Only 401, 403, and 405 are
+        // supported, with 401 being the default to maintain parity with CloudFront.
+        let method_not_allowed_status = dictionary.parse_with(
+            METHOD_NOT_ALLOWED_STATUS,
+            StatusCode::UNAUTHORIZED,
+            |status| match status {
+                "401" => Some(StatusCode::UNAUTHORIZED),
+                "403" => Some(StatusCode::FORBIDDEN),
+                "405" => Some(StatusCode::METHOD_NOT_ALLOWED),
+                _ => None,
+            },
+        );
+
+        // Look up the maximum length of a request path
+        let max_path_length = dictionary.parse(MAX_PATH_LENGTH, 2048);
+
+        // Look up the settings for the circuit breaker, which is disabled without an object store
+        let circuit_breaker_store = dictionary.string(CIRCUIT_BREAKER_STORE);
+        let circuit_breaker_threshold = dictionary.parse(CIRCUIT_BREAKER_THRESHOLD, 5);
+        let circuit_breaker_cooldown = dictionary.parse(CIRCUIT_BREAKER_COOLDOWN, 30);
+
+        // Look up the cap for requests that use a fallback host, which is disabled without an
+        // object store or with a cap of 0
+        let fallback_limit_store = dictionary.string(FALLBACK_LIMIT_STORE);
+        let fallback_limit = dictionary.parse(FALLBACK_LIMIT, 0);
+        let fallback_limit_window = dictionary.parse(FALLBACK_LIMIT_WINDOW, 60);
+
+        // Look up whether query strings are stripped from requests, which is disabled by default
+        let strip_query_string = dictionary.parse(STRIP_QUERY_STRING, false);
+
+        // Look up the time in seconds after which clients should retry when S3 is unavailable
+        let retry_after = dictionary.parse(RETRY_AFTER, 60);
+
+        // Look up whether HEAD requests are sent as GET requests to S3, which is disabled by
+        // default
+        let head_as_get = dictionary.parse(HEAD_AS_GET, false);
+
+        // Look up the fraction of successful requests that are logged, which defaults to all
+        let log_sample_rate = dictionary.parse_with(LOG_SAMPLE_RATE, 1.0, |rate| {
+            rate.parse().ok().filter(|rate| (0.0..=1.0).contains(rate))
+        });
+        let log_sample_salt = dictionary.string(LOG_SAMPLE_SALT).unwrap_or_default();
+
+        // Look up the settings for the security headers
+        let hsts_max_age = dictionary.parse(HSTS_MAX_AGE, 31_536_000);
+        let referrer_policy = dictionary.string(REFERRER_POLICY);
+
+        // Look up the redirects, which are formatted as
+        // `/path=https://target,/other=https://target`
+        let redirect_map = dictionary.parse_with(REDIRECT_MAP, HashMap::new(), |redirects| {
+            Some(split_pairs(redirects)?.into_iter().collect())
+        });
+
+        // Look up whether S3's errors for missing files are replaced, which is disabled by default
+        let custom_404 = dictionary.parse(CUSTOM_404, false);
+
+        // Look up the settings for immutable files, like crates, that never change once published
+        let immutable_prefixes = dictionary.list(IMMUTABLE_PREFIXES).unwrap_or_default();
+        let immutable_max_age = dictionary.parse(IMMUTABLE_MAX_AGE, 31_536_000);
+
+        // Look up whether the service is in maintenance mode
+        let maintenance_mode = dictionary.parse(MAINTENANCE_MODE, false);
+
+        // Look up the settings for the latency stats, which are disabled without an object store
+        let stats_store = dictionary.string(STATS_STORE);
+        let stats_interval = dictionary.parse(STATS_INTERVAL, 1000);
+        let stats_sample_interval = dictionary.parse(STATS_SAMPLE_INTERVAL, 100);
+
+        // Look up the settings for the secondary CloudFront host, which is only used when probing
+        // is enabled
+        let secondary_cloudfront_url = dictionary.string(SECONDARY_CLOUDFRONT_URL);
+        let cloudfront_probe = dictionary.parse(CLOUDFRONT_PROBE, false);
+
+        // Look up the response headers that are stripped. Header names are lowercase, so the
+        // entries are matched in lowercase as well.
+        let stripped_response_headers = dictionary
+            .list(STRIPPED_RESPONSE_HEADERS)
+            .unwrap_or_else(|| vec!["x-amz-".into(), "server".into()])
+            .into_iter()
+            .map(|header| header.to_ascii_lowercase())
+            .collect();
+
+        // Look up the files that are preloaded, which are formatted as
+        // `/prefix=/file /other-file,/other-prefix=/file`
+        let preload_map = dictionary.parse_with(PRELOAD_MAP, Vec::new(), |preloads| {
+            let preloads = split_pairs(preloads)?
+                .into_iter()
+                .map(|(prefix, targets)| {
+                    let targets = targets.split_whitespace().map(String::from).collect();
+                    (prefix, targets)
+                })
+                .collect();
+
+            Some(preloads)
+        });
+
+        // Look up the request headers that are part of the cache key. Only the encoding is included
+        // by default, since it has been normalized to a few variants.
+        let cache_key_headers = dictionary
+            .list(CACHE_KEY_HEADERS)
+            .unwrap_or_else(|| vec!["Accept-Encoding".into()]);
+
+        // Look up the S3 hosts for additional domains, which are formatted as
+        // `domain=primary-host fallback-host,other-domain=primary-host`
+        let host_map = dictionary.parse_with(HOST_MAP, HashMap::new(), |domains| {
+            split_pairs(domains)?
+                .into_iter()
+                .map(|(domain, hosts)| {
+                    let mut hosts = hosts.split_whitespace().map(String::from);
+                    let bucket_hosts = BucketHosts {
+                        primary_host: hosts.next()?,
+                        fallback_hosts: hosts.collect(),
+                    };
+
+                    Some((domain.to_ascii_lowercase(), bucket_hosts))
+                })
+                .collect()
+        });
+
+        // Look up the landing page for bare paths, which is disabled without a URL
+        let root_redirect_url = dictionary.string(ROOT_REDIRECT_URL);
+        let root_redirect_paths = dictionary
+            .list(ROOT_REDIRECT_PATHS)
+            .unwrap_or_else(|| vec!["/".into()]);
+
+        // Look up the maximum size of the request headers
+        let max_header_bytes = dictionary.parse(MAX_HEADER_BYTES, 16 * 1024);
+
+        // Look up the path patterns that can be purged. Without patterns, all purges are rejected.
+        let purge_allowed_paths = dictionary.list(PURGE_ALLOWED_PATHS).unwrap_or_default();
+
+        // Look up the settings for retries against the primary host
+        let primary_retries = dictionary.parse(PRIMARY_RETRIES, 1);
+        let primary_retry_backoff_ms = dictionary.parse(PRIMARY_RETRY_BACKOFF_MS, 0);
+
+        // Look up the content types for path suffixes, which are formatted as
+        // `.suffix=type/subtype,.other-suffix=type/subtype`
+        let content_types = dictionary.parse_with(
+            CONTENT_TYPES,
+            vec![
+                (".crate".into(), "application/x-tar".into()),
+                (".json".into(), "application/json".into()),
+            ],
+            split_pairs,
+        );
+
+        // Look up the settings for debug requests, which require both the flag and a token
+        let debug_enabled = dictionary.parse(DEBUG_ENABLED, false);
+        let debug_token = dictionary.string(DEBUG_TOKEN);
+
+        // Look up the HTTP methods that clients are allowed to use, which default to GET and HEAD.
+        // Invalid methods are ignored, so that a typo doesn't reset the list to the default.
+        let allowed_methods = dictionary
+            .list(ALLOWED_METHODS)
+            .map(|methods| {
+                methods
+                    .iter()
+                    .filter_map(|method| {
+                        let parsed = Method::from_bytes(method.to_ascii_uppercase().as_bytes());
+                        if parsed.is_err() {
+                            dictionary.warn(format!("ignored invalid allowed method: {method}"));
+                        }
+
+                        parsed.ok()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .filter(|methods| !methods.is_empty())
+            .unwrap_or_else(|| vec![Method::GET, Method::HEAD]);
+
+        // Look up the policy for requests with cookies or credentials. The files are public, so
+        // the headers are stripped by default.
+        let sensitive_header_policy = dictionary.parse_with(
+            SENSITIVE_HEADER_POLICY,
+            SensitiveHeaderPolicy::Strip,
+            |policy| match policy {
+                "strip" => Some(SensitiveHeaderPolicy::Strip),
+                "bypass" => Some(SensitiveHeaderPolicy::Bypass),
+                _ => None,
+            },
+        );
+
+        // Look up the timeout for requests to S3
+        let backend_timeout_ms = dictionary.parse(BACKEND_TIMEOUT_MS, 10_000);
+
+        // Look up the body of `robots.txt`, which is served by S3 if it is missing
+        let robots_txt = dictionary.string(ROBOTS_TXT);
+
+        // Look up the size above which responses are logged as oversized, which is disabled by
+        // default
+        let size_warn_threshold = dictionary.parse_with(SIZE_WARN_THRESHOLD, None, |size| {
+            size.parse().ok().map(Some)
+        });
+
+        // Look up whether ETags are synthesized, which is disabled by default
+        let synthesize_etag = dictionary.parse(SYNTHESIZE_ETAG, false);
+
+        // Look up the settings for the rate limiter, which is disabled without an object store. The
+        // limits are formatted as `purge=10/60,health-check=60/60`, with the number of requests per
+        // window in seconds.
+        let rate_limit_store = dictionary.string(RATE_LIMIT_STORE);
+        let rate_limits = dictionary.parse_with(RATE_LIMITS, HashMap::new(), |limits| {
+            split_pairs(limits)?
+                .into_iter()
+                .map(|(class, limit)| {
+                    let (requests, window) = limit.split_once('/')?;
+                    let rate_limit = RateLimit {
+                        requests: requests.trim().parse().ok()?,
+                        window: window.trim().parse().ok()?,
+                    };
+
+                    Some((class, rate_limit))
+                })
+                .collect()
+        });
+
+        // Look up the notice about upcoming maintenance, which must be a valid header value
+        let maintenance_notice = dictionary.parse_with(MAINTENANCE_NOTICE, None, |notice| {
+            HeaderValue::from_str(notice)
+                .ok()
+                .map(|_| Some(notice.to_string()))
+        });
+
+        // Look up whether empty responses from S3 are rejected, which is disabled by default
+        let reject_empty_responses = dictionary.parse(REJECT_EMPTY_RESPONSES, false);
+
+        // Look up the policy for trailing slashes, which leaves paths unchanged by default
+        let trailing_slash_policy =
+            dictionary.parse_with(TRAILING_SLASH_POLICY, TrailingSlashPolicy::Off, |policy| {
+                match policy {
+                    "off" => Some(TrailingSlashPolicy::Off),
+                    "strip" => Some(TrailingSlashPolicy::Strip),
+                    "keep" => Some(TrailingSlashPolicy::Keep),
+                    _ => None,
+                }
+            });
+
+        // Look up the prefixes of files without an extension, which default to the sparse index
+        let trailing_slash_exempt_prefixes = dictionary
+            .list(TRAILING_SLASH_EXEMPT_PREFIXES)
+            .unwrap_or_else(|| vec!["/index/".into()]);
+
+        // Look up the endpoint for metrics, which are not sent without one
+        let metrics_endpoint = dictionary.string(METRICS_ENDPOINT);
+
+        // Look up whether GET and HEAD requests with a body are rejected, which is disabled by
+        // default
+        let reject_bodied_gets = dictionary.parse(REJECT_BODIED_GETS, false);
+
+        // Look up the canonical host, which disables the redirect if it is missing
+        let canonical_host = dictionary
+            .string(CANONICAL_HOST)
+            .map(|host| host.to_ascii_lowercase());
+
+        // Look up the request headers that are allowed in CORS requests. Without a list, no custom
+        // headers are allowed.
+        let cors_allowed_headers = dictionary.list(CORS_ALLOWED_HEADERS).unwrap_or_default();
+
+        // Look up the Host headers that are sent to backends, which are formatted as
+        // `backend=host,other-backend=host`
+        let backend_host_headers =
+            dictionary.parse_with(BACKEND_HOST_HEADERS, HashMap::new(), |hosts| {
+                Some(split_pairs(hosts)?.into_iter().collect())
+            });
+
+        // Look up the IP addresses and ranges that are blocked, in CIDR notation
+        let ip_deny_list = dictionary.parse_with(IP_DENY_LIST, Vec::new(), |networks| {
+            split_list(networks)
+                .iter()
+                .map(|network| network.parse().ok())
+                .collect()
+        });
+
+        // Look up how client IPs are logged, which defaults to the full address
+        let log_ip_mode = dictionary.parse_with(LOG_IP_MODE, LogIpMode::Full, |mode| match mode {
+            "full" => Some(LogIpMode::Full),
+            "masked" => Some(LogIpMode::Masked),
+            "none" => Some(LogIpMode::None),
+            _ => None,
+        });
+
+        // Look up the headers that are added to successful responses, which are formatted as
+        // `Name:value,Other-Name:value`. The CORS headers are controlled by their own settings and
+        // cannot be added this way.
+        let response_headers = dictionary
+            .list(RESPONSE_HEADERS)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|header| {
+                let parsed = parse_response_header(header);
+                if parsed.is_none() {
+                    dictionary.warn(format!("ignored invalid response header: {header}"));
+                }
+
+                parsed
+            })
+            .collect();
+
+        // Look up the path prefixes of large objects, which don't advertise byte ranges without a
+        // list
+        let large_object_prefixes = dictionary.list(LARGE_OBJECT_PREFIXES).unwrap_or_default();
+
+        // Look up whether concurrent cache misses are collapsed, which Fastly does by default
+        let request_collapsing = dictionary.parse(REQUEST_COLLAPSING, true);
+
+        // Look up the path of the error document, which keeps S3's errors without one
+        let error_document_path = dictionary.string(ERROR_DOCUMENT_PATH);
+
+        // The error document already replaces S3's errors for missing files
+        if custom_404 && error_document_path.is_some() {
+            dictionary.warn(format!(
+                "ignored dictionary item {CUSTOM_404}, since {ERROR_DOCUMENT_PATH} is set"
+            ));
+        }
+        let custom_404 = custom_404 && error_document_path.is_none();
+
+        // Look up the maximum size of the body of purge requests, which should not have one
+        let max_purge_body_bytes = dictionary.parse(MAX_PURGE_BODY_BYTES, 1024);
+
+        // Look up the status codes from S3 that fail over to a fallback host
+        let failover_status_codes =
+            dictionary.parse_with(FAILOVER_STATUS_CODES, vec![500, 502, 503, 504], |codes| {
+                split_list(codes)
+                    .iter()
+                    .map(|code| code.parse().ok())
+                    .collect()
+            });
+
+        // Look up the precedence between `Cache-Control` from S3 and the configured TTL, which
+        // ignores S3's header by default. The shorter or longer of the two cannot be applied at the
+        // edge, so these precedences are rejected instead of being ignored.
+        let cache_precedence = match dictionary.string(CACHE_PRECEDENCE) {
+            Some(value) if value == "min" || value == "max" => {
+                dictionary.errors.push(ConfigError::InvalidValue {
+                    key: CACHE_PRECEDENCE.into(),
+                    value,
+                    expected: "origin_wins or config_wins",
+                });
+                CachePrecedence::ConfigWins
+            }
+            _ => dictionary.parse(CACHE_PRECEDENCE, CachePrecedence::ConfigWins),
+        };
+
+        // Look up whether the `User-Agent` header is logged, which is enabled by default
+        let log_user_agent = dictionary.parse(LOG_USER_AGENT, true);
+
+        // Look up the paths that have been removed, and the time that their responses are cached
+        let gone_paths = dictionary.list(GONE_PATHS).unwrap_or_default();
+        let gone_ttl = dictionary.parse(GONE_TTL, DEFAULT_STATIC_TTL);
+
+        // Look up the strategy for the order of the hosts, which tries the primary host first
+        let backend_strategy = dictionary.parse_with(
+            BACKEND_STRATEGY,
+            BackendStrategy::PrimaryWithFallback,
+            |strategy| match strategy {
+                "primary-with-fallback" => Some(BackendStrategy::PrimaryWithFallback),
+                "rotating-fallbacks" => Some(BackendStrategy::RotatingFallbacks),
+                "region-affinity" => Some(BackendStrategy::RegionAffinity),
+                _ => None,
+            },
+        );
+
+        // Look up the hosts for the continents of clients, which are formatted as
+        // `EU=host,NA=other-host` with the two-letter codes of the continents
+        let region_hosts = dictionary.parse_with(REGION_HOSTS, HashMap::new(), |hosts| {
+            Some(
+                split_pairs(hosts)?
+                    .into_iter()
+                    .map(|(continent, host)| (continent.to_ascii_uppercase(), host))
+                    .collect(),
+            )
+        });
+
+        // Look up the files in `/.well-known/` that are served from the edge. The body of each file
+        // is in its own item, like `well-known-file-security.txt`.
+        let well_known_files = dictionary
+            .list(WELL_KNOWN_FILES)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|name| {
+                let body = dictionary.string(&format!("{WELL_KNOWN_FILE_PREFIX}{name}"));
+                if body.is_none() {
+                    dictionary.warn(format!("ignored well-known file without a body: {name}"));
+                }
+
+                Some((format!("/.well-known/{name}"), format!("{}\n", body?)))
+            })
+            .collect();
+
+        if let Some(error) = ConfigError::collect(dictionary.errors) {
+            return Err(error);
+        }
+
+        Ok(Self {
             primary_host,
-            fallback_host,
+            fallback_hosts,
             static_ttl,
+            ttl_overrides,
+            hot_paths,
+            hot_path_ttl,
+            negative_ttl,
+            stale_while_revalidate,
             cloudfront_url,
+            cloudfront_redirect_suffixes,
             request_logs_endpoint,
             service_logs_endpoint,
+            cors_allowed_origins,
+            log_schema_version,
+            purge_token,
+            health_check_path,
+            method_not_allowed_status,
+            max_path_length,
+            circuit_breaker_store,
+            circuit_breaker_threshold,
+            circuit_breaker_cooldown,
+            fallback_limit_store,
+            fallback_limit,
+            fallback_limit_window,
+            strip_query_string,
+            retry_after,
+            head_as_get,
+            log_sample_rate,
+            log_sample_salt,
+            hsts_max_age,
+            referrer_policy,
+            redirect_map,
+            custom_404,
+            immutable_prefixes,
+            immutable_max_age,
+            maintenance_mode,
+            stats_store,
+            stats_interval,
+            stats_sample_interval,
+            secondary_cloudfront_url,
+            cloudfront_probe,
+            stripped_response_headers,
+            preload_map,
+            cache_key_headers,
+            host_map,
+            root_redirect_url,
+            root_redirect_paths,
+            max_header_bytes,
+            purge_allowed_paths,
+            primary_retries,
+            primary_retry_backoff_ms,
+            content_types,
+            debug_enabled,
+            debug_token,
+            allowed_methods,
+            sensitive_header_policy,
+            backend_timeout_ms,
+            robots_txt,
+            size_warn_threshold,
+            synthesize_etag,
+            rate_limit_store,
+            rate_limits,
+            maintenance_notice,
+            reject_empty_responses,
+            trailing_slash_policy,
+            trailing_slash_exempt_prefixes,
+            metrics_endpoint,
+            reject_bodied_gets,
+            canonical_host,
+            cors_allowed_headers,
+            backend_host_headers,
+            ip_deny_list,
+            log_ip_mode,
+            response_headers,
+            large_object_prefixes,
+            request_collapsing,
+            error_document_path,
+            max_purge_body_bytes,
+            failover_status_codes,
+            cache_precedence,
+            log_user_agent,
+            gone_paths,
+            gone_ttl,
+            backend_strategy,
+            region_hosts,
+            well_known_files,
+            warnings: dictionary.warnings,
+        })
+    }
+
+    /// Format the allowed methods for the `Allow` and `Access-Control-Allow-Methods` headers
+    pub fn allowed_methods_header(&self) -> String {
+        self.allowed_methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Lookup of the items in the dictionary by their key
+///
+/// The items are read from Fastly's config store, while tests provide them directly.
+type Items = Box<dyn Fn(&str) -> Option<String>>;
+
+/// Typed access to the items in the dictionary
+///
+/// Missing items are replaced with a default value. Malformed items are replaced with the default
+/// as well, and a warning is recorded for them. The logger can only be initialized once the
+/// configuration has been loaded, so the warnings are logged later. Required items that are
+/// missing or empty record an error instead.
+///
+/// The dictionary declares the version of its schema in the `config-version` item. Items that have
+/// been renamed since that version are still read under their old name, with a deprecation
+/// warning. A missing or unknown version is treated like the current version, but old names are
+/// still read on a best-effort basis if the new name is missing.
+struct Dictionary {
+    items: Items,
+    migrated: HashMap<&'static str, String>,
+    warnings: Vec<String>,
+    errors: Vec<ConfigError>,
+}
+
+impl Dictionary {
+    fn new(items: Items) -> Self {
+        let mut dictionary = Self {
+            items,
+            migrated: HashMap::new(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
+        };
+
+        let version = dictionary.string(CONFIG_VERSION);
+        let version = match version.as_deref().map(str::parse) {
+            Some(Ok(version)) if version <= CURRENT_CONFIG_VERSION => Some(version),
+            Some(_) => {
+                dictionary.warn(format!(
+                    "unknown config version {}, reading it as version {CURRENT_CONFIG_VERSION}",
+                    version.unwrap_or_default()
+                ));
+                None
+            }
+            None => None,
+        };
+
+        dictionary.migrate(version);
+        dictionary
+    }
+
+    /// Read renamed items under their old name
+    ///
+    /// Without a known version, all renamed items are migrated if the new name is missing.
+    fn migrate(&mut self, version: Option<u32>) {
+        for (renamed_in, old_key, new_key) in RENAMED_ITEMS {
+            if version.is_some_and(|version| version >= renamed_in) {
+                continue;
+            }
+
+            let Some(value) = self.string(old_key) else {
+                continue;
+            };
+
+            if self.string(new_key).is_some() {
+                self.warn(format!(
+                    "ignored deprecated dictionary item {old_key}, since {new_key} is set"
+                ));
+                continue;
+            }
+
+            self.warn(format!(
+                "dictionary item {old_key} is deprecated, rename it to {new_key}"
+            ));
+            self.migrated.insert(new_key, value);
+        }
+    }
+
+    /// Get an item as a string, treating empty items like missing ones
+    fn string(&self, key: &str) -> Option<String> {
+        (self.items)(key)
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .or_else(|| self.migrated.get(key).cloned())
+    }
+
+    /// Get a required item as a string
+    ///
+    /// An error is recorded if the item is missing or empty, and an empty string is returned.
+    fn required(&mut self, key: &'static str) -> String {
+        if let Some(value) = self.string(key) {
+            return value;
+        }
+
+        let error = match (self.items)(key) {
+            Some(_) => ConfigError::EmptyValue(key.into()),
+            None => ConfigError::MissingKey(key.into()),
+        };
+        self.errors.push(error);
+
+        String::new()
+    }
+
+    /// Get an item as a comma-separated list of strings
+    fn list(&self, key: &str) -> Option<Vec<String>> {
+        self.string(key).map(|value| split_list(&value))
+    }
+
+    /// Get an item that is parsed with its implementation of `FromStr`
+    fn parse<T: FromStr>(&mut self, key: &str, default: T) -> T {
+        self.parse_with(key, default, |value| value.parse().ok())
+    }
+
+    /// Get an item that is parsed with the given function
+    fn parse_with<T>(&mut self, key: &str, default: T, parse: impl FnOnce(&str) -> Option<T>) -> T {
+        let Some(value) = self.string(key) else {
+            return default;
+        };
+
+        match parse(&value) {
+            Some(parsed) => parsed,
+            None => {
+                self.warn(format!("failed to parse dictionary item {key}: {value}"));
+                default
+            }
+        }
+    }
+
+    /// Record a warning that is logged once the logger has been initialized
+    fn warn(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+}
+
+/// Parse a header in the format `Name:value`
+///
+/// Returns `None` if the name or the value contain invalid characters, or if the name belongs to
+/// CORS.
+fn parse_response_header(header: &str) -> Option<(HeaderName, HeaderValue)> {
+    let (name, value) = header.split_once(':')?;
+
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).ok()?;
+    let value = HeaderValue::from_str(value.trim()).ok()?;
+
+    if name.as_str().starts_with("access-control-") {
+        return None;
+    }
+
+    Some((name, value))
+}
+
+/// Split a comma-separated list, ignoring empty entries
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Split a comma-separated list of `key=value` pairs
+///
+/// Returns `None` if any of the entries is not a pair.
+fn split_pairs(value: &str) -> Option<Vec<(String, String)>> {
+    split_list(value)
+        .iter()
+        .map(|item| {
+            let (key, value) = item.split_once('=')?;
+            Some((key.trim().into(), value.trim().into()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+impl Config {
+    /// Load a configuration for tests
+    ///
+    /// The required items are set to placeholders, and can be replaced by the given items.
+    pub fn for_tests(items: &[(&str, &str)]) -> Self {
+        let items: HashMap<String, String> = tests::REQUIRED_ITEMS
+            .into_iter()
+            .chain(items.iter().copied())
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        Self::from_items(Box::new(move |key| items.get(key).cloned()))
+            .expect("configuration for tests is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Required items with placeholder values
+    pub(super) const REQUIRED_ITEMS: [(&str, &str); 5] = [
+        (PRIMARY_HOST, "primary.s3.example.com"),
+        (FALLBACK_HOSTS, "fallback.s3.example.com"),
+        (CLOUDFRONT_URL, "cloudfront.example.com"),
+        (REQUEST_LOGS_ENDPOINT, "request-logs"),
+        (SERVICE_LOGS_ENDPOINT, "service-logs"),
+    ];
+
+    fn load(items: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let items: HashMap<String, String> = items
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        Config::from_items(Box::new(move |key| items.get(key).cloned()))
+    }
+
+    fn without(key: &str) -> Vec<(&'static str, &'static str)> {
+        REQUIRED_ITEMS
+            .into_iter()
+            .filter(|(required, _)| *required != key)
+            .collect()
+    }
+
+    #[test]
+    fn valid_config_is_loaded() {
+        let config = load(&REQUIRED_ITEMS).unwrap();
+
+        assert_eq!(config.primary_host, "primary.s3.example.com");
+        assert_eq!(config.fallback_hosts, ["fallback.s3.example.com"]);
+        assert!(config.warnings.is_empty());
+    }
+
+    #[test]
+    fn missing_key_is_reported() {
+        for (key, _) in REQUIRED_ITEMS {
+            let error = load(&without(key)).unwrap_err();
+
+            assert!(
+                matches!(&error, ConfigError::MissingKey(missing) if missing == key),
+                "{error}"
+            );
         }
     }
+
+    #[test]
+    fn missing_keys_are_reported_together() {
+        let error = load(&REQUIRED_ITEMS[2..]).unwrap_err();
+
+        let ConfigError::Multiple(errors) = &error else {
+            panic!("expected multiple errors, got {error}");
+        };
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[0], ConfigError::MissingKey(key) if key == PRIMARY_HOST));
+        assert!(matches!(&errors[1], ConfigError::MissingKey(key) if key == FALLBACK_HOSTS));
+        assert_eq!(
+            error.to_string(),
+            "missing dictionary item s3-primary-host; missing dictionary item s3-fallback-host"
+        );
+    }
+
+    fn dictionary(items: &[(&str, &str)]) -> Dictionary {
+        let items: HashMap<String, String> = items
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        Dictionary::new(Box::new(move |key| items.get(key).cloned()))
+    }
+
+    #[test]
+    fn string_is_trimmed() {
+        let dictionary = dictionary(&[("present", " value "), ("empty", "  ")]);
+
+        assert_eq!(dictionary.string("present"), Some("value".into()));
+        assert_eq!(dictionary.string("empty"), None);
+        assert_eq!(dictionary.string("missing"), None);
+    }
+
+    #[test]
+    fn required_records_missing_and_empty_items() {
+        let mut dictionary = dictionary(&[(PRIMARY_HOST, "primary"), (FALLBACK_HOSTS, "")]);
+
+        assert_eq!(dictionary.required(PRIMARY_HOST), "primary");
+        assert_eq!(dictionary.required(FALLBACK_HOSTS), "");
+        assert_eq!(dictionary.required(CLOUDFRONT_URL), "");
+
+        assert!(matches!(
+            dictionary.errors.as_slice(),
+            [ConfigError::EmptyValue(empty), ConfigError::MissingKey(missing)]
+                if empty == FALLBACK_HOSTS && missing == CLOUDFRONT_URL
+        ));
+    }
+
+    #[test]
+    fn list_splits_items() {
+        let dictionary = dictionary(&[("present", "a, b,,c ,")]);
+
+        assert_eq!(
+            dictionary.list("present"),
+            Some(vec!["a".into(), "b".into(), "c".into()])
+        );
+        assert_eq!(dictionary.list("missing"), None);
+    }
+
+    #[test]
+    fn parse_falls_back_to_default() {
+        let mut dictionary = dictionary(&[("present", "42"), ("malformed", "forty-two")]);
+
+        assert_eq!(dictionary.parse("present", 7u32), 42);
+        assert_eq!(dictionary.parse("missing", 7u32), 7);
+        assert!(dictionary.warnings.is_empty());
+
+        assert_eq!(dictionary.parse("malformed", 7u32), 7);
+        assert_eq!(
+            dictionary.warnings,
+            ["failed to parse dictionary item malformed: forty-two"]
+        );
+    }
+
+    #[test]
+    fn parse_with_uses_parser() {
+        let mut dictionary = dictionary(&[("present", "on"), ("malformed", "maybe")]);
+        let parse = |value: &str| match value {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        };
+
+        assert!(dictionary.parse_with("present", false, parse));
+        assert!(!dictionary.parse_with("missing", false, parse));
+        assert!(!dictionary.parse_with("malformed", false, parse));
+        assert_eq!(dictionary.warnings.len(), 1);
+    }
+
+    #[test]
+    fn split_pairs_requires_pairs() {
+        assert_eq!(
+            split_pairs("/a/=1, /b/ = 2"),
+            Some(vec![("/a/".into(), "1".into()), ("/b/".into(), "2".into())])
+        );
+        assert_eq!(split_pairs(""), Some(vec![]));
+        assert_eq!(split_pairs("/a/=1,/b/"), None);
+    }
+
+    #[test]
+    fn malformed_optional_items_only_warn() {
+        let mut items = REQUIRED_ITEMS.to_vec();
+        items.push((STATIC_TTL, "forever"));
+
+        let config = load(&items).unwrap();
+
+        assert_eq!(config.static_ttl, DEFAULT_STATIC_TTL);
+        assert_eq!(
+            config.warnings,
+            ["failed to parse dictionary item static-ttl: forever"]
+        );
+    }
+
+    fn load_with(item: (&'static str, &'static str)) -> Config {
+        load_with_all(&[item])
+    }
+
+    #[test]
+    fn allowed_methods_default_to_get_and_head() {
+        let config = load(&REQUIRED_ITEMS).unwrap();
+
+        assert_eq!(config.allowed_methods, [Method::GET, Method::HEAD]);
+    }
+
+    #[test]
+    fn allowed_methods_can_be_extended() {
+        let config = load_with((ALLOWED_METHODS, "GET, HEAD, options"));
+
+        assert_eq!(
+            config.allowed_methods,
+            [Method::GET, Method::HEAD, Method::OPTIONS]
+        );
+        assert_eq!(config.allowed_methods_header(), "GET, HEAD, OPTIONS");
+    }
+
+    #[test]
+    fn invalid_allowed_methods_are_ignored() {
+        let config = load_with((ALLOWED_METHODS, "GET, G(E)T"));
+
+        assert_eq!(config.allowed_methods, [Method::GET]);
+        assert_eq!(config.warnings, ["ignored invalid allowed method: G(E)T"]);
+    }
+
+    #[test]
+    fn only_invalid_allowed_methods_fall_back_to_default() {
+        let config = load_with((ALLOWED_METHODS, "G(E)T"));
+
+        assert_eq!(config.allowed_methods, [Method::GET, Method::HEAD]);
+    }
+
+    #[test]
+    fn response_headers_are_parsed() {
+        assert_eq!(
+            parse_response_header("X-Robots-Tag: noindex"),
+            Some((
+                HeaderName::from_static("x-robots-tag"),
+                HeaderValue::from_static("noindex")
+            ))
+        );
+    }
+
+    #[test]
+    fn invalid_response_headers_are_rejected() {
+        for header in [
+            "X-Robots-Tag",
+            "X Robots: noindex",
+            "X-Robots\r\nSet-Cookie: a",
+        ] {
+            assert_eq!(parse_response_header(header), None, "header: {header:?}");
+        }
+    }
+
+    #[test]
+    fn cors_response_headers_are_rejected() {
+        assert_eq!(
+            parse_response_header("Access-Control-Allow-Origin: *"),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_response_headers_only_warn() {
+        let config = load_with((RESPONSE_HEADERS, "X-Robots-Tag: noindex, Bad Name: value"));
+
+        assert_eq!(config.response_headers.len(), 1);
+        assert_eq!(
+            config.warnings,
+            ["ignored invalid response header: Bad Name: value"]
+        );
+    }
+
+    fn load_with_all(extra: &[(&'static str, &'static str)]) -> Config {
+        let mut items = REQUIRED_ITEMS.to_vec();
+        items.extend_from_slice(extra);
+        load(&items).unwrap()
+    }
+
+    #[test]
+    fn v1_config_reads_renamed_items() {
+        let config = load_with_all(&[(CONFIG_VERSION, "1"), ("ttl", "600")]);
+
+        assert_eq!(config.static_ttl, 600);
+        assert_eq!(
+            config.warnings,
+            ["dictionary item ttl is deprecated, rename it to static-ttl"]
+        );
+    }
+
+    #[test]
+    fn current_config_ignores_old_names() {
+        let config = load_with_all(&[(CONFIG_VERSION, "2"), ("ttl", "600")]);
+
+        assert_eq!(config.static_ttl, DEFAULT_STATIC_TTL);
+        assert!(config.warnings.is_empty());
+
+        let config = load_with_all(&[(CONFIG_VERSION, "2"), (STATIC_TTL, "900")]);
+
+        assert_eq!(config.static_ttl, 900);
+        assert!(config.warnings.is_empty());
+    }
+
+    #[test]
+    fn new_names_take_precedence_over_old_names() {
+        let config = load_with_all(&[(CONFIG_VERSION, "1"), ("ttl", "600"), (STATIC_TTL, "900")]);
+
+        assert_eq!(config.static_ttl, 900);
+        assert_eq!(
+            config.warnings,
+            ["ignored deprecated dictionary item ttl, since static-ttl is set"]
+        );
+    }
+
+    #[test]
+    fn unknown_versions_read_old_names_best_effort() {
+        for version in [None, Some("3"), Some("latest")] {
+            let mut items = vec![("ttl", "600")];
+            items.extend(version.map(|version| (CONFIG_VERSION, version)));
+
+            let config = load_with_all(&items);
+
+            assert_eq!(config.static_ttl, 600, "version: {version:?}");
+        }
+    }
+
+    #[test]
+    fn missing_dictionary_is_an_error() {
+        let error = ConfigError::from(OpenError::ConfigStoreDoesNotExist);
+
+        assert_eq!(
+            error.to_string(),
+            "failed to open dictionary: config store could not be found"
+        );
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    fn empty_dictionary_is_an_error() {
+        let error = load(&[]).unwrap_err();
+
+        let ConfigError::Multiple(errors) = error else {
+            panic!("expected multiple errors, got {error}");
+        };
+        assert_eq!(errors.len(), REQUIRED_ITEMS.len());
+    }
+
+    #[test]
+    fn failover_status_codes_default_to_retriable_server_errors() {
+        let config = load(&REQUIRED_ITEMS).unwrap();
+
+        assert_eq!(config.failover_status_codes, [500, 502, 503, 504]);
+    }
+
+    #[test]
+    fn empty_value_is_reported() {
+        let mut items = without(PRIMARY_HOST);
+        items.push((PRIMARY_HOST, "  "));
+
+        let error = load(&items).unwrap_err();
+
+        assert!(
+            matches!(&error, ConfigError::EmptyValue(key) if key == PRIMARY_HOST),
+            "{error}"
+        );
+        assert_eq!(error.to_string(), "empty dictionary item s3-primary-host");
+    }
+
+    #[test]
+    fn invalid_value_is_reported() {
+        let mut items = REQUIRED_ITEMS.to_vec();
+        items.push((STATIC_TTL, "0"));
+
+        let error = load(&items).unwrap_err();
+
+        assert!(
+            matches!(
+                &error,
+                ConfigError::InvalidValue { key, value, expected }
+                    if key == STATIC_TTL && value == "0" && *expected == "a TTL greater than zero"
+            ),
+            "{error}"
+        );
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "invalid dictionary item {STATIC_TTL}: expected a TTL greater than zero, got 0"
+            )
+        );
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn different_errors_are_reported_together() {
+        let mut items = without(PRIMARY_HOST);
+        items.retain(|(key, _)| *key != FALLBACK_HOSTS);
+        items.push((FALLBACK_HOSTS, ""));
+        items.push((STATIC_TTL, "0"));
+
+        let error = load(&items).unwrap_err();
+
+        let ConfigError::Multiple(errors) = &error else {
+            panic!("expected multiple errors, got {error}");
+        };
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                ConfigError::MissingKey(missing),
+                ConfigError::EmptyValue(empty),
+                ConfigError::InvalidValue { key, .. },
+            ] if missing == PRIMARY_HOST && empty == FALLBACK_HOSTS && key == STATIC_TTL
+        ));
+    }
+
+    #[test]
+    fn well_known_files_are_read_from_their_items() {
+        let config = load_with_all(&[
+            (WELL_KNOWN_FILES, "security.txt, missing.txt"),
+            (
+                "well-known-file-security.txt",
+                "Contact: mailto:security@example.com",
+            ),
+        ]);
+
+        assert_eq!(
+            config.well_known_files,
+            HashMap::from([(
+                "/.well-known/security.txt".to_string(),
+                "Contact: mailto:security@example.com\n".to_string()
+            )])
+        );
+        assert_eq!(
+            config.warnings,
+            ["ignored well-known file without a body: missing.txt"]
+        );
+    }
+
+    #[test]
+    fn unsupported_cache_precedences_are_rejected() {
+        for precedence in ["min", "max"] {
+            let mut items = REQUIRED_ITEMS.to_vec();
+            items.push((CACHE_PRECEDENCE, precedence));
+
+            let error = load(&items).unwrap_err();
+
+            assert!(
+                matches!(&error, ConfigError::InvalidValue { key, .. } if key == CACHE_PRECEDENCE),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_cache_precedence_only_warns() {
+        let config = load_with((CACHE_PRECEDENCE, "origin"));
+
+        assert_eq!(config.cache_precedence, CachePrecedence::ConfigWins);
+        assert_eq!(config.warnings.len(), 1);
+    }
+
+    #[test]
+    fn stale_if_error_is_ignored() {
+        let config = load_with((STALE_IF_ERROR, "86400"));
+
+        assert_eq!(config.warnings.len(), 1);
+        assert!(config.warnings[0].starts_with("ignored dictionary item stale-if-error"));
+    }
 }
@@ -0,0 +1,116 @@
+/// Entity tag from an `ETag`, `If-None-Match`, or `If-Range` header
+///
+/// Weak entity tags are prefixed with `W/`. The opaque part includes the surrounding quotes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntityTag<'a> {
+    pub weak: bool,
+    pub opaque: &'a str,
+}
+
+impl<'a> EntityTag<'a> {
+    pub fn parse(value: &'a str) -> Option<Self> {
+        let value = value.trim();
+
+        let (weak, opaque) = match value.strip_prefix("W/") {
+            Some(opaque) => (true, opaque),
+            None => (false, value),
+        };
+
+        let is_quoted = opaque.len() >= 2 && opaque.starts_with('"') && opaque.ends_with('"');
+        is_quoted.then_some(Self { weak, opaque })
+    }
+
+    /// Compare two entity tags with the strong comparison from RFC 9110
+    ///
+    /// Both tags must be strong and have the same opaque part.
+    pub fn strong_eq(&self, other: &Self) -> bool {
+        !self.weak && !other.weak && self.opaque == other.opaque
+    }
+
+    /// Compare two entity tags with the weak comparison from RFC 9110
+    ///
+    /// The opaque parts must be the same, regardless of whether the tags are weak or strong.
+    pub fn weak_eq(&self, other: &Self) -> bool {
+        self.opaque == other.opaque
+    }
+}
+
+/// Check if an `If-None-Match` header matches the entity tag of a resource
+///
+/// The header contains `*` or a comma-separated list of entity tags, which are compared with the
+/// weak comparison.
+pub fn if_none_match(header: &str, etag: &EntityTag) -> bool {
+    header.trim() == "*"
+        || header
+            .split(',')
+            .filter_map(EntityTag::parse)
+            .any(|tag| tag.weak_eq(etag))
+}
+
+/// Check if an `If-Range` header matches the entity tag of a resource
+///
+/// The entity tag in the header is compared with the strong comparison, so a weak tag never
+/// matches. Returns `None` if the header contains a date instead of an entity tag.
+pub fn if_range(header: &str, etag: &EntityTag) -> Option<bool> {
+    EntityTag::parse(header).map(|tag| tag.strong_eq(etag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRONG: EntityTag = EntityTag {
+        weak: false,
+        opaque: "\"abc\"",
+    };
+
+    const WEAK: EntityTag = EntityTag {
+        weak: true,
+        opaque: "\"abc\"",
+    };
+
+    #[test]
+    fn entity_tags_are_parsed() {
+        assert_eq!(EntityTag::parse("\"abc\""), Some(STRONG));
+        assert_eq!(EntityTag::parse(" W/\"abc\" "), Some(WEAK));
+        assert_eq!(EntityTag::parse("abc"), None);
+        assert_eq!(EntityTag::parse("\""), None);
+        assert_eq!(EntityTag::parse("W/abc"), None);
+    }
+
+    #[test]
+    fn strong_comparison_rejects_weak_tags() {
+        assert!(STRONG.strong_eq(&STRONG));
+        assert!(!STRONG.strong_eq(&WEAK));
+        assert!(!WEAK.strong_eq(&WEAK));
+    }
+
+    #[test]
+    fn weak_comparison_ignores_weakness() {
+        assert!(STRONG.weak_eq(&WEAK));
+        assert!(WEAK.weak_eq(&WEAK));
+        assert!(!STRONG.weak_eq(&EntityTag::parse("\"def\"").unwrap()));
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        assert!(if_none_match("W/\"abc\"", &STRONG));
+        assert!(if_none_match("\"abc\"", &WEAK));
+        assert!(if_none_match("\"def\", W/\"abc\"", &STRONG));
+        assert!(if_none_match("*", &STRONG));
+        assert!(!if_none_match("\"def\"", &STRONG));
+    }
+
+    #[test]
+    fn if_range_uses_strong_comparison() {
+        assert_eq!(if_range("\"abc\"", &STRONG), Some(true));
+        assert_eq!(if_range("W/\"abc\"", &STRONG), Some(false));
+        assert_eq!(if_range("\"abc\"", &WEAK), Some(false));
+        assert_eq!(if_range("\"def\"", &STRONG), Some(false));
+    }
+
+    #[test]
+    fn if_range_ignores_dates() {
+        assert_eq!(if_range("Wed, 21 Oct 2015 07:28:00 GMT", &STRONG), None);
+    }
+}
@@ -0,0 +1,166 @@
+use time::OffsetDateTime;
+
+use crate::config::Config;
+use crate::shared_state::{SharedState, Stored};
+
+// Key in the object store under which the usage of the fallback hosts is saved
+const USAGE_KEY: &str = "fallback-usage";
+
+/// Number of requests that used a fallback host in the current window
+///
+/// The usage is saved as a string in the format `<request count>,<unix timestamp when the window
+/// started>`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub request_count: u32,
+    pub window_started_at: i64,
+}
+
+impl Stored for Usage {
+    fn parse(value: &str) -> Option<Self> {
+        let (request_count, window_started_at) = value.split_once(',')?;
+
+        Some(Self {
+            request_count: request_count.parse().ok()?,
+            window_started_at: window_started_at.parse().ok()?,
+        })
+    }
+
+    fn serialize(&self) -> String {
+        format!("{},{}", self.request_count, self.window_started_at)
+    }
+}
+
+impl Usage {
+    /// Count a request in the current window
+    ///
+    /// A new window is started once the previous one has expired. Returns the new usage and whether
+    /// the request is within the cap.
+    pub fn admit(self, cap: u32, window: u32, now: i64) -> (Self, bool) {
+        let usage = if now >= self.window_started_at + i64::from(window.max(1)) {
+            Self {
+                request_count: 0,
+                window_started_at: now,
+            }
+        } else {
+            self
+        };
+
+        if usage.request_count >= cap {
+            return (usage, false);
+        }
+
+        let usage = Self {
+            request_count: usage.request_count + 1,
+            ..usage
+        };
+
+        (usage, true)
+    }
+}
+
+/// Check if a request can use a fallback host
+///
+/// When the primary bucket is degraded, every request is sent to the fallback hosts, which might
+/// not be able to handle the whole traffic. The number of requests that use a fallback is capped
+/// per window, and requests above the cap are rejected instead.
+///
+/// The usage is shared between all instances of the function, which means the cap is a soft limit
+/// and a few more requests than the cap might reach the fallback hosts.
+pub fn admit(config: &Config) -> bool {
+    let Some(store_name) = config.fallback_limit_store.as_ref() else {
+        return true;
+    };
+
+    if config.fallback_limit == 0 {
+        return true;
+    }
+
+    let Some(mut store) = SharedState::open(store_name, "the fallback limiter") else {
+        return true;
+    };
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    let (usage, admitted) = store.load::<Usage>(USAGE_KEY).unwrap_or_default().admit(
+        config.fallback_limit,
+        config.fallback_limit_window,
+        now,
+    );
+
+    // Rejected requests don't change the usage, so there is nothing to save
+    if admitted {
+        store.save(USAGE_KEY, &usage);
+    }
+
+    admitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_admits_requests_up_to_cap() {
+        let usage = Usage::default().admit(2, 60, 100).0;
+        let (usage, admitted) = usage.admit(2, 60, 110);
+        assert!(admitted);
+        assert_eq!(usage.request_count, 2);
+
+        let (usage, admitted) = usage.admit(2, 60, 120);
+        assert!(!admitted);
+        assert_eq!(usage.request_count, 2);
+    }
+
+    #[test]
+    fn usage_starts_new_window_after_expiry() {
+        let usage = Usage {
+            request_count: 2,
+            window_started_at: 100,
+        };
+
+        assert!(!usage.admit(2, 60, 159).1);
+        assert_eq!(
+            usage.admit(2, 60, 160),
+            (
+                Usage {
+                    request_count: 1,
+                    window_started_at: 160,
+                },
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn usage_round_trips_through_store() {
+        let usage = Usage {
+            request_count: 7,
+            window_started_at: 1_700_000_000,
+        };
+
+        assert_eq!(Usage::parse(&usage.serialize()), Some(usage));
+        assert_eq!(Usage::parse("7"), None);
+    }
+
+    #[test]
+    fn cap_engages_when_fallback_is_saturated() {
+        let config = Config::for_tests(&[
+            ("fallback-limit-store", "fallback-limits"),
+            ("fallback-limit", "2"),
+            ("fallback-limit-window", "3600"),
+        ]);
+
+        assert!(admit(&config));
+        assert!(admit(&config));
+        assert!(!admit(&config));
+    }
+
+    #[test]
+    fn fallback_is_unlimited_without_store() {
+        let config = Config::for_tests(&[("fallback-limit", "1")]);
+
+        assert!(admit(&config));
+        assert!(admit(&config));
+    }
+}
@@ -0,0 +1,132 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Range of IP addresses in CIDR notation
+///
+/// Both IPv4 ranges like `192.0.2.0/24` and IPv6 ranges like `2001:db8::/32` are supported. An
+/// address without a prefix length matches only itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Check if the network contains the address
+    ///
+    /// IPv4 addresses that are mapped into IPv6 are treated like IPv4 addresses.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+
+        let address = IpAddr::from_str(address.trim())
+            .map_err(|_| ())?
+            .to_canonical();
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.trim().parse().map_err(|_| ())?,
+            None => max_prefix_len,
+        };
+
+        if prefix_len > max_prefix_len {
+            return Err(());
+        }
+
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(value: &str) -> IpNetwork {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_network_contains_addresses_in_range() {
+        let network = network("192.0.2.0/24");
+
+        assert!(network.contains(ip("192.0.2.0")));
+        assert!(network.contains(ip("192.0.2.255")));
+        assert!(!network.contains(ip("192.0.3.1")));
+        assert!(!network.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ipv6_network_contains_addresses_in_range() {
+        let network = network("2001:db8::/32");
+
+        assert!(network.contains(ip("2001:db8::1")));
+        assert!(network.contains(ip("2001:db8:ffff::1")));
+        assert!(!network.contains(ip("2001:db9::1")));
+        assert!(!network.contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn address_without_prefix_matches_only_itself() {
+        let network = network("192.0.2.1");
+
+        assert!(network.contains(ip("192.0.2.1")));
+        assert!(!network.contains(ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn zero_prefix_matches_all_addresses_of_family() {
+        assert!(network("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(network("::/0").contains(ip("2001:db8::1")));
+        assert!(!network("::/0").contains(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn mapped_ipv4_addresses_are_treated_as_ipv4() {
+        assert!(network("192.0.2.0/24").contains(ip("::ffff:192.0.2.1")));
+        assert!(network("::ffff:192.0.2.1").contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        for value in [
+            "",
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/x",
+            "example.com",
+        ] {
+            assert_eq!(value.parse::<IpNetwork>(), Err(()), "network: {value}");
+        }
+    }
+}
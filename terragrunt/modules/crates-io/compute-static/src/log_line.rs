@@ -1,23 +1,280 @@
-use std::net::IpAddr;
+use std::error::Error;
 
 use derive_builder::Builder;
 use serde::Serialize;
 use time::OffsetDateTime;
 
+use crate::stats::StatsLine;
+
 #[derive(Debug, Serialize)]
 #[serde(tag = "version")]
 pub enum LogLine {
     #[serde(rename = "1")]
     V1(LogLineV1),
+    #[serde(rename = "2")]
+    V2(Box<LogLineV2>),
+    #[serde(rename = "stats")]
+    Stats(StatsLine),
 }
 
-#[derive(Debug, Builder, Serialize)]
+impl LogLine {
+    pub fn schema_version(&self) -> u8 {
+        match self {
+            LogLine::V1(_) => 1,
+            LogLine::V2(_) => 2,
+            LogLine::Stats(_) => 1,
+        }
+    }
+}
+
+/// Envelope that is serialized for every log line
+///
+/// The envelope adds the schema version as an integer at the top level, so that consumers don't
+/// have to infer it from the tag of the variant. Stats lines have their own schema, which is
+/// versioned separately from the request logs.
+#[derive(Debug, Serialize)]
+pub struct Envelope {
+    schema_version: u8,
+    #[serde(flatten)]
+    log_line: LogLine,
+}
+
+impl Envelope {
+    /// Serialize the log line as a single line of JSON
+    ///
+    /// The log pipeline ingests newline-delimited JSON. The compact serialization never spans
+    /// multiple lines, since newlines and other control characters in values like the URL are
+    /// escaped.
+    pub fn to_json_line(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+}
+
+impl From<LogLine> for Envelope {
+    fn from(log_line: LogLine) -> Self {
+        Self {
+            schema_version: log_line.schema_version(),
+            log_line,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Builder, Serialize)]
 pub struct LogLineV1 {
     #[serde(with = "time::serde::rfc3339")]
     date_time: OffsetDateTime,
     url: String,
+    #[builder(default)]
     bytes: Option<usize>,
-    ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
     method: Option<String>,
+    #[builder(default)]
     status: Option<u16>,
+    #[builder(default)]
+    backend_duration_ms: Option<u64>,
+    #[builder(default)]
+    edge_duration_ms: Option<u64>,
+    #[builder(default)]
+    error_detail: Option<String>,
+    #[builder(default)]
+    request_id: Option<String>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query_string_stripped: Option<bool>,
+}
+
+#[derive(Debug, Builder, Serialize)]
+pub struct LogLineV2 {
+    #[serde(flatten)]
+    v1: LogLineV1,
+    #[builder(default)]
+    cache_status: Option<String>,
+    #[builder(default)]
+    backend_host: Option<String>,
+    #[builder(default)]
+    used_fallback: bool,
+    #[builder(default)]
+    primary_status: Option<u16>,
+    final_status: u16,
+    #[builder(default)]
+    accept: Option<String>,
+    #[builder(default)]
+    pop: Option<String>,
+    #[builder(default)]
+    original_path: Option<String>,
+    #[builder(default)]
+    final_path: Option<String>,
+    #[builder(default)]
+    request_header_count: Option<usize>,
+    #[builder(default)]
+    response_header_count: Option<usize>,
+    #[builder(default)]
+    ttl_applied: Option<u32>,
+    #[builder(default)]
+    ttl_rule: Option<String>,
+    #[builder(default)]
+    user_agent: Option<String>,
+    #[builder(default)]
+    cargo_version: Option<String>,
+}
+
+/// Builder for all versions of the log line
+///
+/// The fields that are shared between all versions are collected in the builder for the first
+/// version, while the builder for the second version only collects the fields that were added to
+/// it. The version that gets built is decided when the log line is finalized.
+#[derive(Clone, Default)]
+pub struct LogLineBuilder {
+    pub v1: LogLineV1Builder,
+    pub v2: LogLineV2Builder,
+}
+
+impl LogLineBuilder {
+    pub fn build(&self, schema_version: u8) -> Result<LogLine, Box<dyn Error>> {
+        let v1 = self.v1.build()?;
+
+        match schema_version {
+            2 => Ok(LogLine::V2(Box::new(self.v2.clone().v1(v1).build()?))),
+            _ => Ok(LogLine::V1(v1)),
+        }
+    }
+}
+
+/// Truncate a value to the given number of characters
+///
+/// Some fields contain arbitrary strings, which are truncated to keep the log lines small.
+pub fn truncate(value: &str, max_chars: usize) -> String {
+    value.chars().take(max_chars).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_line() -> LogLineBuilder {
+        let mut log_line = LogLineBuilder::default();
+        log_line
+            .v1
+            .date_time(OffsetDateTime::UNIX_EPOCH)
+            .url("https://static.crates.io/index/se/rd/serde".into())
+            .bytes(Some(42))
+            .ip(None)
+            .method(Some("GET".into()))
+            .status(Some(200));
+        log_line
+    }
+
+    fn to_json_line(log_line: &LogLineBuilder, schema_version: u8) -> String {
+        Envelope::from(log_line.build(schema_version).unwrap())
+            .to_json_line()
+            .unwrap()
+    }
+
+    #[test]
+    fn query_string_stripped_is_omitted_unless_set() {
+        let mut log_line = log_line();
+        assert!(!to_json_line(&log_line, 1).contains("query_string_stripped"));
+
+        log_line.v1.query_string_stripped(Some(true));
+        assert!(to_json_line(&log_line, 1).contains(r#""query_string_stripped":true"#));
+    }
+
+    fn to_json(log_line: &LogLineBuilder, schema_version: u8) -> serde_json::Value {
+        serde_json::from_str(&to_json_line(log_line, schema_version)).unwrap()
+    }
+
+    #[test]
+    fn v1_round_trips_through_json() {
+        let json = to_json(&log_line(), 1);
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "schema_version": 1,
+                "version": "1",
+                "date_time": "1970-01-01T00:00:00Z",
+                "url": "https://static.crates.io/index/se/rd/serde",
+                "bytes": 42,
+                "method": "GET",
+                "status": 200,
+                "backend_duration_ms": null,
+                "edge_duration_ms": null,
+                "error_detail": null,
+                "request_id": null,
+            })
+        );
+    }
+
+    #[test]
+    fn v2_round_trips_through_json() {
+        let mut log_line = log_line();
+        log_line
+            .v2
+            .cache_status(Some("MISS".into()))
+            .backend_host(Some("fallback.s3.example.com".into()))
+            .used_fallback(true)
+            .primary_status(Some(503))
+            .final_status(200);
+
+        let json = to_json(&log_line, 2);
+
+        assert_eq!(json["schema_version"], 2);
+        assert_eq!(json["version"], "2");
+        assert_eq!(json["url"], "https://static.crates.io/index/se/rd/serde");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["cache_status"], "MISS");
+        assert_eq!(json["backend_host"], "fallback.s3.example.com");
+        assert_eq!(json["used_fallback"], true);
+        assert_eq!(json["primary_status"], 503);
+        assert_eq!(json["final_status"], 200);
+    }
+
+    #[test]
+    fn v1_omits_fields_of_v2() {
+        let mut log_line = log_line();
+        log_line
+            .v2
+            .cache_status(Some("HIT".into()))
+            .final_status(200);
+
+        let json = to_json(&log_line, 1);
+
+        assert!(json.get("cache_status").is_none());
+        assert!(json.get("final_status").is_none());
+    }
+
+    #[test]
+    fn schema_version_is_serialized() {
+        let mut log_line = log_line();
+        log_line.v2.final_status(200);
+
+        assert!(to_json_line(&log_line, 1).starts_with(r#"{"schema_version":1,"#));
+        assert!(to_json_line(&log_line, 2).starts_with(r#"{"schema_version":2,"#));
+    }
+
+    #[test]
+    fn unknown_versions_are_built_as_v1() {
+        assert_eq!(log_line().build(0).unwrap().schema_version(), 1);
+        assert_eq!(log_line().build(3).unwrap().schema_version(), 1);
+    }
+
+    #[test]
+    fn json_line_escapes_newlines() {
+        let mut log_line = log_line();
+        log_line
+            .v1
+            .url("https://static.crates.io/crates/foo\nbar\r\n".into())
+            .error_detail(Some("first line\nsecond line".into()));
+        log_line.v2.final_status(200);
+
+        for schema_version in [1, 2] {
+            let json_line = to_json_line(&log_line, schema_version);
+            assert!(!json_line.contains(['\n', '\r']), "line: {json_line}");
+
+            let json = to_json(&log_line, schema_version);
+            assert_eq!(json["url"], "https://static.crates.io/crates/foo\nbar\r\n");
+            assert_eq!(json["error_detail"], "first line\nsecond line");
+        }
+    }
 }
@@ -1,201 +1,6212 @@
-use fastly::http::{Method, StatusCode};
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::fmt::Display;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use fastly::experimental::RequestCacheKey;
+use fastly::http::purge::purge_surrogate_key;
+use fastly::http::request::PollResult;
+use fastly::http::{HeaderName, HeaderValue, Method, StatusCode};
+use fastly::mime::{APPLICATION_JSON, TEXT_PLAIN_UTF_8};
 use fastly::{Error, Request, Response};
-use log::{info, warn, LevelFilter};
+use log::{info, warn, LevelFilter, Log, Metadata, Record};
 use log_fastly::Logger;
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use time::OffsetDateTime;
 
-use crate::config::Config;
-use crate::log_line::{LogLine, LogLineV1Builder};
+use crate::backend_selector::Candidates;
+use crate::cache_policy::CachePrecedence;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::{BucketHosts, Config, LogIpMode, SensitiveHeaderPolicy, TrailingSlashPolicy};
+use crate::etag::EntityTag;
+use crate::log_line::{truncate, Envelope, LogLineBuilder};
+use crate::path::{canonicalize, decodes_to_utf8, has_traversal_segment, percent_decode};
+use crate::stats::record_backend_duration;
 
+mod backend_selector;
+mod cache_policy;
+mod circuit_breaker;
 mod config;
+mod etag;
+mod fallback_limiter;
+mod ip_network;
 mod log_line;
+mod metrics;
+mod path;
+mod rate_limiter;
+mod shared_state;
+mod stats;
+
+/// Maximum number of characters of an error that are recorded in the request logs
+const MAX_ERROR_DETAIL_LENGTH: usize = 512;
+
+/// Name of the header with the unique identifier of a request
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Maximum length of a request identifier that is accepted from clients
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Maximum number of characters of a rejected path that are logged
+const MAX_LOGGED_PATH_LENGTH: usize = 256;
+
+/// Headers for content negotiation, conditional, and range requests that are explicitly forwarded
+/// to S3
+const FORWARDED_HEADERS: [&str; 5] = [
+    "Accept",
+    "If-None-Match",
+    "If-Modified-Since",
+    "Range",
+    "If-Range",
+];
+
+/// Hop-by-hop headers from RFC 7230 that apply to a single connection and are never forwarded to S3
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Body of HTTP 404 responses that replaces the error from S3
+const NOT_FOUND_BODY: &str = "Not found: this file does not exist on crates.io\n";
+
+/// Interval at which pending requests to the backends are polled
+const BACKEND_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Class of paths for the rate limit of purges
+const PURGE_RATE_LIMIT_CLASS: &str = "purge";
+
+/// Class of paths for the rate limit of health checks
+const HEALTH_CHECK_RATE_LIMIT_CLASS: &str = "health-check";
+
+/// Request headers that must not reach the shared cache
+const SENSITIVE_HEADERS: [&str; 2] = ["Cookie", "Authorization"];
+
+/// Status codes from the primary host that are retried before the fallbacks are queried
+const RETRIABLE_STATUS_CODES: [u16; 4] = [500, 502, 503, 504];
+
+/// Maximum number of characters of the `Accept` header that are logged
+const MAX_LOGGED_ACCEPT_LENGTH: usize = 256;
+
+/// Maximum number of characters of the `User-Agent` header that are logged
+const MAX_LOGGED_USER_AGENT_LENGTH: usize = 256;
+
+/// Response headers that are never stripped, regardless of the configured prefixes
+const PRESERVED_RESPONSE_HEADERS: [&str; 3] = ["content-type", "etag", "content-length"];
+
+/// Time in seconds that clients may cache `robots.txt`
+const ROBOTS_TXT_MAX_AGE: u32 = 3600;
+
+/// Time in seconds that clients may cache the files in `/.well-known/`
+const WELL_KNOWN_MAX_AGE: u32 = 3600;
+
+/// Time in seconds that browsers may cache the result of a CORS preflight request
+const CORS_MAX_AGE: &str = "3000";
 
 #[fastly::main]
-fn main(request: Request) -> Result<Response, Error> {
-    let config = Config::from_dictionary();
+fn main(mut request: Request) -> Result<Response, Error> {
+    let started_at = Instant::now();
+    let config = match Config::from_dictionary() {
+        Ok(config) => config,
+        Err(error) => return Ok(reject_without_config(&error)),
+    };
 
-    // Forward purge requests immediately to a backend
-    // https://developer.fastly.com/learning/concepts/purging/#forwarding-purge-requests
-    if request.get_method() == "PURGE" {
-        return send_request_to_s3(&config, &request);
+    init_logging(&config);
+    for warning in &config.warnings {
+        warn!("{warning}");
     }
 
-    init_logging(&config);
-    let mut log = collect_request(&request);
+    normalize_method(&mut request);
+
+    let request_id = assign_request_id(&mut request);
+    let mut log = LogGuard {
+        config: &config,
+        log_line: collect_request(&config, &request, &request_id, request.get_client_ip_addr()),
+        is_sampled: true,
+    };
+
+    let origin = request
+        .get_header("Origin")
+        .and_then(|origin| origin.to_str().ok())
+        .map(String::from);
+    let path = request.get_path().to_string();
+    let is_purge = request.get_method() == "PURGE";
 
-    let has_origin_header = request.get_header("Origin").is_some();
-    let mut response = handle_request(&config, request);
+    let mut response = route_request(&config, request, &mut log.log_line);
 
-    if has_origin_header {
-        add_cors_headers(&mut response);
+    add_security_headers(&config, &mut response);
+    add_maintenance_notice(&config, &mut response);
+
+    if let Some(origin) = origin {
+        add_cors_headers(&config, &mut response, &origin);
+    }
+
+    if let Ok(response) = &mut response {
+        response.set_header(REQUEST_ID_HEADER, request_id.as_str());
     }
 
-    let log = collect_response(&mut log, &response);
-    build_and_send_log(log, &config);
+    add_response_headers(&config, &mut response);
+
+    log.log_line = finish_log_line(&config, &mut log.log_line, &response, &path, started_at);
+
+    // Purges are rare and always logged, so that we can audit them
+    log.is_sampled = is_purge || is_sampled(&config, &request_id, &response);
+
+    metrics::flush(&config);
 
     response
 }
 
-/// Initialize the logger
+/// Guard that sends the log line of a request when it goes out of scope
 ///
-/// Fastly provides its own logger implementation that streams logs to pre-configured endpoints. We
-/// have created one endpoint for request logs and one for service logs.
+/// The guard is dropped on every path out of `main`, including early returns, so that a log line is
+/// sent for every request. Requests are logged unless sampling decided otherwise.
 ///
-/// Logs are echoed to stdout as well to enable tailing the logs with the Fastly CLI.
-fn init_logging(config: &Config) {
-    Logger::builder()
-        .max_level(LevelFilter::Debug)
-        .endpoint(config.request_logs_endpoint.clone())
-        .default_endpoint(config.service_logs_endpoint.clone())
-        .echo_stdout(true)
-        .init();
+/// Panics cannot be logged. The function is compiled for wasm32-wasi, where panics abort the
+/// instance without unwinding, so the guard is never dropped.
+struct LogGuard<'a> {
+    config: &'a Config,
+    log_line: LogLineBuilder,
+    is_sampled: bool,
 }
 
-/// Collect data for the logs from the request
-fn collect_request(request: &Request) -> LogLineV1Builder {
-    LogLineV1Builder::default()
-        .date_time(OffsetDateTime::now_utc())
-        .url(request.get_url_str().into())
-        .ip(request.get_client_ip_addr())
-        .method(Some(request.get_method().to_string()))
-        .to_owned()
+impl Drop for LogGuard<'_> {
+    fn drop(&mut self) {
+        if self.is_sampled {
+            build_and_send_log(std::mem::take(&mut self.log_line), self.config);
+        }
+    }
 }
 
-/// Handle the request
+/// Reject the request because the configuration could not be loaded
+///
+/// The logger cannot be initialized without a valid configuration, so the error is printed to
+/// stderr, which can be tailed with the Fastly CLI. The client gets a clean HTTP 500 Internal
+/// Server Error instead of a crashed function.
+fn reject_without_config(error: &dyn Display) -> Response {
+    eprintln!("{error}");
+
+    Response::from_body("Internal server error").with_status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Route the request
 ///
-/// This method handles the incoming request and returns a response for the client. It first ensures
-/// that the request uses whitelisted request methods, then sets a TTL to cache the response, before
-/// finally forwarding the request to S3.
-fn handle_request(config: &Config, mut request: Request) -> Result<Response, Error> {
-    if let Some(response) = limit_http_methods(&request) {
+/// Requests from denied IPs and with oversized headers are rejected first, including purges and
+/// health checks. Purge requests are forwarded immediately to a backend after they have been
+/// authorized, while all other requests are handled by `handle_request`.
+fn route_request(
+    config: &Config,
+    mut request: Request,
+    log: &mut LogLineBuilder,
+) -> Result<Response, Error> {
+    if let Some(response) = deny_client_ips(config, &request) {
         return Ok(response);
     }
 
-    set_ttl(config, &mut request);
-    rewrite_urls_with_plus_character(&mut request);
+    if let Some(response) = limit_header_size(config, &request) {
+        return Ok(response);
+    }
 
-    // Database dump is too big to cache on Fastly
-    if request.get_url_str().ends_with("db-dump.tar.gz") {
-        redirect_db_dump_to_cloudfront(config)
-    } else {
-        send_request_to_s3(config, &request)
+    // Forward purge requests immediately to a backend
+    // https://developer.fastly.com/learning/concepts/purging/#forwarding-purge-requests
+    if request.get_method() == "PURGE" {
+        if let Some(response) = limit_request_rate(config, &request, PURGE_RATE_LIMIT_CLASS) {
+            return Ok(response);
+        }
+
+        if let Some(response) = authorize_purge(config, &mut request) {
+            return Ok(response);
+        }
+
+        if let Some(response) = limit_purge_body(config, &mut request) {
+            return Ok(response);
+        }
+
+        if let Some(response) = limit_purge_paths(config, &request) {
+            return Ok(response);
+        }
+
+        // The purge must have the same cache key as the requests for the file that it purges
+        normalize_path(&mut request);
+        canonicalize_s3_path(&mut request);
+        strip_query_string(config, &mut request);
+        negotiate_content_encoding(&mut request);
+        compute_cache_key(config, &mut request);
+
+        return send_request_to_s3(config, &request, log);
     }
+
+    handle_request(config, request, log)
 }
 
-/// Limit HTTP methods
+/// Deny requests from blocked client IPs
 ///
-/// Clients are only allowed to request resources using GET and HEAD requests. If any other HTTP
-/// method is received, HTTP 403 Unauthorized is returned.
-///
-/// We don't return HTTP 405 Method Not Allowed to maintain parity with CloudFront.
-fn limit_http_methods(request: &Request) -> Option<Response> {
-    let method = request.get_method();
+/// During incidents, abusive clients can be blocked at the edge by adding their IP addresses or
+/// ranges to the deny list. Their requests are rejected with HTTP 403 Forbidden.
+fn deny_client_ips(config: &Config, request: &Request) -> Option<Response> {
+    if config.ip_deny_list.is_empty() {
+        return None;
+    }
+
+    let ip = request.get_client_ip_addr()?;
 
-    if method != Method::GET && method != Method::HEAD {
-        return Some(
-            Response::from_body("Method not allowed").with_status(StatusCode::UNAUTHORIZED),
+    if is_denied_ip(config, ip) {
+        warn!(
+            "Blocked request from {}",
+            logged_ip(config, Some(ip)).unwrap_or_else(|| "a redacted IP".into())
         );
+        return Some(Response::from_body("Forbidden").with_status(StatusCode::FORBIDDEN));
     }
 
     None
 }
 
-/// Set the TTL
-///
-/// A TTL header is added to the request to ensure that the content is cached for the given amount
-/// of time.
-fn set_ttl(config: &Config, request: &mut Request) {
-    request.set_ttl(config.static_ttl);
+/// Check if the IP address is in one of the blocked ranges
+fn is_denied_ip(config: &Config, ip: IpAddr) -> bool {
+    config
+        .ip_deny_list
+        .iter()
+        .any(|network| network.contains(ip))
 }
 
-/// Rewrite URLs with a plus character
+/// Authorize purge requests
 ///
-/// An issue was reported for crates.io where URLs that encoded the `+` character in a crate's
-/// version as `%2B` were not working correctly. As a backwards-compatible fix, we are transparently
-/// rewriting URLs that contain the `+` character to use `%2B` instead. This ensures that crates in
-/// Amazon S3 are accessed in a consistent way across all clients and Content Delivery Networks.
+/// Purge requests must include a shared secret in the `X-Purge-Token` header that matches the token
+/// in the dictionary. If the header is missing or does not match, HTTP 403 Forbidden is returned.
+/// All purge requests are rejected if no token has been configured.
 ///
-/// See more: https://github.com/rust-lang/crates.io/issues/4891
-fn rewrite_urls_with_plus_character(request: &mut Request) {
-    let url = request.get_url_mut();
-    let path = url.path();
+/// The header is removed from the request so that the secret is not forwarded to S3.
+fn authorize_purge(config: &Config, request: &mut Request) -> Option<Response> {
+    let token = request.remove_header("X-Purge-Token");
 
-    if path.contains('+') {
-        let new_path = path.replace('+', "%2B");
-        url.set_path(&new_path);
+    if is_purge_authorized(config, token.as_ref()) {
+        None
+    } else {
+        Some(Response::from_body("Forbidden").with_status(StatusCode::FORBIDDEN))
     }
 }
 
-/// Redirect request to CloudFront
+/// Check if the token of a purge request matches the configured token
+fn is_purge_authorized(config: &Config, token: Option<&HeaderValue>) -> bool {
+    match (&config.purge_token, token) {
+        (Some(expected), Some(token)) => constant_time_eq(expected.as_bytes(), token.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Limit the size of the body of purge requests
 ///
-/// As of early 2023, certain files are too large to be served through Fastly. One of those is the
-/// database dump, which gets redirected to CloudFront.
-fn redirect_db_dump_to_cloudfront(config: &Config) -> Result<Response, Error> {
-    let url = format!("https://{}/db-dump.tar.gz", config.cloudfront_url);
-    Ok(Response::temporary_redirect(url))
+/// Purges are forwarded without their body, but they shouldn't carry a large one in the first
+/// place. Requests whose body exceeds the configured limit are rejected with HTTP 413 Payload Too
+/// Large. The `Content-Length` is checked if it is present. Otherwise, at most one byte more than
+/// the limit is read from the body.
+fn limit_purge_body(config: &Config, request: &mut Request) -> Option<Response> {
+    let limit = config.max_purge_body_bytes;
+
+    let size = match declared_body_size(request) {
+        Some(length) => length,
+        None => {
+            let mut body = Vec::new();
+            match request
+                .take_body()
+                .take(limit.saturating_add(1))
+                .read_to_end(&mut body)
+            {
+                Ok(size) => size as u64,
+                Err(_) => u64::MAX,
+            }
+        }
+    };
+
+    if size <= limit {
+        return None;
+    }
+
+    warn!("Rejected purge with a body of more than {limit} bytes");
+
+    Some(Response::from_body("Payload too large").with_status(StatusCode::PAYLOAD_TOO_LARGE))
 }
 
-/// Forward client request to S3
+/// Get the size of the body from the `Content-Length` header, if it is present and valid
+fn declared_body_size(request: &Request) -> Option<u64> {
+    request
+        .get_header("Content-Length")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|length| length.trim().parse().ok())
+}
+
+/// Limit the paths that can be purged
 ///
-/// The request that was received by the client is forwarded to S3. First, the primary bucket is
-/// queried. If the response indicates a server issue (status code >= 500), the request is sent to
-/// a fallback bucket in a different geographical region.
-fn send_request_to_s3(config: &Config, request: &Request) -> Result<Response, Error> {
-    let primary_request = request.clone_without_body();
+/// Purges are only forwarded for paths that match one of the configured patterns. All purges are
+/// rejected with HTTP 400 Bad Request if no patterns have been configured.
+fn limit_purge_paths(config: &Config, request: &Request) -> Option<Response> {
+    let path = request.get_path();
+    let logged_path = truncate(path, MAX_LOGGED_PATH_LENGTH);
 
-    let mut response = primary_request.send(&config.primary_host)?;
-    let status_code = response.get_status().as_u16();
+    if is_purge_allowed(config, path) {
+        info!("Accepted purge for {logged_path}");
+        None
+    } else {
+        warn!("Rejected purge for {logged_path}");
+        Some(Response::from_body("Bad request").with_status(StatusCode::BAD_REQUEST))
+    }
+}
 
-    if status_code >= 500 {
-        warn!(
-            "Request to host {} returned status code {}",
-            config.primary_host, status_code
-        );
+/// Check if the path matches one of the patterns that can be purged
+fn is_purge_allowed(config: &Config, path: &str) -> bool {
+    config
+        .purge_allowed_paths
+        .iter()
+        .any(|pattern| matches_path_pattern(pattern, path))
+}
 
-        let fallback_request = request.clone_without_body();
-        response = fallback_request.send(&config.fallback_host)?;
+/// Compare two byte slices in constant time
+///
+/// The time it takes to compare the slices only depends on their length, and not on their content.
+/// This prevents timing attacks against secrets.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
     }
 
-    Ok(response)
+    a.iter()
+        .zip(b)
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
 }
 
-/// Add CORS headers to response
+/// Initialize the logger
 ///
-/// We are explicitly adding the three CORS headers to requests that include an `Origin` header to
-/// match functionality with CloudFront.
-fn add_cors_headers(response: &mut Result<Response, Error>) {
-    if let Ok(response) = response {
-        response.set_header("Access-Control-Allow-Origin", "*");
-        response.set_header("Access-Control-Allow-Methods", "GET");
-        response.set_header("Access-Control-Max-Age", "3000");
+/// Fastly provides its own logger implementation that streams logs to pre-configured endpoints. We
+/// have created one endpoint for request logs and one for service logs. An endpoint for metrics can
+/// be configured as well.
+///
+/// Logs are echoed to stdout as well to enable tailing the logs with the Fastly CLI.
+///
+/// If the endpoints are misconfigured, the logger cannot be built. Requests must never fail because
+/// of logging, so the logs are then only printed to stdout, and a single warning is printed to
+/// stderr.
+fn init_logging(config: &Config) {
+    let mut builder = Logger::builder();
+    builder
+        .max_level(LevelFilter::Debug)
+        .endpoint(config.request_logs_endpoint.clone())
+        .default_endpoint(config.service_logs_endpoint.clone())
+        .echo_stdout(true);
+
+    if let Some(metrics_endpoint) = &config.metrics_endpoint {
+        builder.endpoint(metrics_endpoint.clone());
+    }
+
+    let logger = builder.build();
+
+    let result = match logger {
+        Ok(logger) => log::set_boxed_logger(Box::new(logger)),
+        Err(error) => {
+            eprintln!("failed to initialize logging endpoints, logging to stdout only: {error}");
+            log::set_boxed_logger(Box::new(StdoutLogger))
+        }
+    };
+
+    if result.is_ok() {
+        log::set_max_level(LevelFilter::Debug);
     }
 }
 
-/// Collect data for the logs from the response
-fn collect_response(
-    log_line: &mut LogLineV1Builder,
-    response: &Result<Response, Error>,
-) -> LogLineV1Builder {
-    if let Ok(response) = response {
+/// Logger that only prints to stdout
+///
+/// The logger is used when the logging endpoints are misconfigured.
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= LevelFilter::Debug
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("{} {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Assign a unique identifier to the request
+///
+/// The identifier is used to correlate the logs at the edge with the logs of S3. Clients can
+/// provide their own identifier in the `X-Request-Id` header. If the header is missing, or if it is
+/// too long or contains unexpected characters, a new identifier is generated.
+///
+/// The identifier is set on the request, so that it is forwarded to S3, and later returned to the
+/// client in the response.
+fn assign_request_id(request: &mut Request) -> String {
+    let request_id = request
+        .get_header(REQUEST_ID_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+        .filter(|id| {
+            id.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        })
+        .map(String::from)
+        .unwrap_or_else(generate_request_id);
+
+    request.set_header(REQUEST_ID_HEADER, request_id.as_str());
+
+    request_id
+}
+
+/// Generate a random identifier for a request
+///
+/// The identifier is formatted like a version 4 UUID. The random numbers come from the randomly
+/// seeded hasher of the standard library, which avoids pulling in another dependency.
+fn generate_request_id() -> String {
+    let random = |salt: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(salt);
+        hasher.finish()
+    };
+
+    let seed = OffsetDateTime::now_utc().unix_timestamp_nanos() as u64;
+    let (high, low) = (random(seed), random(!seed));
+
+    format!(
+        "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0x0fff,
+        0x8000 | ((low >> 48) & 0x3fff),
+        low & 0xffff_ffff_ffff,
+    )
+}
+
+/// Collect data for the logs from the request
+///
+/// The IP of the client is passed in separately, since it is looked up from the connection and not
+/// from the request itself.
+fn collect_request(
+    config: &Config,
+    request: &Request,
+    request_id: &str,
+    client_ip: Option<IpAddr>,
+) -> LogLineBuilder {
+    let mut log_line = LogLineBuilder::default();
+
+    log_line
+        .v1
+        .request_id(Some(request_id.into()))
+        .date_time(OffsetDateTime::now_utc())
+        .url(request.get_url_str().into())
+        .ip(logged_ip(config, client_ip))
+        .method(Some(request.get_method().to_string()));
+
+    // The header is logged even if it is malformed, since that is useful for debugging clients
+    let accept = request.get_header("Accept").map(|header| {
+        truncate(
+            &String::from_utf8_lossy(header.as_bytes()),
+            MAX_LOGGED_ACCEPT_LENGTH,
+        )
+    });
+    // Fastly exposes the point of presence that runs the function in the environment
+    let pop = std::env::var("FASTLY_POP")
+        .ok()
+        .filter(|pop| !pop.is_empty());
+
+    // The path is logged again once it has been rewritten, which shows how often clients send
+    // paths that are not canonical
+    let path = truncate(request.get_path(), MAX_LOGGED_PATH_LENGTH);
+
+    log_line
+        .v2
+        .accept(accept)
+        .pop(pop)
+        .original_path(Some(path.clone()))
+        .final_path(Some(path))
+        .request_header_count(Some(request.get_headers().count()));
+
+    if config.log_user_agent {
+        let user_agent = request
+            .get_header("User-Agent")
+            .map(|header| normalize_user_agent(&String::from_utf8_lossy(header.as_bytes())));
+        let cargo_version = user_agent.as_deref().and_then(cargo_version);
+
         log_line
-            .bytes(response.get_content_length())
-            .status(Some(response.get_status().as_u16()))
-            .to_owned()
-    } else {
-        log_line.status(Some(500)).to_owned()
+            .v2
+            .user_agent(user_agent)
+            .cargo_version(cargo_version);
     }
+
+    log_line
 }
 
-/// Finalize the builder and log the line
-fn build_and_send_log(log_line: LogLineV1Builder, config: &Config) {
-    match log_line.build() {
-        Ok(log) => {
-            let versioned_log = LogLine::V1(log);
-            info!(target: &config.request_logs_endpoint, "{}", json!(versioned_log).to_string())
+/// Normalize the `User-Agent` header for the logs
+///
+/// Control characters are stripped, so that clients cannot inject anything into the log lines, and
+/// the header is truncated.
+fn normalize_user_agent(user_agent: &str) -> String {
+    let user_agent: String = user_agent.chars().filter(|c| !c.is_control()).collect();
+    truncate(user_agent.trim(), MAX_LOGGED_USER_AGENT_LENGTH)
+}
+
+/// Extract the version of cargo from a `User-Agent` like `cargo/1.75.0 (1d8b05cdd 2023-11-20)`
+fn cargo_version(user_agent: &str) -> Option<String> {
+    let version = user_agent
+        .strip_prefix("cargo/")?
+        .split_whitespace()
+        .next()?;
+
+    let is_version = version
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+'));
+
+    is_version.then(|| version.to_string())
+}
+
+/// Redact the IP address of the client for the logs
+///
+/// Depending on the configured mode, the full address is logged, the address is masked, or it is
+/// omitted from the logs. Masking zeroes the last octet of IPv4 addresses and the last 80 bits of
+/// IPv6 addresses. Returns `None` if the address must not be logged.
+fn logged_ip(config: &Config, ip: Option<IpAddr>) -> Option<String> {
+    match config.log_ip_mode {
+        LogIpMode::Full => Some(format_ip(ip)),
+        LogIpMode::Masked => Some(format_ip(ip.map(mask_ip))),
+        LogIpMode::None => None,
+    }
+}
+
+fn mask_ip(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & 0xffff_ff00)),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !((1 << 80) - 1))),
+    }
+}
+
+/// Format the IP address of the client for the logs
+///
+/// IPv4 addresses that are mapped into IPv6 are logged as IPv4 addresses, and IPv6 addresses are
+/// logged in their canonical lowercase form. Missing addresses are logged as `unknown`, since the
+/// parser of the logs does not accept null values.
+fn format_ip(ip: Option<IpAddr>) -> String {
+    ip.map(|ip| ip.to_canonical().to_string())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// Handle the request
+///
+/// This method handles the incoming request and returns a response for the client. It first answers
+/// CORS preflight requests and ensures that the request uses whitelisted request methods. Health
+/// checks are answered at the edge as well. For all other requests, it sets a TTL to cache the
+/// response, before finally forwarding the request to S3.
+fn handle_request(
+    config: &Config,
+    mut request: Request,
+    log: &mut LogLineBuilder,
+) -> Result<Response, Error> {
+    let original_path =
+        is_debug_request(config, &mut request).then(|| request.get_path().to_string());
+
+    if let Some(response) = answer_cors_preflight(config, &request) {
+        return Ok(response);
+    }
+
+    if let Some(response) = limit_http_methods(config, &request) {
+        return Ok(response);
+    }
+
+    if let Some(response) = reject_request_body(config, &request) {
+        return Ok(response);
+    }
+
+    if let Some(response) = limit_path_length(config, &request) {
+        return Ok(response);
+    }
+
+    if let Some(response) = reject_path_traversal(&request) {
+        return Ok(response);
+    }
+
+    if request.get_path() == config.health_check_path {
+        if let Some(response) = limit_request_rate(config, &request, HEALTH_CHECK_RATE_LIMIT_CLASS)
+        {
+            return Ok(response);
         }
-        Err(error) => {
-            warn!("failed to serialize request log: {error}");
+
+        return Ok(answer_health_check());
+    }
+
+    if let Some(response) = redirect_to_canonical_host(config, &request) {
+        return Ok(response);
+    }
+
+    if let Some(response) = answer_robots_txt(config, &request) {
+        return Ok(response);
+    }
+
+    if let Some(response) = answer_well_known_file(config, &request) {
+        return Ok(response);
+    }
+
+    if config.maintenance_mode {
+        return Ok(answer_during_maintenance(config, &request));
+    }
+
+    handle_sensitive_headers(config, &mut request);
+
+    rewrite_path(&mut request, log);
+    let ttl = set_ttl(config, &mut request, log);
+
+    if let Some(response) = answer_gone_paths(config, &request) {
+        return Ok(response);
+    }
+
+    if let Some(response) = redirect_moved_paths(config, &request) {
+        return Ok(response);
+    }
+
+    if let Some(response) = redirect_root_paths(config, &request) {
+        return Ok(response);
+    }
+
+    if let Some(response) = redirect_trailing_slash(config, &request) {
+        return Ok(response);
+    }
+
+    if redirects_to_cloudfront(config, request.get_path()) {
+        return redirect_to_cloudfront(config, &request);
+    }
+
+    log.v1
+        .query_string_stripped(strip_query_string(config, &mut request));
+
+    negotiate_content_encoding(&mut request);
+    let cache_key = compute_cache_key(config, &mut request);
+
+    if config.negative_ttl > 0 {
+        if let Ok(surrogate_key) = HeaderValue::from_str(&surrogate_key(&cache_key)) {
+            request.set_surrogate_key(surrogate_key);
         }
-    };
+    }
+
+    if !collapses(config, &request) {
+        request.set_pass(true);
+    }
+
+    if let Some(original_path) = original_path {
+        return Ok(answer_debug_request(
+            config,
+            &request,
+            &original_path,
+            ttl,
+            &cache_key,
+        ));
+    }
+
+    let mut response = send_request_to_s3(config, &request, log)?;
+
+    if !if_range_matches(&request, &response) {
+        response = send_request_to_s3(config, &without_range(&request), log)?;
+    }
+
+    if is_expired_negative_response(config, &response) && purge_negative_response(&cache_key) {
+        response = send_request_to_s3(config, &request, log)?;
+    }
+
+    if let Some(response) = reject_empty_response(config, &request, &response) {
+        return Ok(response);
+    }
+
+    if let Some(error_document_path) = &config.error_document_path {
+        serve_error_document(config, &request, error_document_path, &mut response);
+    }
+
+    strip_response_headers(config, &mut response);
+    correct_content_type(config, &request, &mut response);
+
+    if config.synthesize_etag {
+        synthesize_etag(&request, &mut response);
+    }
+    vary_on_accept_encoding(&mut response);
+    // The TTL is read from the `Cache-Control` header of S3, before immutable files replace it
+    log_origin_ttl(config, &response, log);
+    mark_immutable(config, &request, &mut response);
+    advertise_byte_ranges(config, &request, &mut response);
+    apply_negative_ttl(config, &mut response);
+    add_preload_links(config, &request, &mut response);
+    answer_if_none_match(&request, &mut response);
+
+    replace_not_found_body(config, &mut response);
+
+    Ok(response)
+}
+
+/// Add preload hints to the response
+///
+/// Clients that fetch a file often fetch related files next. The paths of those files are
+/// configured for path prefixes, and added to successful responses as `Link` headers so that
+/// clients can start fetching them early. Only the first matching prefix is used.
+fn add_preload_links(config: &Config, request: &Request, response: &mut Response) {
+    if !response.get_status().is_success() {
+        return;
+    }
+
+    let path = request.get_path();
+    let Some((_, targets)) = config
+        .preload_map
+        .iter()
+        .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+    else {
+        return;
+    };
+
+    for target in targets {
+        response.append_header("Link", format!("<{target}>; rel=preload"));
+    }
+}
+
+/// Reject empty responses
+///
+/// A successful response without any content from S3 usually means that an upload was truncated.
+/// If enabled, these responses are replaced with HTTP 502 Bad Gateway, so that clients and the
+/// cache don't keep the empty file.
+fn reject_empty_response(
+    config: &Config,
+    request: &Request,
+    response: &Response,
+) -> Option<Response> {
+    if !rejects_empty_response(config, response) {
+        return None;
+    }
+
+    warn!(
+        "Rejected empty response from S3 for {}",
+        truncate(request.get_path(), MAX_LOGGED_PATH_LENGTH)
+    );
+
+    Some(Response::from_body("Bad gateway").with_status(StatusCode::BAD_GATEWAY))
+}
+
+/// Check if the response is empty and empty responses are rejected
+fn rejects_empty_response(config: &Config, response: &Response) -> bool {
+    config.reject_empty_responses && is_empty_success(response)
+}
+
+/// Check if S3 returned HTTP 200 OK without any content
+fn is_empty_success(response: &Response) -> bool {
+    response.get_status() == StatusCode::OK && response.get_content_length() == Some(0)
+}
+
+/// Get the size of the response if it exceeds the configured threshold
+///
+/// Responses without a `Content-Length` are never oversized, since their size is unknown.
+fn oversized_content_length(config: &Config, response: &Response) -> Option<usize> {
+    let threshold = config.size_warn_threshold?;
+    let size = response.get_content_length()?;
+
+    (size > threshold).then_some(size)
+}
+
+/// Serve the error document for HTTP 404 responses
+///
+/// Instead of the built-in message, the body of HTTP 404 responses can be an error document that
+/// is hosted in S3. The document is fetched from the primary host and served with its own content
+/// type and the status code 404. If the document cannot be fetched, for example because it is
+/// missing itself or S3 is unavailable, the built-in message is served instead. Requests for the
+/// error document never fetch it again, which prevents loops.
+///
+/// The error document replaces the body of S3's errors, so `custom_404` is disabled when an error
+/// document is configured.
+fn serve_error_document(
+    config: &Config,
+    request: &Request,
+    error_document_path: &str,
+    response: &mut Response,
+) {
+    if response.get_status() != StatusCode::NOT_FOUND {
+        return;
+    }
+
+    let document = if request.get_path() == error_document_path {
+        None
+    } else {
+        // The request is built from scratch, since the client's request has its own cache key
+        let mut url = request.get_url().clone();
+        url.set_path(error_document_path);
+        url.set_query(None);
+        let document_request = Request::new(request.get_method().clone(), url);
+
+        usable_error_document(send_with_timeout(
+            config,
+            &document_request,
+            &config.primary_host,
+        ))
+    };
+
+    match document {
+        Some(document) => *response = document.with_status(StatusCode::NOT_FOUND),
+        None => {
+            warn!("Failed to fetch error document {error_document_path}");
+            response.set_body_text_plain(NOT_FOUND_BODY);
+        }
+    }
+}
+
+/// Get the error document from the result of fetching it
+///
+/// Only successful responses can be served as the error document. Requests that failed or timed
+/// out, and errors from S3, are not.
+fn usable_error_document(result: Result<Option<Response>, Error>) -> Option<Response> {
+    match result {
+        Ok(document) => document.filter(|document| document.get_status().is_success()),
+        Err(error) => {
+            warn!("Failed to send request for the error document: {error}");
+            None
+        }
+    }
+}
+
+/// Replace the body of HTTP 404 responses
+///
+/// S3 returns an XML document for files that don't exist, which is hard to read for users and leaks
+/// details about the bucket. If enabled, the body is replaced with a short message, while the
+/// status code is preserved.
+fn replace_not_found_body(config: &Config, response: &mut Response) {
+    if strip_not_found_validators(config, response) {
+        response.set_body_text_plain(NOT_FOUND_BODY);
+    }
+}
+
+/// Remove the validators of a 404 response whose body is replaced
+///
+/// The `ETag` and `Last-Modified` headers from S3 describe its XML document. They would not match
+/// the replaced body, and a conditional request could be answered for the wrong content.
+///
+/// Returns whether the body gets replaced.
+fn strip_not_found_validators(config: &Config, response: &mut Response) -> bool {
+    if !config.custom_404 || response.get_status() != StatusCode::NOT_FOUND {
+        return false;
+    }
+
+    response.remove_header("ETag");
+    response.remove_header("Last-Modified");
+    true
+}
+
+/// Answer CORS preflight requests
+///
+/// Browsers send an `OPTIONS` request with an `Access-Control-Request-Method` header before making
+/// certain cross-origin requests. These requests are answered directly at the edge with HTTP 204 No
+/// Content, and never forwarded to S3. The `Access-Control-Allow-Origin` header is added later by
+/// `add_cors_headers`.
+///
+/// Browsers ask for permission to send custom headers in `Access-Control-Request-Headers`. Only the
+/// requested headers that are in the configured allowlist are echoed back in
+/// `Access-Control-Allow-Headers`, and the header is omitted if none of them are allowed.
+fn answer_cors_preflight(config: &Config, request: &Request) -> Option<Response> {
+    if request.get_method() != Method::OPTIONS
+        || request
+            .get_header("Access-Control-Request-Method")
+            .is_none()
+    {
+        return None;
+    }
+
+    let mut response = Response::from_status(StatusCode::NO_CONTENT)
+        .with_header(
+            "Access-Control-Allow-Methods",
+            config.allowed_methods_header(),
+        )
+        .with_header("Access-Control-Max-Age", CORS_MAX_AGE);
+
+    let allowed_headers: Vec<&str> = request
+        .get_header("Access-Control-Request-Headers")
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|header| {
+            config
+                .cors_allowed_headers
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(header))
+        })
+        .collect();
+
+    if !allowed_headers.is_empty() {
+        response.set_header("Access-Control-Allow-Headers", allowed_headers.join(", "));
+    }
+
+    Some(response)
+}
+
+/// Normalize the HTTP method
+///
+/// Methods are case-sensitive according to the HTTP specification, but some clients send them in
+/// lowercase or mixed case. The method is uppercased once before the request is routed, so that the
+/// purge check and the checks for allowed methods see the same value. Methods that are still
+/// unknown after the normalization are rejected by `limit_http_methods`.
+fn normalize_method(request: &mut Request) {
+    let method = request.get_method_str().to_ascii_uppercase();
+
+    if let Ok(method) = Method::from_bytes(method.as_bytes()) {
+        request.set_method(method);
+    }
+}
+
+/// Limit the size of the request headers
+///
+/// Huge header blocks waste resources at the edge and are never sent by legitimate clients. The
+/// sizes of all header names and values are summed up, and requests that exceed the limit are
+/// rejected with HTTP 431 Request Header Fields Too Large.
+fn limit_header_size(config: &Config, request: &Request) -> Option<Response> {
+    let size = oversized_header_size(config, request)?;
+
+    warn!(
+        "Rejected request with {size} bytes of headers, limit is {}",
+        config.max_header_bytes
+    );
+
+    Some(
+        Response::from_body("Request header fields too large")
+            .with_status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+    )
+}
+
+/// Get the size of the request headers if it exceeds the limit
+fn oversized_header_size(config: &Config, request: &Request) -> Option<usize> {
+    let size: usize = request
+        .get_headers()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+
+    (size > config.max_header_bytes).then_some(size)
+}
+
+/// Limit HTTP methods
+///
+/// Clients are only allowed to request resources using the configured methods, which default to
+/// GET and HEAD. If any other HTTP method is received, the configured status code is returned. The
+/// same methods are advertised to browsers in the CORS headers.
+///
+/// By default, we return HTTP 401 Unauthorized instead of HTTP 405 Method Not Allowed to maintain
+/// parity with CloudFront. When HTTP 405 is configured, the `Allow` header is added to the response
+/// as required by the HTTP specification.
+fn limit_http_methods(config: &Config, request: &Request) -> Option<Response> {
+    if !config.allowed_methods.contains(request.get_method()) {
+        metrics::increment(metrics::REQUEST_REJECTED_METHOD);
+
+        let mut response = method_not_allowed(config);
+        response.set_body("Method not allowed");
+
+        return Some(response);
+    }
+
+    None
+}
+
+/// Build the response for a method that is not allowed, without its body
+fn method_not_allowed(config: &Config) -> Response {
+    let mut response = Response::from_status(config.method_not_allowed_status);
+
+    if config.method_not_allowed_status == StatusCode::METHOD_NOT_ALLOWED {
+        response.set_header("Allow", config.allowed_methods_header());
+    }
+
+    response
+}
+
+/// Reject GET and HEAD requests with a body
+///
+/// Requests for files should never have a body, and forwarding one wastes bandwidth. Only the
+/// headers are inspected, so the body is never read: a request has a body if its `Content-Length`
+/// is not zero, or if it uses `Transfer-Encoding`.
+fn reject_request_body(config: &Config, request: &Request) -> Option<Response> {
+    if !rejects_request_body(config, request) {
+        return None;
+    }
+
+    warn!("Rejected {} request with a body", request.get_method());
+    Some(Response::from_body("Bad request").with_status(StatusCode::BAD_REQUEST))
+}
+
+/// Check if the request is a GET or HEAD request with a body and these are rejected
+fn rejects_request_body(config: &Config, request: &Request) -> bool {
+    let method = request.get_method();
+    if !config.reject_bodied_gets || (method != Method::GET && method != Method::HEAD) {
+        return false;
+    }
+
+    let has_content_length = request
+        .get_header("Content-Length")
+        .and_then(|header| header.to_str().ok())
+        .is_some_and(|length| length.trim() != "0");
+    let has_transfer_encoding = request.get_header("Transfer-Encoding").is_some();
+
+    has_content_length || has_transfer_encoding
+}
+
+/// Limit the length of the path
+///
+/// Pathological URLs are rejected with HTTP 414 URI Too Long before they are rewritten or forwarded
+/// to S3. The length of the decoded path is checked, which excludes the query string.
+fn limit_path_length(config: &Config, request: &Request) -> Option<Response> {
+    let path = request.get_path();
+
+    if is_path_too_long(config, path) {
+        warn!(
+            "Rejected path with a length of {}: {}",
+            percent_decode(path).len(),
+            truncate(path, MAX_LOGGED_PATH_LENGTH)
+        );
+
+        return Some(Response::from_body("URI too long").with_status(StatusCode::URI_TOO_LONG));
+    }
+
+    None
+}
+
+/// Check if the decoded path is longer than the configured limit
+fn is_path_too_long(config: &Config, path: &str) -> bool {
+    percent_decode(path).len() > config.max_path_length
+}
+
+/// Reject path traversal
+///
+/// Paths with `..` segments could escape the intended prefix once they are resolved, so they are
+/// rejected with HTTP 400 Bad Request before the path is rewritten or forwarded to S3. The path is
+/// decoded first, which catches encoded variants like `%2e%2e`. Dots that are part of a file name
+/// are allowed.
+fn reject_path_traversal(request: &Request) -> Option<Response> {
+    let path = request.get_path();
+
+    if has_traversal_segment(path) {
+        warn!(
+            "Rejected path with traversal segment: {}",
+            truncate(path, MAX_LOGGED_PATH_LENGTH)
+        );
+
+        return Some(Response::from_body("Bad request").with_status(StatusCode::BAD_REQUEST));
+    }
+
+    None
+}
+
+/// Limit the rate of requests from a client
+///
+/// Only paths that are never cached are limited, so that high volumes of traffic for files are
+/// not affected. Clients that exceed the limit for a class of paths get HTTP 429 Too Many Requests,
+/// with a `Retry-After` header that tells them when they can send the next request.
+fn limit_request_rate(config: &Config, request: &Request, class: &str) -> Option<Response> {
+    let ip = request.get_client_ip_addr();
+    let retry_after = rate_limiter::check(config, class, ip)?;
+
+    warn!(
+        "Rate limited {} for {class}",
+        logged_ip(config, ip).unwrap_or_else(|| "a redacted IP".into())
+    );
+
+    Some(
+        Response::from_body("Too many requests")
+            .with_status(StatusCode::TOO_MANY_REQUESTS)
+            .with_header("Retry-After", retry_after.to_string()),
+    )
+}
+
+/// Answer health checks
+///
+/// Uptime monitoring periodically requests the health check path to ensure that the service is
+/// running. The request is answered at the edge without querying S3, and the response must not be
+/// cached so that every check reaches the service.
+fn answer_health_check() -> Response {
+    Response::from_body(health_check_body().to_string())
+        .with_content_type(APPLICATION_JSON)
+        .with_header("Cache-Control", "no-store")
+}
+
+/// Describe the status and the version of the service for health checks
+fn health_check_body() -> serde_json::Value {
+    json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION"),
+        "service_version": std::env::var("FASTLY_SERVICE_VERSION").ok(),
+    })
+}
+
+/// Check if the request asks for the routing decisions
+///
+/// Operators can debug the routing of a request by sending the debug token in the `X-Debug-Token`
+/// header. Debugging is only possible if it has been enabled and a token has been configured. The
+/// header is always removed from the request so that the secret is not forwarded to S3.
+fn is_debug_request(config: &Config, request: &mut Request) -> bool {
+    let token = request.remove_header("X-Debug-Token");
+
+    if !config.debug_enabled {
+        return false;
+    }
+
+    match (&config.debug_token, &token) {
+        (Some(expected), Some(token)) => constant_time_eq(expected.as_bytes(), token.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Answer debug requests
+///
+/// Instead of forwarding the request to S3, the decisions that were made for the request are
+/// returned as a JSON document. Requests that are answered at the edge, for example with a
+/// redirect, are answered normally.
+fn answer_debug_request(
+    config: &Config,
+    request: &Request,
+    original_path: &str,
+    ttl: Option<u32>,
+    cache_key: &str,
+) -> Response {
+    let body = debug_decisions(config, request, original_path, ttl, cache_key);
+
+    Response::from_body(body.to_string())
+        .with_content_type(APPLICATION_JSON)
+        .with_header("Cache-Control", "no-store")
+}
+
+/// Describe the decisions that were made for a debug request
+fn debug_decisions(
+    config: &Config,
+    request: &Request,
+    original_path: &str,
+    ttl: Option<u32>,
+    cache_key: &str,
+) -> serde_json::Value {
+    let (primary_host, fallback_hosts) = match select_mapped_hosts(config, request) {
+        Some(hosts) => (&hosts.primary_host, &hosts.fallback_hosts),
+        None => (&config.primary_host, &config.fallback_hosts),
+    };
+
+    json!({
+        "original_path": original_path,
+        "path": request.get_path(),
+        "query": request.get_query_str(),
+        "primary_host": primary_host,
+        "fallback_hosts": fallback_hosts,
+        "ttl": ttl,
+        "cache_key": cache_key,
+    })
+}
+
+/// Redirect requests to the canonical host
+///
+/// Requests for alternate hostnames or IP addresses are redirected to the same path and query
+/// string on the canonical host with HTTP 301 Moved Permanently. Domains in the host map are served
+/// by this service on purpose and are never redirected. Health checks are answered before this
+/// check, and purges never reach it.
+fn redirect_to_canonical_host(config: &Config, request: &Request) -> Option<Response> {
+    let canonical_host = config.canonical_host.as_ref()?;
+
+    let host = request
+        .get_header("Host")
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    if host == *canonical_host || config.host_map.contains_key(&host) {
+        return None;
+    }
+
+    let location = match request.get_query_str() {
+        Some(query) => format!("https://{canonical_host}{}?{query}", request.get_path()),
+        None => format!("https://{canonical_host}{}", request.get_path()),
+    };
+
+    Some(Response::from_status(StatusCode::MOVED_PERMANENTLY).with_header("Location", location))
+}
+
+/// Answer requests for `robots.txt`
+///
+/// Crawlers request `robots.txt` from every host, and S3 would answer with an error. If a body has
+/// been configured, it is served from the edge without querying S3. Without a body, the request is
+/// forwarded to S3 like any other.
+fn answer_robots_txt(config: &Config, request: &Request) -> Option<Response> {
+    let body = robots_txt_body(config, request)?;
+
+    Some(
+        Response::from_body(body.as_str())
+            .with_content_type(TEXT_PLAIN_UTF_8)
+            .with_header(
+                "Cache-Control",
+                format!("public, max-age={ROBOTS_TXT_MAX_AGE}"),
+            ),
+    )
+}
+
+/// Get the configured body of `robots.txt` if the request asks for it
+fn robots_txt_body<'a>(config: &'a Config, request: &Request) -> Option<&'a String> {
+    let body = config.robots_txt.as_ref()?;
+    (request.get_path() == "/robots.txt").then_some(body)
+}
+
+/// Answer requests for files in `/.well-known/`
+///
+/// Security scanners and other tools expect files like `/.well-known/security.txt`. The files that
+/// have been configured are served from the edge without querying S3, while requests for all other
+/// files in `/.well-known/` are forwarded to S3 like any other.
+fn answer_well_known_file(config: &Config, request: &Request) -> Option<Response> {
+    let body = well_known_file_body(config, request)?;
+
+    Some(
+        Response::from_body(body.as_str())
+            .with_content_type(TEXT_PLAIN_UTF_8)
+            .with_header(
+                "Cache-Control",
+                format!("public, max-age={WELL_KNOWN_MAX_AGE}"),
+            ),
+    )
+}
+
+/// Get the configured body of the file in `/.well-known/` that the request asks for
+fn well_known_file_body<'a>(config: &'a Config, request: &Request) -> Option<&'a String> {
+    config.well_known_files.get(request.get_path())
+}
+
+/// Answer requests during maintenance
+///
+/// During migrations, the service can be put into maintenance mode. All requests except for health
+/// checks are answered with HTTP 503 Service Unavailable, without querying S3.
+fn answer_during_maintenance(config: &Config, request: &Request) -> Response {
+    info!(
+        "Answered request for {} in maintenance mode",
+        request.get_path()
+    );
+
+    let mut response = maintenance_response(config);
+    response.set_body("Service unavailable due to maintenance, please try again later");
+    response
+}
+
+/// Build the response during maintenance, without its body
+fn maintenance_response(config: &Config) -> Response {
+    Response::from_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_header("Retry-After", config.retry_after.to_string())
+}
+
+/// Handle requests with cookies or credentials
+///
+/// The files are public, so requests should never include `Cookie` or `Authorization` headers.
+/// Some clients send them anyway, and they must not end up in the shared cache. By default, the
+/// headers are stripped before the request is forwarded to S3. Alternatively, the cache can be
+/// bypassed for these requests, which forwards the headers unchanged.
+fn handle_sensitive_headers(config: &Config, request: &mut Request) {
+    let has_sensitive_headers = SENSITIVE_HEADERS
+        .iter()
+        .any(|header| request.get_header(*header).is_some());
+
+    if !has_sensitive_headers {
+        return;
+    }
+
+    match config.sensitive_header_policy {
+        SensitiveHeaderPolicy::Strip => {
+            for header in SENSITIVE_HEADERS {
+                request.remove_header(header);
+            }
+
+            info!("Stripped sensitive headers from request");
+        }
+        SensitiveHeaderPolicy::Bypass => {
+            request.set_pass(true);
+            info!("Bypassed cache for request with sensitive headers");
+        }
+    }
+}
+
+/// Set the TTL
+///
+/// A TTL header is added to the request to ensure that the content is cached for the given amount
+/// of time.
+///
+/// The TTL can be overridden for paths that start with a given prefix. Prefixes are matched
+/// literally and case-sensitively against the path of the request, after it has been normalized
+/// and canonicalized. If multiple prefixes match, the longest one wins. Requests that match no
+/// prefix use the TTL for the static bucket.
+///
+/// Hot paths, like the `config.json` of the sparse index, are requested by nearly every cargo
+/// operation and must stay fresh. They are matched exactly and get their own short TTL, which takes
+/// precedence over the prefixes. Concurrent cache misses for them are collapsed by Fastly into a
+/// single request to S3, which absorbs the load while the TTL is short.
+///
+/// If configured, Fastly serves stale content while it revalidates the cached content in the
+/// background.
+///
+/// The TTL and the rule that chose it are recorded in the log line. The rule is `hot-path`, the
+/// matching prefix, or `default`.
+///
+/// If the `Cache-Control` header from S3 takes precedence, no TTL is set and Fastly caches the
+/// response for the `max-age` from S3, or for its default TTL if S3 sent none. The rule is `origin`
+/// in this case, and the TTL is recorded once the response is known.
+///
+/// Returns the TTL that was set on the request, if any.
+fn set_ttl(config: &Config, request: &mut Request, log: &mut LogLineBuilder) -> Option<u32> {
+    if config.stale_while_revalidate > 0 {
+        request.set_stale_while_revalidate(config.stale_while_revalidate);
+    }
+
+    if config.cache_precedence == CachePrecedence::OriginWins {
+        log.v2.ttl_rule(Some("origin".into()));
+        return None;
+    }
+
+    let path = request.get_path();
+
+    let (ttl, rule) = if config.hot_paths.iter().any(|hot_path| hot_path == path) {
+        (config.hot_path_ttl, "hot-path".to_string())
+    } else {
+        config
+            .ttl_overrides
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, ttl)| (*ttl, prefix.clone()))
+            .unwrap_or_else(|| (config.static_ttl, "default".to_string()))
+    };
+
+    request.set_ttl(ttl);
+    log.v2.ttl_applied(Some(ttl)).ttl_rule(Some(rule));
+
+    Some(ttl)
+}
+
+/// Mark immutable files
+///
+/// Files like crates never change once they have been published, which is advertised to browsers
+/// with the `immutable` directive of the `Cache-Control` header. Only successful responses for
+/// paths that match one of the configured prefixes are marked, so that files that can change, like
+/// the index, are not. This is independent of the TTL at the edge.
+fn mark_immutable(config: &Config, request: &Request, response: &mut Response) {
+    let path = request.get_path();
+
+    let is_immutable = config
+        .immutable_prefixes
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()));
+
+    if is_immutable && response.get_status().is_success() {
+        response.set_header(
+            "Cache-Control",
+            format!("public, max-age={}, immutable", config.immutable_max_age),
+        );
+    }
+}
+
+/// Advertise support for byte ranges
+///
+/// Download managers only resume interrupted downloads if the server advertises `Accept-Ranges`.
+/// S3 supports ranges but doesn't always advertise them, so successful responses for paths that
+/// match one of the configured prefixes get `Accept-Ranges: bytes`. An existing header, including
+/// `Accept-Ranges: none`, is left unchanged.
+fn advertise_byte_ranges(config: &Config, request: &Request, response: &mut Response) {
+    let path = request.get_path();
+
+    let is_large_object = config
+        .large_object_prefixes
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()));
+
+    if is_large_object
+        && response.get_status().is_success()
+        && !response.contains_header("Accept-Ranges")
+    {
+        response.set_header("Accept-Ranges", "bytes");
+    }
+}
+
+/// Log the TTL from the `Cache-Control` header of S3
+///
+/// Objects in S3 can have their own `Cache-Control` metadata. If it takes precedence over the
+/// configured TTL, no TTL was set on the request and Fastly caches the response for the maximum age
+/// from S3. The maximum age is recorded in the log line, and is missing if S3 sent none.
+fn log_origin_ttl(config: &Config, response: &Response, log: &mut LogLineBuilder) {
+    if config.cache_precedence != CachePrecedence::OriginWins {
+        return;
+    }
+
+    let origin_max_age = response
+        .get_header("Cache-Control")
+        .and_then(|header| header.to_str().ok())
+        .and_then(cache_policy::max_age);
+
+    log.v2.ttl_applied(origin_max_age);
+}
+
+/// Check if a cached client error has outlived the negative TTL
+///
+/// Repeated requests for files that don't exist, for example from scanners, should not reach S3
+/// every time, so responses with a 4xx status are cached for the negative TTL. The negative TTL is
+/// separate from the TTL of the static bucket, so that files that are published later become
+/// available quickly.
+///
+/// The SDK sets the TTL at the edge before the status of the response is known, which means that
+/// client errors are cached as long as files. Instead, the age of a cached client error is checked
+/// when it is served. Once it is older than the negative TTL, it is purged and fetched again.
+fn is_expired_negative_response(config: &Config, response: &Response) -> bool {
+    if config.negative_ttl == 0 || !response.get_status().is_client_error() {
+        return false;
+    }
+
+    response
+        .get_header("Age")
+        .and_then(|age| age.to_str().ok())
+        .and_then(|age| age.trim().parse::<u32>().ok())
+        .is_some_and(|age| age > config.negative_ttl)
+}
+
+/// Purge a cached client error
+///
+/// Every request with a negative TTL is tagged with a surrogate key that is derived from its
+/// cache key, which allows purging a single entry in the cache. Returns whether the purge
+/// succeeded.
+fn purge_negative_response(cache_key: &str) -> bool {
+    match purge_surrogate_key(&surrogate_key(cache_key)) {
+        Ok(()) => true,
+        Err(error) => {
+            warn!("Failed to purge expired client error: {error}");
+            false
+        }
+    }
+}
+
+/// Get the surrogate key for a cache key
+fn surrogate_key(cache_key: &str) -> String {
+    format!("cache-key-{:016x}", fnv1a(cache_key.as_bytes()))
+}
+
+/// Cache client errors for a short time in downstream caches
+///
+/// Responses with a 4xx status get the negative TTL in the `Surrogate-Control` and `Cache-Control`
+/// headers, so that downstream caches and clients don't keep them for longer than the edge.
+fn apply_negative_ttl(config: &Config, response: &mut Response) {
+    if config.negative_ttl > 0 && response.get_status().is_client_error() {
+        let max_age = format!("max-age={}", config.negative_ttl);
+
+        response.set_header("Surrogate-Control", max_age.as_str());
+        response.set_header("Cache-Control", format!("public, {max_age}"));
+    }
+}
+
+/// Rewrite the path to the key in S3, and record it as the final path in the logs
+fn rewrite_path(request: &mut Request, log: &mut LogLineBuilder) {
+    normalize_path(request);
+    canonicalize_s3_path(request);
+
+    log.v2
+        .final_path(Some(truncate(request.get_path(), MAX_LOGGED_PATH_LENGTH)));
+}
+
+/// Normalize the path
+///
+/// Clients sometimes request paths with repeated slashes, for example `//crates/foo`. These paths
+/// map to different keys in S3 and fragment the cache, so repeated slashes are collapsed into a
+/// single slash. The query string and encoded slashes (`%2F`) are not modified.
+fn normalize_path(request: &mut Request) {
+    let url = request.get_url_mut();
+    let path = url.path();
+
+    if path.contains("//") {
+        let mut new_path = String::with_capacity(path.len());
+
+        for character in path.chars() {
+            if character == '/' && new_path.ends_with('/') {
+                continue;
+            }
+
+            new_path.push(character);
+        }
+
+        url.set_path(&new_path);
+    }
+}
+
+/// Canonicalize the path for S3
+///
+/// An issue was reported for crates.io where URLs that encoded the `+` character in a crate's
+/// version as `%2B` were not working correctly. As a backwards-compatible fix, we are transparently
+/// rewriting URLs that contain the `+` character to use `%2B` instead. This ensures that crates in
+/// Amazon S3 are accessed in a consistent way across all clients and Content Delivery Networks.
+///
+/// We have since seen similar issues with other characters, like spaces and tildes. The path is
+/// therefore rewritten to a canonical percent-encoding, so that the same key in S3 is always
+/// requested with the same path regardless of how the client encoded it.
+///
+/// Paths that don't decode to valid UTF-8 are canonicalized all the same, since they can't match a
+/// key in S3 anyway. A warning is logged for them, since they are usually sent by broken clients.
+///
+/// See more: https://github.com/rust-lang/crates.io/issues/4891
+fn canonicalize_s3_path(request: &mut Request) {
+    let url = request.get_url_mut();
+    let path = url.path();
+
+    if !decodes_to_utf8(path) {
+        warn!(
+            "Received path that is not valid UTF-8: {}",
+            truncate(path, MAX_LOGGED_PATH_LENGTH)
+        );
+    }
+
+    if let Cow::Owned(new_path) = canonicalize(path) {
+        if new_path != path {
+            url.set_path(&new_path);
+        }
+    }
+}
+
+/// Strip the query string
+///
+/// Crates and index files are immutable, so query strings only fragment the cache and confuse S3.
+/// Returns whether the request had a query string that was removed, or `None` if stripping is
+/// disabled.
+fn strip_query_string(config: &Config, request: &mut Request) -> Option<bool> {
+    if !config.strip_query_string {
+        return None;
+    }
+
+    let url = request.get_url_mut();
+
+    if url.query().is_none() {
+        return Some(false);
+    }
+
+    url.set_query(None);
+    Some(true)
+}
+
+/// Compute the cache key of the request
+///
+/// Fastly's default cache key includes the full URL and the host, and responses can vary on any
+/// header. To keep the cache from fragmenting when more headers are forwarded to S3, the key is set
+/// explicitly. It is derived from the primary host of the bucket that the request is sent to, the
+/// path and query string, which have been canonicalized at this point, and the values of the
+/// configured request headers. The header values should be normalized before the key is computed,
+/// like `Accept-Encoding` is.
+///
+/// The host is part of the key since the bucket can be selected with the `X-Forwarded-Host` header,
+/// and files from one bucket must never be served for another.
+///
+/// Returns the cache key before it was hashed.
+fn compute_cache_key(config: &Config, request: &mut Request) -> String {
+    let primary_host = lookup_mapped_hosts(config, request)
+        .map_or(&config.primary_host, |(_, hosts)| &hosts.primary_host);
+
+    let mut key = format!("{primary_host}\n{}", request.get_path());
+
+    if let Some(query) = request.get_query_str() {
+        key.push('?');
+        key.push_str(query);
+    }
+
+    for header in &config.cache_key_headers {
+        let value = request
+            .get_header(header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        key.push('\n');
+        key.push_str(&header.to_ascii_lowercase());
+        key.push(':');
+        key.push_str(value);
+    }
+
+    request.set_cache_key(Sha256::digest(key.as_bytes()).into());
+
+    key
+}
+
+/// Decide if concurrent cache misses for the request are collapsed
+///
+/// Mass purges, for example of the index files, empty the cache and every request stampedes S3.
+/// Fastly collapses concurrent cache misses for the same cache key into a single request to the
+/// backend, and the waiting requests get the same response. Requests for the same file collapse
+/// even if their paths were encoded differently, since the cache key is computed from the
+/// canonical path. Only `GET` and `HEAD` requests are cached, and thus collapsed.
+///
+/// The SDK cannot opt a cached request out of collapsing. If collapsing is disabled, requests are
+/// passed to S3 without going through the cache, which is only meant for debugging S3.
+///
+/// The collapsed request is sent through the fallback logic like any other request, and the
+/// response of a fallback host is cached under the same key as one from the primary host. Server
+/// errors are not cached, so the waiting requests are released and each of them goes through the
+/// fallback logic on its own.
+fn collapses(config: &Config, request: &Request) -> bool {
+    config.request_collapsing && matches!(*request.get_method(), Method::GET | Method::HEAD)
+}
+
+/// Negotiate the content encoding
+///
+/// Some objects in S3 are stored pre-compressed. The `Accept-Encoding` header of the client is
+/// normalized to the best encoding that we support, which is then forwarded to S3. Normalizing the
+/// header keeps the number of variants in the cache small. Objects are never compressed at the
+/// edge, and the `Content-Encoding` of the response from S3 is passed through to the client.
+/// Fastly only compresses responses that carry the `X-Compress-Hint` header, which is never set,
+/// so objects that S3 already compressed are never compressed twice.
+fn negotiate_content_encoding(request: &mut Request) {
+    let encoding = request
+        .get_header("Accept-Encoding")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| {
+            ["br", "gzip"]
+                .into_iter()
+                .find(|encoding| accepts_encoding(header, encoding))
+        });
+
+    match encoding {
+        Some(encoding) => request.set_header("Accept-Encoding", encoding),
+        None => {
+            request.remove_header("Accept-Encoding");
+        }
+    }
+}
+
+/// Check if the `Accept-Encoding` header accepts the given encoding
+///
+/// Encodings with a quality value of zero are explicitly not acceptable to the client.
+fn accepts_encoding(header: &str, encoding: &str) -> bool {
+    header.split(',').any(|item| {
+        let mut parameters = item.split(';').map(str::trim);
+        let name = parameters.next().unwrap_or_default();
+
+        let rejected = parameters
+            .filter_map(|parameter| parameter.strip_prefix("q="))
+            .any(|quality| quality.parse::<f32>() == Ok(0.0));
+
+        name.eq_ignore_ascii_case(encoding) && !rejected
+    })
+}
+
+/// Strip internal headers from the response
+///
+/// S3 adds headers like `x-amz-request-id` and `Server` that leak details about the bucket. Headers
+/// that match one of the configured entries are removed from the response, except for the headers
+/// that clients need to handle the response correctly. Entries that end with `-`, like `x-amz-`,
+/// match all headers with that prefix, while other entries must match the name exactly. This keeps
+/// headers like `Server-Timing` when `Server` is stripped.
+fn strip_response_headers(config: &Config, response: &mut Response) {
+    let stripped_headers: Vec<String> = response
+        .get_header_names()
+        .map(|name| name.as_str().to_string())
+        .filter(|name| !PRESERVED_RESPONSE_HEADERS.contains(&name.as_str()))
+        .filter(|name| {
+            config
+                .stripped_response_headers
+                .iter()
+                .any(|entry| matches_header_entry(name, entry))
+        })
+        .collect();
+
+    for name in stripped_headers {
+        response.remove_header(name);
+    }
+}
+
+/// Check if the header name matches an entry of the stripped headers
+fn matches_header_entry(name: &str, entry: &str) -> bool {
+    if entry.ends_with('-') {
+        name.starts_with(entry)
+    } else {
+        name == entry
+    }
+}
+
+/// Correct the content type of the response
+///
+/// Depending on how files were uploaded to S3, they can have a generic or wrong content type. The
+/// content type of successful responses is replaced based on the suffix of the path. Responses for
+/// paths without a configured suffix keep the content type from S3.
+fn correct_content_type(config: &Config, request: &Request, response: &mut Response) {
+    if !response.get_status().is_success() {
+        return;
+    }
+
+    let path = request.get_path();
+    if let Some((_, content_type)) = config
+        .content_types
+        .iter()
+        .find(|(suffix, _)| path.ends_with(suffix.as_str()))
+    {
+        response.set_header("Content-Type", content_type);
+    }
+}
+
+/// Synthesize an `ETag` for responses without one
+///
+/// Clients need an `ETag` to make conditional requests. If S3 did not include one in a successful
+/// response, a weak `ETag` is derived from the path, the `Content-Length`, and the `Last-Modified`
+/// header. These are stable for an object, so clients get the same value for every request. S3
+/// doesn't know the synthesized value and answers conditional requests with it with the full file,
+/// which `answer_if_none_match` then turns into HTTP 304 Not Modified.
+fn synthesize_etag(request: &Request, response: &mut Response) {
+    if !response.get_status().is_success() || response.get_header("ETag").is_some() {
+        return;
+    }
+
+    let last_modified = response
+        .get_header("Last-Modified")
+        .map(|header| header.as_bytes())
+        .unwrap_or_default();
+    let content_length = response
+        .get_content_length()
+        .map(|length| length.to_string())
+        .unwrap_or_default();
+
+    let attributes = [
+        request.get_path().as_bytes(),
+        content_length.as_bytes(),
+        last_modified,
+    ]
+    .join(&b'\n');
+
+    response.set_header("ETag", format!("W/\"{:016x}\"", fnv1a(&attributes)));
+}
+
+/// Check if a partial response matches the `If-Range` header of the request
+///
+/// Clients that resume a download send `If-Range` with the `ETag` of the partial file, and must
+/// only get a range of the file if it is unchanged. Entity tags in `If-Range` are compared with the
+/// strong comparison, so a weak or missing `ETag` never matches. `If-Range` headers with a date
+/// are left to S3.
+fn if_range_matches(request: &Request, response: &Response) -> bool {
+    if response.get_status() != StatusCode::PARTIAL_CONTENT {
+        return true;
+    }
+
+    let Some(if_range) = request
+        .get_header("If-Range")
+        .and_then(|header| header.to_str().ok())
+    else {
+        return true;
+    };
+
+    let etag = response
+        .get_header("ETag")
+        .and_then(|header| header.to_str().ok())
+        .and_then(EntityTag::parse);
+
+    match etag {
+        Some(etag) => etag::if_range(if_range, &etag).unwrap_or(true),
+        None => EntityTag::parse(if_range).is_none(),
+    }
+}
+
+/// Copy a request without its `Range` and `If-Range` headers, which fetches the full file
+fn without_range(request: &Request) -> Request {
+    let mut request = request.clone_without_body();
+    request.remove_header("Range");
+    request.remove_header("If-Range");
+    request
+}
+
+/// Answer requests with a matching `If-None-Match` header with HTTP 304 Not Modified
+///
+/// S3 answers conditional requests for its own `ETag`, but not for synthesized ones or for files
+/// from Fastly's cache. Entity tags in `If-None-Match` are compared with the weak comparison, so
+/// that a weak `ETag` matches as well. The headers of the response are preserved.
+fn answer_if_none_match(request: &Request, response: &mut Response) {
+    if response.get_status() != StatusCode::OK {
+        return;
+    }
+
+    let Some(if_none_match) = request
+        .get_header("If-None-Match")
+        .and_then(|header| header.to_str().ok())
+    else {
+        return;
+    };
+
+    let etag = response
+        .get_header("ETag")
+        .and_then(|header| header.to_str().ok())
+        .and_then(EntityTag::parse);
+
+    if etag.is_some_and(|etag| etag::if_none_match(if_none_match, &etag)) {
+        response.set_status(StatusCode::NOT_MODIFIED);
+        response.try_take_body();
+    }
+}
+
+/// Add `Accept-Encoding` to the `Vary` header
+///
+/// The response can differ based on the `Accept-Encoding` header of the request, which must be
+/// part of the cache key. The header is always added, unless S3 already included it.
+fn vary_on_accept_encoding(response: &mut Response) {
+    let varies_on_encoding = response
+        .get_header_all("Vary")
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .any(|header| header.trim().eq_ignore_ascii_case("Accept-Encoding"));
+
+    if !varies_on_encoding {
+        response.append_header("Vary", "Accept-Encoding");
+    }
+}
+
+/// Check if a path matches a pattern
+///
+/// A pattern that ends with `/**` matches all paths below its prefix, while all other patterns must
+/// match the path exactly.
+fn matches_path_pattern(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix("**") {
+        Some(prefix) if prefix.ends_with('/') => path.starts_with(prefix),
+        _ => path == pattern,
+    }
+}
+
+/// Answer requests for removed paths with HTTP 410 Gone
+///
+/// Files that have been removed on purpose, unlike files that never existed, get a definitive
+/// answer without a request to S3. The paths are configured as patterns like the paths that can be
+/// purged. The response can be cached by clients and downstream caches for the configured TTL.
+fn answer_gone_paths(config: &Config, request: &Request) -> Option<Response> {
+    if !is_gone_path(config, request.get_path()) {
+        return None;
+    }
+
+    let mut response = gone_response(config);
+    response.set_body("Gone: this file has been removed from crates.io\n");
+    Some(response)
+}
+
+/// Check if the path matches one of the paths that have been removed
+fn is_gone_path(config: &Config, path: &str) -> bool {
+    config
+        .gone_paths
+        .iter()
+        .any(|pattern| matches_path_pattern(pattern, path))
+}
+
+/// Build the response for removed paths, without its body
+fn gone_response(config: &Config) -> Response {
+    let max_age = format!("max-age={}", config.gone_ttl);
+
+    Response::from_status(StatusCode::GONE)
+        .with_content_type(TEXT_PLAIN_UTF_8)
+        .with_header("Surrogate-Control", max_age.as_str())
+        .with_header("Cache-Control", format!("public, {max_age}"))
+}
+
+/// Redirect moved paths
+///
+/// Paths that have been renamed or moved can be redirected to a new location with HTTP 301 Moved
+/// Permanently. Only exact matches are redirected. The query string of the request is preserved,
+/// unless the target already has its own query string.
+///
+/// Redirects are logged so that we can measure their usage and eventually retire them.
+fn redirect_moved_paths(config: &Config, request: &Request) -> Option<Response> {
+    let path = request.get_path();
+    let target = config.redirect_map.get(path)?;
+
+    let location = match request.get_query_str() {
+        Some(query) if !target.contains('?') => format!("{target}?{query}"),
+        _ => target.clone(),
+    };
+
+    info!("Redirected {path} to {location}");
+
+    Some(Response::from_status(StatusCode::MOVED_PERMANENTLY).with_header("Location", location))
+}
+
+/// Redirect bare paths to the landing page
+///
+/// The bucket has no index document, so a request for `/` would return an error from S3. If a
+/// landing page is configured, requests for `/` and the other configured paths are redirected to it
+/// with HTTP 302 Found. Only exact matches are redirected, so files below these paths are still
+/// served from S3.
+fn redirect_root_paths(config: &Config, request: &Request) -> Option<Response> {
+    let url = config.root_redirect_url.as_ref()?;
+
+    if !config
+        .root_redirect_paths
+        .iter()
+        .any(|path| path == request.get_path())
+    {
+        return None;
+    }
+
+    Some(Response::from_status(StatusCode::FOUND).with_header("Location", url))
+}
+
+/// Redirect paths to normalize their trailing slash
+///
+/// Paths with and without a trailing slash are different keys in S3 and in the cache. With the
+/// `strip` policy, trailing slashes are removed from all paths except for the root. With the `keep`
+/// policy, a trailing slash is added to paths that look like directories, which means that their
+/// last segment has no file extension. The redirects use HTTP 301 Moved Permanently and preserve
+/// the query string.
+///
+/// Some files have no extension, like the files of the sparse index, and would be mistaken for
+/// directories. Paths that start with one of the exempt prefixes are never redirected, which by
+/// default is `/index/`.
+fn redirect_trailing_slash(config: &Config, request: &Request) -> Option<Response> {
+    let path = request.get_path();
+
+    if config
+        .trailing_slash_exempt_prefixes
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()))
+    {
+        return None;
+    }
+
+    let new_path = match config.trailing_slash_policy {
+        TrailingSlashPolicy::Off => return None,
+        TrailingSlashPolicy::Strip => {
+            if path == "/" || !path.ends_with('/') {
+                return None;
+            }
+
+            path.trim_end_matches('/').to_string()
+        }
+        TrailingSlashPolicy::Keep => {
+            let last_segment = path.rsplit('/').next().unwrap_or_default();
+            if path.ends_with('/') || last_segment.contains('.') {
+                return None;
+            }
+
+            format!("{path}/")
+        }
+    };
+
+    // Paths that consist only of slashes are stripped to the root
+    let new_path = if new_path.is_empty() {
+        "/".to_string()
+    } else {
+        new_path
+    };
+
+    let location = match request.get_query_str() {
+        Some(query) => format!("{new_path}?{query}"),
+        None => new_path,
+    };
+
+    Some(Response::from_status(StatusCode::MOVED_PERMANENTLY).with_header("Location", location))
+}
+
+/// Check if the path has one of the suffixes that are redirected to CloudFront
+///
+/// Some files, like the database dump, are too big to cache on Fastly.
+fn redirects_to_cloudfront(config: &Config, path: &str) -> bool {
+    config
+        .cloudfront_redirect_suffixes
+        .iter()
+        .any(|suffix| path.ends_with(suffix.as_str()))
+}
+
+/// Redirect request to CloudFront
+///
+/// As of early 2023, certain files are too large to be served through Fastly. One of those is the
+/// database dump, which gets redirected to CloudFront. The suffixes of the paths that get
+/// redirected are configured in the dictionary, and the path of the request is preserved in the
+/// redirect.
+fn redirect_to_cloudfront(config: &Config, request: &Request) -> Result<Response, Error> {
+    let host = select_cloudfront_host(config, request.get_path(), is_cloudfront_healthy);
+    info!("Redirecting {} to CloudFront at {host}", request.get_path());
+
+    let url = format!("https://{}{}", host, request.get_path());
+    Ok(Response::temporary_redirect(url))
+}
+
+/// Select the CloudFront host for a redirect
+///
+/// If a secondary CloudFront host is configured and probing is enabled, the primary host is probed
+/// with a HEAD request for the same path. When the probe fails or returns a server error, clients
+/// are redirected to the secondary host instead. Probing adds a round trip to every redirect, so it
+/// is disabled by default. The probe requires a backend with the name of the primary host.
+///
+/// The probe is sent with `is_healthy`, which gets the host and the path.
+fn select_cloudfront_host<'a>(
+    config: &'a Config,
+    path: &str,
+    is_healthy: impl FnOnce(&str, &str) -> bool,
+) -> &'a str {
+    let Some(secondary_host) = config.secondary_cloudfront_url.as_deref() else {
+        return &config.cloudfront_url;
+    };
+
+    if !config.cloudfront_probe || is_healthy(&config.cloudfront_url, path) {
+        &config.cloudfront_url
+    } else {
+        secondary_host
+    }
+}
+
+fn is_cloudfront_healthy(host: &str, path: &str) -> bool {
+    let probe = Request::head(format!("https://{host}{path}")).with_pass(true);
+
+    match probe.send(host) {
+        Ok(response) if !response.get_status().is_server_error() => true,
+        Ok(response) => {
+            warn!(
+                "Probe of CloudFront at {host} returned status code {}",
+                response.get_status().as_u16()
+            );
+            false
+        }
+        Err(error) => {
+            warn!("Probe of CloudFront at {host} failed: {error}");
+            false
+        }
+    }
+}
+
+/// Select the S3 hosts for the domain of the request
+///
+/// The same service can serve multiple domains, for example a staging domain next to production,
+/// each with its own buckets. The domain is read from the `X-Forwarded-Host` header, or from the
+/// `Host` header if it is missing. Only domains in the host map are honored, so that clients cannot
+/// pick arbitrary buckets. All other requests are sent to the default hosts.
+fn select_mapped_hosts<'a>(config: &'a Config, request: &Request) -> Option<&'a BucketHosts> {
+    let (domain, hosts) = lookup_mapped_hosts(config, request)?;
+    info!(
+        "Selected host {} from the host map for {domain}",
+        hosts.primary_host
+    );
+
+    Some(hosts)
+}
+
+/// Look up the domain of the request in the host map
+fn lookup_mapped_hosts<'a>(
+    config: &'a Config,
+    request: &Request,
+) -> Option<(String, &'a BucketHosts)> {
+    if config.host_map.is_empty() {
+        return None;
+    }
+
+    let domain = request
+        .get_header("X-Forwarded-Host")
+        .or_else(|| request.get_header("Host"))
+        .and_then(|header| header.to_str().ok())?
+        .trim()
+        .to_ascii_lowercase();
+
+    let hosts = config.host_map.get(&domain)?;
+    Some((domain, hosts))
+}
+
+/// Send the request to the primary host, retrying transient errors
+///
+/// Many server errors from S3 are transient, and a retry against the primary bucket avoids a
+/// more expensive request to a bucket in another region. Only the status codes in
+/// `RETRIABLE_STATUS_CODES` are retried, up to the configured number of times, with an optional
+/// backoff between the attempts. Timeouts are not retried, since the fallbacks are more likely to
+/// respond in time.
+fn send_with_retries(
+    config: &Config,
+    host: &str,
+    send: &mut impl FnMut(&str) -> Result<Option<Response>, Error>,
+) -> Result<Option<Response>, Error> {
+    let attempts = config.primary_retries.saturating_add(1);
+    let mut attempt = 1;
+
+    loop {
+        let Some(response) = send(host)? else {
+            return Ok(None);
+        };
+        let status_code = response.get_status().as_u16();
+
+        if attempt >= attempts || !RETRIABLE_STATUS_CODES.contains(&status_code) {
+            return Ok(Some(response));
+        }
+
+        warn!(
+            "Retrying request to host {host} after status code {status_code} (attempt {} of {})",
+            attempt + 1,
+            attempts
+        );
+
+        if config.primary_retry_backoff_ms > 0 {
+            std::thread::sleep(Duration::from_millis(
+                config.primary_retry_backoff_ms.into(),
+            ));
+        }
+
+        attempt += 1;
+    }
+}
+
+/// Send the request to a host, giving up after the configured timeout
+///
+/// The timeouts of the backends are configured on the service and cannot be changed for a single
+/// request. The request is therefore sent asynchronously and polled until the deadline has passed.
+/// Returns `None` if the host did not respond in time.
+fn send_with_timeout(
+    config: &Config,
+    request: &Request,
+    host: &str,
+) -> Result<Option<Response>, Error> {
+    let deadline = Instant::now() + Duration::from_millis(config.backend_timeout_ms.into());
+    let mut pending = build_backend_request(config, request, host).send_async(host)?;
+
+    loop {
+        pending = match pending.poll() {
+            PollResult::Done(response) => return Ok(Some(response?)),
+            PollResult::Pending(pending) => pending,
+        };
+
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+
+        std::thread::sleep(BACKEND_POLL_INTERVAL);
+    }
+}
+
+/// Forward client request to S3
+///
+/// The request that was received by the client is forwarded to S3. First, the primary bucket is
+/// queried. If the response indicates a server issue, the request is sent to the fallback buckets
+/// in different geographical regions, one after another. The status codes that indicate a server
+/// issue are configurable, and default to 500, 502, 503, and 504. Other server errors, like HTTP
+/// 501 Not Implemented for an unsupported operation, are returned directly since a fallback would
+/// answer the same. The first response that does not indicate a server issue is returned. If all
+/// of them failed, a clean HTTP 503 Service Unavailable is returned instead of the raw error from
+/// S3, and the status code from S3 is recorded in the log line.
+///
+/// Transient errors from the primary bucket are retried before the fallbacks are queried. Requests
+/// that time out are treated like server errors, and if the last host timed out, HTTP 504 Gateway
+/// Timeout is returned.
+///
+/// If the circuit breaker is enabled and open, the primary bucket is skipped and the request is
+/// sent directly to the fallbacks.
+///
+/// The order in which the hosts are tried is decided by the configured strategy. By default, the
+/// primary host is tried first and the fallbacks in their configured order. The first host that is
+/// tried is retried like the primary host, while the circuit breaker only tracks the primary host.
+///
+/// Conditional requests are forwarded to S3 as well. When S3 responds with HTTP 304 Not Modified,
+/// the response is returned to the client without a body but with its `ETag`.
+///
+/// Range requests are forwarded to S3 as well, which allows clients to resume downloads. Responses
+/// with HTTP 206 Partial Content are passed through untouched, including their `Content-Range` and
+/// `Accept-Ranges` headers. Clients that resume a download send `If-Range` with the `ETag` of the
+/// partial file. S3 answers with HTTP 206 if the file is unchanged, and with the full file and HTTP
+/// 200 OK otherwise. Both are passed through without querying a fallback.
+///
+/// The time spent waiting for S3 is recorded in the log line, and includes all requests that were
+/// sent to fallback buckets.
+fn send_request_to_s3(
+    config: &Config,
+    request: &Request,
+    log: &mut LogLineBuilder,
+) -> Result<Response, Error> {
+    let mapped_hosts = select_mapped_hosts(config, request);
+    let (primary_host, fallback_hosts) = match mapped_hosts {
+        Some(hosts) => (&hosts.primary_host, &hosts.fallback_hosts),
+        None => (&config.primary_host, &config.fallback_hosts),
+    };
+
+    // The circuit breaker only tracks the default primary host
+    let mut circuit_breaker = match mapped_hosts {
+        Some(_) => None,
+        None => CircuitBreaker::load(config),
+    };
+
+    let skip_primary = !fallback_hosts.is_empty()
+        && circuit_breaker
+            .as_ref()
+            .is_some_and(CircuitBreaker::is_open);
+
+    if skip_primary {
+        warn!(
+            "Skipped host {} because the circuit breaker is open",
+            primary_host
+        );
+    }
+
+    let candidates = Candidates {
+        primary_host,
+        fallback_hosts,
+        skip_primary,
+    };
+    let hosts = backend_selector::selector(config).select(request, &candidates);
+
+    let failover = try_hosts(
+        config,
+        &candidates,
+        &hosts,
+        circuit_breaker.as_mut(),
+        log,
+        |host| send_with_timeout(config, request, host),
+    )?;
+
+    let mut response = match failover.into_result(config) {
+        Ok(response) => response,
+        Err(unavailable) => {
+            log.v1.error_detail(Some(unavailable.error_detail));
+
+            return Ok(Response::from_body(unavailable.message)
+                .with_status(unavailable.status)
+                .with_header("Retry-After", config.retry_after.to_string()));
+        }
+    };
+
+    if response.get_status() == StatusCode::NOT_MODIFIED {
+        response.take_body();
+    }
+
+    if config.head_as_get && request.get_method() == Method::HEAD {
+        strip_body(&mut response);
+    }
+
+    Ok(response)
+}
+
+/// Outcome of sending a request to the hosts one after another
+enum Failover {
+    /// A host responded, which is the last host with a server error if all of them failed
+    Responded(Box<Response>),
+    /// The last host did not respond in time
+    TimedOut,
+    /// The primary host failed, and the cap on requests to the fallback hosts has been reached
+    FallbackSaturated,
+}
+
+impl Failover {
+    /// Get the response of the host, or the error for the client if all hosts failed
+    fn into_result(self, config: &Config) -> Result<Response, Unavailable> {
+        let response = match self {
+            Failover::Responded(response) => *response,
+            Failover::FallbackSaturated => {
+                return Err(Unavailable {
+                    status: StatusCode::SERVICE_UNAVAILABLE,
+                    message: "Service unavailable, please try again later",
+                    error_detail: "primary host failed, fallback cap reached".into(),
+                })
+            }
+            Failover::TimedOut => {
+                return Err(Unavailable {
+                    status: StatusCode::GATEWAY_TIMEOUT,
+                    message: "Gateway timeout, please try again later",
+                    error_detail: "all hosts failed, last host timed out".into(),
+                })
+            }
+        };
+
+        let status_code = response.get_status().as_u16();
+        if config.failover_status_codes.contains(&status_code) {
+            return Err(Unavailable {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                message: "Service unavailable, please try again later",
+                error_detail: format!("all hosts failed, last status code was {status_code}"),
+            });
+        }
+
+        Ok(response)
+    }
+}
+
+/// Error for the client when all hosts failed
+///
+/// The client gets a clean error with a `Retry-After` header instead of the raw error from S3,
+/// while the reason is recorded in the log line.
+struct Unavailable {
+    status: StatusCode,
+    message: &'static str,
+    error_detail: String,
+}
+
+/// Send the request to the hosts in the given order until one of them responds without a server
+/// issue
+///
+/// The request is sent to a single host with `send`, which returns `None` if the host did not
+/// respond in time. The primary host is retried wherever it is in the order, the circuit breaker
+/// tracks its responses, and the status codes and the time spent waiting for the hosts are recorded
+/// in the log line.
+fn try_hosts(
+    config: &Config,
+    candidates: &Candidates,
+    hosts: &[&String],
+    mut circuit_breaker: Option<&mut CircuitBreaker>,
+    log: &mut LogLineBuilder,
+    mut send: impl FnMut(&str) -> Result<Option<Response>, Error>,
+) -> Result<Failover, Error> {
+    let started_at = Instant::now();
+    let mut response = None;
+    let mut timed_out = false;
+    let mut fallback_saturated = false;
+    let mut failed_over = false;
+
+    for (position, host) in hosts.iter().enumerate() {
+        // Selectors can put a fallback host before the primary host, for example the one that is
+        // nearest to the client. That host is the regular host of the request, and only counts as a
+        // failover if it replaces the primary host that must be skipped.
+        let is_primary = *host == candidates.primary_host;
+        let is_failover = !is_primary && (position > 0 || candidates.skip_primary);
+
+        // Count each request only once, when it first fails over to a fallback host
+        let is_first_fallback = is_failover && !failed_over;
+        failed_over |= is_failover;
+
+        if is_first_fallback && !fallback_limiter::admit(config) {
+            warn!(
+                "Fallback cap of {} requests per {} seconds reached, skipped host {}",
+                config.fallback_limit, config.fallback_limit_window, host
+            );
+
+            fallback_saturated = true;
+            break;
+        }
+
+        log.v2
+            .backend_host(Some(host.to_string()))
+            .used_fallback(!is_primary);
+
+        let backend_response = if is_primary {
+            send_with_retries(config, host, &mut send)?
+        } else {
+            send(host)?
+        };
+
+        let Some(backend_response) = backend_response else {
+            warn!(
+                "Request to host {} ({} of {}) timed out after {} ms",
+                host,
+                position + 1,
+                hosts.len(),
+                config.backend_timeout_ms
+            );
+
+            if let Some(circuit_breaker) = circuit_breaker.as_mut().filter(|_| is_primary) {
+                circuit_breaker.record_failure();
+            }
+
+            metrics::increment(metrics::BACKEND_FAILURE);
+            timed_out = true;
+            continue;
+        };
+
+        let status_code = backend_response.get_status().as_u16();
+        response = Some(backend_response);
+        timed_out = false;
+
+        if is_primary {
+            log.v2.primary_status(Some(status_code));
+        }
+
+        let fails_over = config.failover_status_codes.contains(&status_code);
+
+        if let Some(circuit_breaker) = circuit_breaker.as_mut().filter(|_| is_primary) {
+            if fails_over {
+                circuit_breaker.record_failure();
+            } else {
+                circuit_breaker.record_success();
+            }
+        }
+
+        if !fails_over {
+            if !is_primary {
+                metrics::increment(metrics::BACKEND_FALLBACK);
+            }
+
+            break;
+        }
+
+        metrics::increment(metrics::BACKEND_FAILURE);
+        warn!(
+            "Request to host {} ({} of {}) returned status code {}",
+            host,
+            position + 1,
+            hosts.len(),
+            status_code
+        );
+    }
+
+    let backend_duration_ms = started_at.elapsed().as_millis() as u64;
+    log.v1.backend_duration_ms(Some(backend_duration_ms));
+    record_backend_duration(config, backend_duration_ms);
+
+    if fallback_saturated {
+        return Ok(Failover::FallbackSaturated);
+    }
+
+    if timed_out {
+        return Ok(Failover::TimedOut);
+    }
+
+    let response = response.expect("at least one host responded");
+    Ok(Failover::Responded(Box::new(response)))
+}
+
+/// Strip the body from a response
+///
+/// The body of a response to a HEAD request that was sent as a GET request to S3 is removed. The
+/// headers are preserved, so that `Content-Length` and `ETag` still describe the resource.
+fn strip_body(response: &mut Response) {
+    let content_length = response.get_header("Content-Length").cloned();
+
+    response.try_take_body();
+
+    if let Some(content_length) = content_length {
+        response.set_header("Content-Length", content_length);
+    }
+}
+
+/// Build the request that is sent to S3
+///
+/// The request is cloned without its body, since clients only download resources. The headers for
+/// content negotiation, conditional, and range requests are explicitly copied from the original
+/// request to make sure that they reach S3.
+///
+/// Hop-by-hop headers, and the headers that the client lists in its `Connection` header, are
+/// removed from the request. Legacy HTTP/1.0 clients send them, and they must not reach S3 since
+/// they only apply to the connection between the client and Fastly.
+///
+/// If configured, HEAD requests are sent as GET requests to S3, since we have seen inconsistent
+/// `Content-Length` headers for HEAD requests. The body is stripped from the response later.
+///
+/// The `Host` header can be overridden for each backend, for example for virtual-hosted-style
+/// buckets whose hostname differs from the name of the backend. The TLS certificate and SNI
+/// hostname are part of the backend's configuration in the service.
+fn build_backend_request(config: &Config, request: &Request, backend: &str) -> Request {
+    let mut backend_request = request.clone_without_body();
+    strip_hop_by_hop_headers(&mut backend_request);
+
+    if let Some(host_header) = config.backend_host_headers.get(backend) {
+        match HeaderValue::from_str(host_header) {
+            Ok(value) => backend_request.set_header("Host", value),
+            Err(_) => warn!("Ignored invalid Host header for backend {backend}: {host_header}"),
+        }
+    }
+
+    if config.head_as_get && request.get_method() == Method::HEAD {
+        backend_request.set_method(Method::GET);
+    }
+
+    for header in FORWARDED_HEADERS {
+        if let Some(value) = request.get_header(header) {
+            backend_request.set_header(header, value);
+        }
+    }
+
+    backend_request
+}
+
+/// Remove hop-by-hop headers from a request
+fn strip_hop_by_hop_headers(request: &mut Request) {
+    // Invalid names in the `Connection` header are ignored
+    let connection_headers: Vec<HeaderName> = request
+        .get_header_all("Connection")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|header| HeaderName::from_bytes(header.trim().as_bytes()).ok())
+        .collect();
+
+    for header in connection_headers {
+        request.remove_header(header);
+    }
+
+    for header in HOP_BY_HOP_HEADERS {
+        request.remove_header(header);
+    }
+}
+
+/// Add security headers to response
+///
+/// All responses include headers that harden browsers against downgrade attacks and content
+/// sniffing. The headers are independent of the CORS headers.
+fn add_security_headers(config: &Config, response: &mut Result<Response, Error>) {
+    if let Ok(response) = response {
+        response.set_header(
+            "Strict-Transport-Security",
+            format!("max-age={}", config.hsts_max_age),
+        );
+        response.set_header("X-Content-Type-Options", "nosniff");
+
+        if let Some(referrer_policy) = &config.referrer_policy {
+            response.set_header("Referrer-Policy", referrer_policy.as_str());
+        }
+    }
+}
+
+/// Add the maintenance notice to the response
+///
+/// Upcoming maintenance can be announced to clients with the `X-Maintenance-Notice` header, while
+/// requests are still served normally. This is independent of the maintenance mode.
+fn add_maintenance_notice(config: &Config, response: &mut Result<Response, Error>) {
+    if let (Ok(response), Some(notice)) = (response, &config.maintenance_notice) {
+        response.set_header("X-Maintenance-Notice", notice.as_str());
+    }
+}
+
+/// Add the configured headers to successful responses
+///
+/// The headers are added last and never replace a header that is already set, so that the
+/// security, CORS, and request ID headers always take precedence.
+fn add_response_headers(config: &Config, response: &mut Result<Response, Error>) {
+    let Ok(response) = response else {
+        return;
+    };
+
+    if !response.get_status().is_success() {
+        return;
+    }
+
+    for (name, value) in &config.response_headers {
+        if !response.contains_header(name) {
+            response.set_header(name, value);
+        }
+    }
+}
+
+/// Add CORS headers to response
+///
+/// We are explicitly adding the three CORS headers to requests that include an `Origin` header to
+/// match functionality with CloudFront.
+///
+/// If a list of allowed origins is configured, the request's origin is only echoed back when it is
+/// part of the list. Otherwise, no CORS headers are added and the browser will block the response.
+/// Without a list, all origins are allowed.
+fn add_cors_headers(config: &Config, response: &mut Result<Response, Error>, origin: &str) {
+    let allowed_origin = if config.cors_allowed_origins.is_empty() {
+        "*"
+    } else if config
+        .cors_allowed_origins
+        .iter()
+        .any(|allowed| allowed == origin)
+    {
+        origin
+    } else {
+        return;
+    };
+
+    if let Ok(response) = response {
+        response.set_header("Access-Control-Allow-Origin", allowed_origin);
+        if allowed_origin != "*" {
+            response.append_header("Vary", "Origin");
+        }
+        response.set_header(
+            "Access-Control-Allow-Methods",
+            config.allowed_methods_header(),
+        );
+        response.set_header("Access-Control-Max-Age", CORS_MAX_AGE);
+    }
+}
+
+/// Collect data for the logs from the response and record the time spent at the edge
+///
+/// The time includes the backend and collecting the logs, but not sending them. The difference to
+/// the backend duration is the overhead of the edge.
+fn finish_log_line(
+    config: &Config,
+    log_line: &mut LogLineBuilder,
+    response: &Result<Response, Error>,
+    path: &str,
+    started_at: Instant,
+) -> LogLineBuilder {
+    let mut log_line = collect_response(config, log_line, response, path);
+    log_line
+        .v1
+        .edge_duration_ms(Some(started_at.elapsed().as_millis() as u64));
+    log_line
+}
+
+/// Collect data for the logs from the response
+///
+/// Files that are too big are redirected to CloudFront. To move them before they become a problem,
+/// a warning is logged for responses that are larger than the configured threshold.
+///
+/// Successful responses without any content usually mean that an upload was truncated, which is
+/// logged as well.
+///
+/// The final status is the status code that is sent to the client. Together with the status code
+/// of the primary host, it shows how often a fallback turned an error into a successful response.
+fn collect_response(
+    config: &Config,
+    log_line: &mut LogLineBuilder,
+    response: &Result<Response, Error>,
+    path: &str,
+) -> LogLineBuilder {
+    match response {
+        Ok(response) => {
+            metrics::increment(metrics::status_class_counter(
+                response.get_status().as_u16(),
+            ));
+
+            if cache_status(response) == "HIT" {
+                metrics::increment(metrics::CACHE_HIT);
+            }
+
+            if is_empty_success(response) {
+                warn!(
+                    "Served empty response for {}",
+                    truncate(path, MAX_LOGGED_PATH_LENGTH)
+                );
+            }
+
+            if let Some(size) = oversized_content_length(config, response) {
+                warn!(
+                    "Served {} with {size} bytes, which exceeds the threshold of {} bytes",
+                    truncate(path, MAX_LOGGED_PATH_LENGTH),
+                    config.size_warn_threshold.unwrap_or_default()
+                );
+            }
+
+            log_line
+                .v1
+                .bytes(response.get_content_length())
+                .status(Some(response.get_status().as_u16()));
+            log_line.v2.final_status(response.get_status().as_u16());
+            // The headers are counted as they are sent to the client, after they have been stripped
+            log_line
+                .v2
+                .cache_status(Some(cache_status(response).into()))
+                .response_header_count(Some(response.get_headers().count()));
+        }
+        Err(error) => collect_error(log_line, error),
+    }
+
+    log_line.to_owned()
+}
+
+/// Collect data for the logs from an error that prevented a response
+///
+/// The client gets HTTP 500 Internal Server Error without a body, so no bytes were sent.
+fn collect_error(log_line: &mut LogLineBuilder, error: &Error) {
+    metrics::increment(metrics::status_class_counter(500));
+
+    log_line
+        .v1
+        .bytes(None)
+        .status(Some(500))
+        .error_detail(Some(truncate(&error.to_string(), MAX_ERROR_DETAIL_LENGTH)));
+    log_line.v2.final_status(500);
+}
+
+/// Decide if the request is logged
+///
+/// Only a configurable fraction of successful requests is logged to control the volume of the
+/// logs, while errors are always logged. The decision is based on a hash of the request identifier,
+/// which makes it stable and reproducible from the logs.
+///
+/// Clients can provide their own request identifier, and could choose one that is never sampled.
+/// The identifier is therefore hashed together with a secret salt from the dictionary, which
+/// clients don't know.
+fn is_sampled(config: &Config, request_id: &str, response: &Result<Response, Error>) -> bool {
+    let is_error = match response {
+        Ok(response) => response.get_status().is_server_error(),
+        Err(_) => true,
+    };
+
+    if is_error || config.log_sample_rate >= 1.0 {
+        return true;
+    }
+
+    let hash = fnv1a(format!("{}{request_id}", config.log_sample_salt).as_bytes());
+    (hash as f64 / u64::MAX as f64) < config.log_sample_rate
+}
+
+/// Hash bytes with the 64-bit FNV-1a algorithm
+///
+/// The hasher of the standard library is not guaranteed to be stable across releases, so we use a
+/// simple hash that is.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Determine the cache status of the response
+///
+/// Fastly reports whether a response was served from its cache in the `X-Cache` header. When the
+/// request passed through multiple caches, e.g. through a shield, the header contains one entry
+/// per cache and the last entry belongs to the cache closest to the client.
+fn cache_status(response: &Response) -> &'static str {
+    let status = response
+        .get_header("X-Cache")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.rsplit(',').next())
+        .map(str::trim);
+
+    match status {
+        Some(status) if status.starts_with("HIT") => "HIT",
+        Some(status) if status.starts_with("MISS") => "MISS",
+        Some(status) if status.starts_with("PASS") => "PASS",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Finalize the builder and log the line
+fn build_and_send_log(log_line: LogLineBuilder, config: &Config) {
+    let json_line = log_line
+        .build(config.log_schema_version)
+        .and_then(|versioned_log| Ok(Envelope::from(versioned_log).to_json_line()?));
+
+    match json_line {
+        Ok(json_line) => {
+            info!(target: &config.request_logs_endpoint, "{json_line}")
+        }
+        Err(error) => {
+            warn!("failed to serialize request log: {error}");
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::Once;
+
+    use super::*;
+    use crate::backend_selector::{BackendSelector, PrimaryWithFallback};
+
+    thread_local! {
+        static REQUEST_LOGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Logger that captures the request logs of the current thread
+    struct CapturingLogger;
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            if record.target() == "request-logs" {
+                REQUEST_LOGS.with(|logs| logs.borrow_mut().push(record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_request_logs(handle: impl FnOnce()) -> Vec<String> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_boxed_logger(Box::new(CapturingLogger)).unwrap();
+            log::set_max_level(LevelFilter::Debug);
+        });
+
+        REQUEST_LOGS.with(|logs| logs.borrow_mut().clear());
+        handle();
+        REQUEST_LOGS.with(RefCell::take)
+    }
+
+    fn request_log_line() -> LogLineBuilder {
+        let mut log_line = LogLineBuilder::default();
+        log_line
+            .v1
+            .date_time(OffsetDateTime::UNIX_EPOCH)
+            .url("https://static.crates.io/crates/foo/foo-1.0.0.crate".into())
+            .ip(None)
+            .method(Some("GET".into()));
+        log_line
+    }
+
+    #[test]
+    fn collect_response_logs_errors() {
+        let config = Config::for_tests(&[]);
+        let mut log_line = request_log_line();
+        let response = Err(Error::msg("connection refused"));
+        let log_line = collect_response(&config, &mut log_line, &response, "/crates/foo");
+
+        for schema_version in [1, 2] {
+            let log_line = log_line.build(schema_version).unwrap();
+            let json_line = Envelope::from(log_line).to_json_line().unwrap();
+
+            assert!(json_line.contains(r#""bytes":null"#));
+            assert!(json_line.contains(r#""status":500"#));
+            assert!(json_line.contains(r#""error_detail":"connection refused""#));
+        }
+
+        let json_line = Envelope::from(log_line.build(2).unwrap())
+            .to_json_line()
+            .unwrap();
+        assert!(json_line.contains(r#""final_status":500"#));
+    }
+
+    fn cors_response(config: &Config, origin: &str) -> Response {
+        let mut response = Ok(Response::new());
+        add_cors_headers(config, &mut response, origin);
+        response.unwrap()
+    }
+
+    #[test]
+    fn cors_echoes_allowed_origin() {
+        let config = Config::for_tests(&[("cors-allowed-origins", "https://crates.io")]);
+        let response = cors_response(&config, "https://crates.io");
+
+        assert_eq!(
+            response.get_header_str("Access-Control-Allow-Origin"),
+            Some("https://crates.io")
+        );
+        assert_eq!(response.get_header_str("Vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn cors_omits_disallowed_origin() {
+        let config = Config::for_tests(&[("cors-allowed-origins", "https://crates.io")]);
+        let response = cors_response(&config, "https://example.com");
+
+        assert!(!response.contains_header("Access-Control-Allow-Origin"));
+        assert!(!response.contains_header("Access-Control-Allow-Methods"));
+    }
+
+    #[test]
+    fn cors_allows_all_origins_without_list() {
+        let config = Config::for_tests(&[]);
+        let response = cors_response(&config, "https://example.com");
+
+        assert_eq!(
+            response.get_header_str("Access-Control-Allow-Origin"),
+            Some("*")
+        );
+        assert!(!response.contains_header("Vary"));
+    }
+
+    fn cache_key(config: &Config, request: &mut Request) -> String {
+        normalize_path(request);
+        canonicalize_s3_path(request);
+        negotiate_content_encoding(request);
+        compute_cache_key(config, request)
+    }
+
+    #[test]
+    fn identical_requests_share_cache_key() {
+        let config = Config::for_tests(&[]);
+
+        let mut plus = Request::get("https://static.crates.io/crates/foo/foo-1.0.0+1.crate");
+        let mut encoded = Request::get("https://static.crates.io//crates/foo/foo-1.0.0%2b1.crate");
+
+        assert_eq!(
+            cache_key(&config, &mut plus),
+            cache_key(&config, &mut encoded)
+        );
+        assert!(collapses(&config, &plus));
+        assert!(collapses(&config, &encoded));
+    }
+
+    #[test]
+    fn distinct_requests_have_distinct_cache_keys() {
+        let config = Config::for_tests(&[]);
+
+        let mut foo = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+        let mut bar = Request::get("https://static.crates.io/crates/bar/bar-1.0.0.crate");
+
+        assert_ne!(cache_key(&config, &mut foo), cache_key(&config, &mut bar));
+    }
+
+    #[test]
+    fn requests_are_not_collapsed_when_disabled() {
+        let config = Config::for_tests(&[("request-collapsing", "false")]);
+        let request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+
+        assert!(!collapses(&config, &request));
+    }
+
+    #[test]
+    fn purges_are_not_collapsed() {
+        let config = Config::for_tests(&[]);
+        let request = Request::new(
+            Method::from_bytes(b"PURGE").unwrap(),
+            "https://static.crates.io/crates/foo/foo-1.0.0.crate",
+        );
+
+        assert!(!collapses(&config, &request));
+    }
+
+    fn ttl_for(config: &Config, url: &str) -> u32 {
+        let mut request = Request::get(url);
+        let mut log = LogLineBuilder::default();
+
+        normalize_path(&mut request);
+        canonicalize_s3_path(&mut request);
+        set_ttl(config, &mut request, &mut log).expect("TTL is set on the request")
+    }
+
+    #[test]
+    fn ttl_uses_matching_prefix() {
+        let config = Config::for_tests(&[("ttl-overrides", "/index/=60,/crates/=31536000")]);
+
+        let ttl = ttl_for(&config, "https://static.crates.io/index/se/rd/serde");
+        assert_eq!(ttl, 60);
+    }
+
+    #[test]
+    fn ttl_uses_longest_matching_prefix() {
+        let config = Config::for_tests(&[("ttl-overrides", "/crates/=31536000,/crates/foo/=300")]);
+
+        let ttl = ttl_for(
+            &config,
+            "https://static.crates.io/crates/foo/foo-1.0.0.crate",
+        );
+        assert_eq!(ttl, 300);
+
+        let ttl = ttl_for(
+            &config,
+            "https://static.crates.io/crates/bar/bar-1.0.0.crate",
+        );
+        assert_eq!(ttl, 31536000);
+    }
+
+    #[test]
+    fn ttl_falls_back_to_static_ttl() {
+        let config = Config::for_tests(&[("static-ttl", "3600"), ("ttl-overrides", "/index/=60")]);
+
+        let ttl = ttl_for(
+            &config,
+            "https://static.crates.io/crates/foo/foo-1.0.0.crate",
+        );
+        assert_eq!(ttl, 3600);
+    }
+
+    #[test]
+    fn ttl_matches_canonical_path() {
+        let config = Config::for_tests(&[("ttl-overrides", "/index/=60")]);
+
+        let ttl = ttl_for(&config, "https://static.crates.io//index/se/rd/serde");
+        assert_eq!(ttl, 60);
+
+        let ttl = ttl_for(&config, "https://static.crates.io/%69ndex/se/rd/serde");
+        assert_eq!(ttl, 60);
+    }
+
+    #[test]
+    fn hot_paths_use_hot_path_ttl() {
+        let config = Config::for_tests(&[
+            ("hot-paths", "/index/config.json"),
+            ("hot-path-ttl", "5"),
+            ("ttl-overrides", "/index/=60"),
+        ]);
+
+        assert_eq!(
+            ttl_for(&config, "https://static.crates.io/index/config.json"),
+            5
+        );
+        assert_eq!(
+            ttl_for(&config, "https://static.crates.io//index/config.json"),
+            5
+        );
+    }
+
+    #[test]
+    fn other_paths_use_static_ttl() {
+        let config =
+            Config::for_tests(&[("hot-paths", "/index/config.json"), ("static-ttl", "3600")]);
+
+        assert_eq!(
+            ttl_for(&config, "https://static.crates.io/index/config.json.bak"),
+            3600
+        );
+    }
+
+    /// Get the cache settings of a request, which the SDK only exposes in the debug output
+    fn cache_override(request: &Request) -> String {
+        let debug = format!("{request:?}");
+        let start = debug
+            .find("cache_override: ")
+            .expect("request has cache settings");
+        let end = debug[start..].find(", is_from_client").unwrap();
+
+        debug[start + "cache_override: ".len()..start + end].to_string()
+    }
+
+    #[test]
+    fn stale_while_revalidate_is_set_when_configured() {
+        let config = Config::for_tests(&[("stale-while-revalidate", "60")]);
+        let mut request = Request::get("https://static.crates.io/index/se/rd/serde");
+
+        set_ttl(&config, &mut request, &mut LogLineBuilder::default());
+
+        assert!(cache_override(&request).contains("stale_while_revalidate: Some(60)"));
+    }
+
+    #[test]
+    fn stale_while_revalidate_is_disabled_by_default() {
+        let config = Config::for_tests(&[("stale-while-revalidate", "0")]);
+        let mut request = Request::get("https://static.crates.io/index/se/rd/serde");
+
+        set_ttl(&config, &mut request, &mut LogLineBuilder::default());
+
+        assert!(cache_override(&request).contains("stale_while_revalidate: None"));
+    }
+
+    #[test]
+    fn configured_ttl_overrides_origin() {
+        let config = Config::for_tests(&[("static-ttl", "3600")]);
+        let mut request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+
+        let ttl = set_ttl(&config, &mut request, &mut LogLineBuilder::default());
+
+        assert_eq!(ttl, Some(3600));
+        assert!(cache_override(&request).contains("ttl: Some(3600)"));
+    }
+
+    #[test]
+    fn origin_wins_leaves_ttl_to_fastly() {
+        let config =
+            Config::for_tests(&[("cache-precedence", "origin_wins"), ("static-ttl", "3600")]);
+        let mut request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+        let mut log_line = request_log_line();
+
+        assert_eq!(set_ttl(&config, &mut request, &mut log_line), None);
+        assert_eq!(cache_override(&request), "None");
+
+        let response = Response::new().with_header("Cache-Control", "max-age=60");
+        log_origin_ttl(&config, &response, &mut log_line);
+        let log_line = collect_response(&config, &mut log_line, &Ok(response), "/");
+
+        let log_line = json_log_line(&log_line, 2);
+        assert_eq!(log_line["ttl_applied"], 60);
+        assert_eq!(log_line["ttl_rule"], "origin");
+    }
+
+    #[test]
+    fn origin_ttl_is_missing_without_max_age() {
+        let config = Config::for_tests(&[("cache-precedence", "origin_wins")]);
+        let mut log_line = request_log_line();
+
+        let response = Response::new().with_header("Cache-Control", "public");
+        log_origin_ttl(&config, &response, &mut log_line);
+        let log_line = collect_response(&config, &mut log_line, &Ok(response), "/");
+
+        assert_eq!(json_log_line(&log_line, 2)["ttl_applied"], json!(null));
+    }
+
+    #[test]
+    fn origin_ttl_is_not_logged_when_config_wins() {
+        let config = Config::for_tests(&[("static-ttl", "3600")]);
+        let mut request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+        let mut log_line = request_log_line();
+
+        set_ttl(&config, &mut request, &mut log_line);
+        let response = Response::new().with_header("Cache-Control", "max-age=60");
+        log_origin_ttl(&config, &response, &mut log_line);
+        let log_line = collect_response(&config, &mut log_line, &Ok(response), "/");
+
+        assert_eq!(json_log_line(&log_line, 2)["ttl_applied"], 3600);
+    }
+
+    #[test]
+    fn client_errors_expire_after_negative_ttl() {
+        let config = Config::for_tests(&[("negative-ttl", "10")]);
+
+        let fresh = Response::new().with_status(404).with_header("Age", "10");
+        let expired = Response::new().with_status(404).with_header("Age", "11");
+        let uncached = Response::new().with_status(404);
+
+        assert!(!is_expired_negative_response(&config, &fresh));
+        assert!(is_expired_negative_response(&config, &expired));
+        assert!(!is_expired_negative_response(&config, &uncached));
+    }
+
+    #[test]
+    fn successful_responses_never_expire_early() {
+        let config = Config::for_tests(&[("negative-ttl", "10")]);
+        let response = Response::new().with_header("Age", "3600");
+
+        assert!(!is_expired_negative_response(&config, &response));
+    }
+
+    #[test]
+    fn negative_ttl_can_be_disabled() {
+        let config = Config::for_tests(&[("negative-ttl", "0")]);
+        let response = Response::new().with_status(404).with_header("Age", "3600");
+
+        assert!(!is_expired_negative_response(&config, &response));
+    }
+
+    #[test]
+    fn client_errors_get_negative_ttl_downstream() {
+        let config = Config::for_tests(&[("negative-ttl", "10")]);
+        let mut response = Response::new().with_status(404);
+
+        apply_negative_ttl(&config, &mut response);
+
+        assert_eq!(
+            response.get_header_str("Cache-Control"),
+            Some("public, max-age=10")
+        );
+    }
+
+    #[test]
+    fn successful_responses_keep_their_ttl_downstream() {
+        let config = Config::for_tests(&[("negative-ttl", "10")]);
+        let mut response = Response::new().with_header("Cache-Control", "public, max-age=3600");
+
+        apply_negative_ttl(&config, &mut response);
+
+        assert_eq!(
+            response.get_header_str("Cache-Control"),
+            Some("public, max-age=3600")
+        );
+        assert!(!response.contains_header("Surrogate-Control"));
+    }
+
+    #[test]
+    fn error_document_is_served_when_found() {
+        let document = Response::new().with_header("Content-Type", "text/html");
+        let document = usable_error_document(Ok(Some(document))).unwrap();
+
+        assert_eq!(document.get_header_str("Content-Type"), Some("text/html"));
+    }
+
+    #[test]
+    fn error_document_falls_back_when_missing() {
+        let missing = Response::new().with_status(404);
+
+        assert!(usable_error_document(Ok(Some(missing))).is_none());
+        assert!(usable_error_document(Ok(None)).is_none());
+        assert!(usable_error_document(Err(Error::msg("connection refused"))).is_none());
+    }
+
+    #[test]
+    fn error_document_disables_custom_404() {
+        let config =
+            Config::for_tests(&[("custom-404", "true"), ("error-document-path", "/404.html")]);
+
+        assert!(!config.custom_404);
+        assert_eq!(config.warnings.len(), 1);
+    }
+
+    #[test]
+    fn custom_404_is_kept_without_error_document() {
+        let config = Config::for_tests(&[("custom-404", "true")]);
+
+        assert!(config.custom_404);
+    }
+
+    fn not_found_from_s3() -> Response {
+        Response::from_status(StatusCode::NOT_FOUND)
+            .with_header("ETag", "\"abc\"")
+            .with_header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+    }
+
+    #[test]
+    fn not_found_body_is_replaced_when_enabled() {
+        let config = Config::for_tests(&[("custom-404", "true")]);
+        let mut response = not_found_from_s3();
+
+        assert!(strip_not_found_validators(&config, &mut response));
+        assert_eq!(response.get_status(), StatusCode::NOT_FOUND);
+        assert!(!response.contains_header("ETag"));
+        assert!(!response.contains_header("Last-Modified"));
+    }
+
+    #[test]
+    fn not_found_body_is_kept_when_disabled() {
+        let config = Config::for_tests(&[]);
+        let mut response = not_found_from_s3();
+
+        assert!(!strip_not_found_validators(&config, &mut response));
+        assert_eq!(response.get_status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.get_header_str("ETag"), Some("\"abc\""));
+        assert!(response.contains_header("Last-Modified"));
+    }
+
+    #[test]
+    fn only_not_found_bodies_are_replaced() {
+        let config = Config::for_tests(&[("custom-404", "true")]);
+        let mut response = Response::new().with_header("ETag", "\"abc\"");
+
+        assert!(!strip_not_found_validators(&config, &mut response));
+        assert_eq!(response.get_header_str("ETag"), Some("\"abc\""));
+    }
+
+    #[test]
+    fn log_guard_logs_rejected_request() {
+        let config = Config::for_tests(&[]);
+
+        let logs = capture_request_logs(|| {
+            let mut log = LogGuard {
+                config: &config,
+                log_line: request_log_line(),
+                is_sampled: true,
+            };
+
+            let response = Ok(Response::new().with_status(StatusCode::FORBIDDEN));
+            log.log_line = collect_response(&config, &mut log.log_line, &response, "/");
+        });
+
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].contains(r#""status":403"#));
+    }
+
+    #[test]
+    fn log_guard_logs_purge() {
+        let config = Config::for_tests(&[]);
+
+        let logs = capture_request_logs(|| {
+            let mut log_line = request_log_line();
+            log_line.v1.method(Some("PURGE".into()));
+
+            let _log = LogGuard {
+                config: &config,
+                log_line,
+                is_sampled: true,
+            };
+        });
+
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].contains(r#""method":"PURGE""#));
+    }
+
+    #[test]
+    fn log_guard_logs_on_early_return() {
+        fn handle(config: &Config) -> Result<(), Error> {
+            let _log = LogGuard {
+                config,
+                log_line: request_log_line(),
+                is_sampled: true,
+            };
+
+            Err(Error::msg("request failed"))
+        }
+
+        let config = Config::for_tests(&[]);
+        let logs = capture_request_logs(|| assert!(handle(&config).is_err()));
+
+        assert_eq!(logs.len(), 1);
+    }
+
+    #[test]
+    fn log_guard_skips_unsampled_request() {
+        let config = Config::for_tests(&[]);
+
+        let logs = capture_request_logs(|| {
+            let _log = LogGuard {
+                config: &config,
+                log_line: request_log_line(),
+                is_sampled: false,
+            };
+        });
+
+        assert!(logs.is_empty());
+    }
+
+    #[test]
+    fn compressed_responses_are_passed_through() {
+        let mut response = Response::new().with_header("Content-Encoding", "gzip");
+
+        vary_on_accept_encoding(&mut response);
+
+        assert_eq!(response.get_header_str("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.get_header_str("Vary"), Some("Accept-Encoding"));
+        assert!(!response.contains_header("X-Compress-Hint"));
+    }
+
+    #[test]
+    fn uncompressed_responses_vary_on_encoding() {
+        let mut response = Response::new();
+
+        vary_on_accept_encoding(&mut response);
+
+        assert!(!response.contains_header("Content-Encoding"));
+        assert_eq!(response.get_header_str("Vary"), Some("Accept-Encoding"));
+        assert!(!response.contains_header("X-Compress-Hint"));
+    }
+
+    #[test]
+    fn vary_on_encoding_is_added_once() {
+        let mut response = Response::new().with_header("Vary", "Origin, accept-encoding");
+
+        vary_on_accept_encoding(&mut response);
+
+        assert_eq!(
+            response.get_header_all_str("Vary"),
+            ["Origin, accept-encoding"]
+        );
+    }
+
+    #[test]
+    fn if_none_match_with_weak_etag_is_not_modified() {
+        let mut request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+        request.set_header("If-None-Match", "\"abc\"");
+        let mut response = Response::new().with_header("ETag", "W/\"abc\"");
+
+        answer_if_none_match(&request, &mut response);
+
+        assert_eq!(response.get_status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.get_header_str("ETag"), Some("W/\"abc\""));
+    }
+
+    #[test]
+    fn if_none_match_with_other_etag_is_served() {
+        let mut request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+        request.set_header("If-None-Match", "\"def\"");
+        let mut response = Response::new().with_header("ETag", "\"abc\"");
+
+        answer_if_none_match(&request, &mut response);
+
+        assert_eq!(response.get_status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn synthesized_etag_answers_if_none_match() {
+        let request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+        let mut response =
+            Response::new().with_header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT");
+        synthesize_etag(&request, &mut response);
+
+        let etag = response.get_header_str("ETag").unwrap().to_string();
+        assert!(etag.starts_with("W/"));
+
+        let mut conditional = request.clone_without_body();
+        conditional.set_header("If-None-Match", etag.as_str());
+        answer_if_none_match(&conditional, &mut response);
+
+        assert_eq!(response.get_status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn if_range_with_weak_etag_fetches_full_file() {
+        let mut request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+        request.set_header("If-Range", "\"abc\"");
+
+        let strong = Response::new()
+            .with_status(StatusCode::PARTIAL_CONTENT)
+            .with_header("ETag", "\"abc\"");
+        let weak = Response::new()
+            .with_status(StatusCode::PARTIAL_CONTENT)
+            .with_header("ETag", "W/\"abc\"");
+
+        assert!(if_range_matches(&request, &strong));
+        assert!(!if_range_matches(&request, &weak));
+    }
+
+    fn trailing_slash_redirect(config: &Config, url: &str) -> Option<String> {
+        let request = Request::get(url);
+        let response = redirect_trailing_slash(config, &request)?;
+
+        assert_eq!(response.get_status(), StatusCode::MOVED_PERMANENTLY);
+        response.get_header_str("Location").map(String::from)
+    }
+
+    #[test]
+    fn strip_policy_removes_trailing_slash() {
+        let config = Config::for_tests(&[("trailing-slash-policy", "strip")]);
+
+        assert_eq!(
+            trailing_slash_redirect(&config, "https://static.crates.io/crates/foo/?a=b"),
+            Some("/crates/foo?a=b".into())
+        );
+        assert_eq!(
+            trailing_slash_redirect(&config, "https://static.crates.io/crates/foo"),
+            None
+        );
+        assert_eq!(
+            trailing_slash_redirect(&config, "https://static.crates.io/"),
+            None
+        );
+    }
+
+    #[test]
+    fn keep_policy_adds_trailing_slash_to_directories() {
+        let config = Config::for_tests(&[("trailing-slash-policy", "keep")]);
+
+        assert_eq!(
+            trailing_slash_redirect(&config, "https://static.crates.io/crates/foo"),
+            Some("/crates/foo/".into())
+        );
+        assert_eq!(
+            trailing_slash_redirect(&config, "https://static.crates.io/crates/foo/"),
+            None
+        );
+        assert_eq!(
+            trailing_slash_redirect(
+                &config,
+                "https://static.crates.io/crates/foo/foo-1.0.0.crate"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn keep_policy_skips_sparse_index() {
+        let config = Config::for_tests(&[("trailing-slash-policy", "keep")]);
+
+        assert_eq!(
+            trailing_slash_redirect(&config, "https://static.crates.io/index/3/s/syn"),
+            None
+        );
+    }
+
+    #[test]
+    fn off_policy_leaves_paths_unchanged() {
+        let config = Config::for_tests(&[]);
+
+        assert_eq!(
+            trailing_slash_redirect(&config, "https://static.crates.io/crates/foo/"),
+            None
+        );
+        assert_eq!(
+            trailing_slash_redirect(&config, "https://static.crates.io/crates/foo"),
+            None
+        );
+    }
+
+    #[test]
+    fn sampling_never_drops_errors() {
+        let config = Config::for_tests(&[("log-sample-rate", "0.0")]);
+
+        assert!(is_sampled(
+            &config,
+            "request-id",
+            &Ok(Response::from_status(StatusCode::BAD_GATEWAY))
+        ));
+        assert!(is_sampled(
+            &config,
+            "request-id",
+            &Err(Error::msg("request failed"))
+        ));
+    }
+
+    #[test]
+    fn sampling_rate_zero_drops_successes() {
+        let config = Config::for_tests(&[("log-sample-rate", "0.0")]);
+
+        for status in [
+            StatusCode::OK,
+            StatusCode::NOT_MODIFIED,
+            StatusCode::NOT_FOUND,
+        ] {
+            assert!(!is_sampled(
+                &config,
+                "request-id",
+                &Ok(Response::from_status(status))
+            ));
+        }
+    }
+
+    #[test]
+    fn sampling_rate_one_keeps_successes() {
+        let config = Config::for_tests(&[]);
+
+        assert!(is_sampled(
+            &config,
+            "request-id",
+            &Ok(Response::from_status(StatusCode::OK))
+        ));
+    }
+
+    #[test]
+    fn sampling_is_stable_for_an_identifier() {
+        let config = Config::for_tests(&[("log-sample-rate", "0.5")]);
+        let response = Ok(Response::from_status(StatusCode::OK));
+
+        let sampled = (0..100)
+            .filter(|index| {
+                let id = format!("request-id-{index}");
+                let first = is_sampled(&config, &id, &response);
+                assert_eq!(is_sampled(&config, &id, &response), first);
+                first
+            })
+            .count();
+
+        assert!(sampled > 0 && sampled < 100);
+    }
+
+    #[test]
+    fn strip_response_headers_removes_internal_headers() {
+        let config = Config::for_tests(&[]);
+        let mut response = Response::new()
+            .with_header("x-amz-request-id", "4442587FB7D0A2F9")
+            .with_header(
+                "x-amz-id-2",
+                "vlR7PnpV2Ce81l0PRw6jlUpck7Jo5ZsQjryTjKlc5aLWGVHPZLj5NeC6qMa0emYBDXOo6QBU0Wo=",
+            )
+            .with_header("Server", "AmazonS3");
+
+        strip_response_headers(&config, &mut response);
+
+        assert_eq!(response.get_header_names().count(), 0);
+    }
+
+    #[test]
+    fn strip_response_headers_keeps_other_headers() {
+        let config = Config::for_tests(&[]);
+        let mut response = Response::new()
+            .with_header("Content-Type", "application/gzip")
+            .with_header("Content-Length", "1024")
+            .with_header("ETag", "\"abc\"")
+            .with_header("Server-Timing", "edge;dur=1")
+            .with_header("Cache-Control", "public");
+
+        strip_response_headers(&config, &mut response);
+
+        assert_eq!(response.get_header_names().count(), 5);
+    }
+
+    #[test]
+    fn strip_response_headers_preserves_essential_headers() {
+        let config = Config::for_tests(&[("stripped-response-headers", "content-,etag")]);
+        let mut response = Response::new()
+            .with_header("Content-Type", "application/gzip")
+            .with_header("Content-Encoding", "gzip")
+            .with_header("ETag", "\"abc\"");
+
+        strip_response_headers(&config, &mut response);
+
+        assert!(response.contains_header("Content-Type"));
+        assert!(response.contains_header("ETag"));
+        assert!(!response.contains_header("Content-Encoding"));
+    }
+
+    #[test]
+    fn preflight_is_answered_at_the_edge() {
+        let config = Config::for_tests(&[]);
+        let request = Request::new(Method::OPTIONS, "https://static.crates.io/crates/foo")
+            .with_header("Origin", "https://crates.io")
+            .with_header("Access-Control-Request-Method", "GET");
+
+        let response = answer_cors_preflight(&config, &request).unwrap();
+
+        assert_eq!(response.get_status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.get_header_str("Access-Control-Allow-Methods"),
+            Some("GET, HEAD")
+        );
+        assert_eq!(
+            response.get_header_str("Access-Control-Max-Age"),
+            Some(CORS_MAX_AGE)
+        );
+    }
+
+    #[test]
+    fn options_without_preflight_headers_is_not_answered() {
+        let config = Config::for_tests(&[]);
+        let request = Request::new(Method::OPTIONS, "https://static.crates.io/crates/foo");
+
+        assert!(answer_cors_preflight(&config, &request).is_none());
+    }
+
+    #[test]
+    fn get_is_not_answered_as_preflight() {
+        let config = Config::for_tests(&[]);
+        let request = Request::get("https://static.crates.io/crates/foo")
+            .with_header("Access-Control-Request-Method", "GET");
+
+        assert!(answer_cors_preflight(&config, &request).is_none());
+    }
+
+    /// Backend that answers each host with its queued status codes, where `None` is a timeout
+    #[derive(Default)]
+    struct MockBackend {
+        responses: HashMap<&'static str, VecDeque<Option<u16>>>,
+        sent: Vec<String>,
+        delay: Duration,
+    }
+
+    impl MockBackend {
+        fn new(responses: &[(&'static str, &[Option<u16>])]) -> Self {
+            Self {
+                responses: responses
+                    .iter()
+                    .map(|(host, statuses)| (*host, statuses.iter().copied().collect()))
+                    .collect(),
+                ..Self::default()
+            }
+        }
+
+        fn send(&mut self, host: &str) -> Result<Option<Response>, Error> {
+            self.sent.push(host.into());
+            std::thread::sleep(self.delay);
+
+            let status = self
+                .responses
+                .get_mut(host)
+                .and_then(VecDeque::pop_front)
+                .expect("mock backend has a response for the host");
+
+            Ok(status.map(|status| Response::from_status(StatusCode::from_u16(status).unwrap())))
+        }
+    }
+
+    const PRIMARY: &str = "primary.s3.example.com";
+    const FALLBACK_1: &str = "fallback-1.s3.example.com";
+    const FALLBACK_2: &str = "fallback-2.s3.example.com";
+
+    fn failover_config(items: &[(&str, &str)]) -> Config {
+        let mut items = items.to_vec();
+        items.push((
+            "s3-fallback-host",
+            "fallback-1.s3.example.com,fallback-2.s3.example.com",
+        ));
+        Config::for_tests(&items)
+    }
+
+    fn try_mock_hosts(
+        config: &Config,
+        backend: &mut MockBackend,
+        log: &mut LogLineBuilder,
+    ) -> Failover {
+        let candidates = Candidates {
+            primary_host: &config.primary_host,
+            fallback_hosts: &config.fallback_hosts,
+            skip_primary: false,
+        };
+        let request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+        let hosts = PrimaryWithFallback.select(&request, &candidates);
+
+        try_hosts(config, &candidates, &hosts, None, log, |host| {
+            backend.send(host)
+        })
+        .unwrap()
+    }
+
+    fn responded_status(failover: Failover) -> u16 {
+        match failover {
+            Failover::Responded(response) => response.get_status().as_u16(),
+            Failover::TimedOut => panic!("hosts timed out"),
+            Failover::FallbackSaturated => panic!("fallback cap reached"),
+        }
+    }
+
+    #[test]
+    fn failover_walks_the_full_chain() {
+        let config = failover_config(&[("primary-retries", "0")]);
+        let mut backend = MockBackend::new(&[
+            (PRIMARY, &[Some(503)]),
+            (FALLBACK_1, &[Some(500)]),
+            (FALLBACK_2, &[Some(200)]),
+        ]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+
+        assert_eq!(responded_status(failover), 200);
+        assert_eq!(backend.sent, [PRIMARY, FALLBACK_1, FALLBACK_2]);
+    }
+
+    #[test]
+    fn failover_stops_on_first_success() {
+        let config = failover_config(&[("primary-retries", "0")]);
+        let mut backend = MockBackend::new(&[
+            (PRIMARY, &[Some(503)]),
+            (FALLBACK_1, &[Some(200)]),
+            (FALLBACK_2, &[Some(200)]),
+        ]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+
+        assert_eq!(responded_status(failover), 200);
+        assert_eq!(backend.sent, [PRIMARY, FALLBACK_1]);
+    }
+
+    #[test]
+    fn failover_returns_last_response_when_all_hosts_fail() {
+        let config = failover_config(&[("primary-retries", "0")]);
+        let mut backend = MockBackend::new(&[
+            (PRIMARY, &[Some(503)]),
+            (FALLBACK_1, &[Some(503)]),
+            (FALLBACK_2, &[Some(502)]),
+        ]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+
+        assert_eq!(responded_status(failover), 502);
+        assert_eq!(backend.sent, [PRIMARY, FALLBACK_1, FALLBACK_2]);
+    }
+
+    fn json_log_line(log_line: &LogLineBuilder, schema_version: u8) -> serde_json::Value {
+        let log_line = log_line.build(schema_version).unwrap();
+        let json_line = Envelope::from(log_line).to_json_line().unwrap();
+        serde_json::from_str(&json_line).unwrap()
+    }
+
+    #[test]
+    fn backend_duration_is_logged() {
+        let config = failover_config(&[]);
+        let mut backend = MockBackend::new(&[(PRIMARY, &[Some(200)])]);
+        backend.delay = Duration::from_millis(5);
+
+        let mut log_line = request_log_line();
+        try_mock_hosts(&config, &mut backend, &mut log_line);
+
+        let backend_duration_ms = json_log_line(&log_line, 1)["backend_duration_ms"]
+            .as_u64()
+            .unwrap();
+        assert!(backend_duration_ms >= 5);
+    }
+
+    #[test]
+    fn backend_duration_is_omitted_without_backend() {
+        assert!(json_log_line(&request_log_line(), 1)["backend_duration_ms"].is_null());
+    }
+
+    #[test]
+    fn purge_with_valid_token_is_authorized() {
+        let config = Config::for_tests(&[("purge-token", "secret")]);
+        let token = HeaderValue::from_static("secret");
+
+        assert!(is_purge_authorized(&config, Some(&token)));
+    }
+
+    #[test]
+    fn purge_with_invalid_token_is_rejected() {
+        let config = Config::for_tests(&[("purge-token", "secret")]);
+
+        for token in ["wrong!", "secre", "secrets", ""] {
+            let token = HeaderValue::from_static(token);
+            assert!(!is_purge_authorized(&config, Some(&token)));
+        }
+    }
+
+    #[test]
+    fn purge_without_token_is_rejected() {
+        let config = Config::for_tests(&[("purge-token", "secret")]);
+
+        assert!(!is_purge_authorized(&config, None));
+    }
+
+    #[test]
+    fn purge_is_rejected_without_configured_token() {
+        let config = Config::for_tests(&[]);
+        let token = HeaderValue::from_static("secret");
+
+        assert!(!is_purge_authorized(&config, Some(&token)));
+        assert!(!is_purge_authorized(&config, None));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_content_and_length() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"Secret"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn health_check_reports_status_and_version() {
+        let body = health_check_body();
+
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    fn normalized_path(url: &str) -> String {
+        let mut request = Request::get(url);
+        normalize_path(&mut request);
+        request.get_path().to_string()
+    }
+
+    #[test]
+    fn leading_double_slash_is_collapsed() {
+        assert_eq!(
+            normalized_path("https://static.crates.io//crates/foo/foo-1.0.0.crate"),
+            "/crates/foo/foo-1.0.0.crate"
+        );
+    }
+
+    #[test]
+    fn interior_slashes_are_collapsed() {
+        assert_eq!(
+            normalized_path("https://static.crates.io/crates///foo//foo-1.0.0.crate?a=b"),
+            "/crates/foo/foo-1.0.0.crate"
+        );
+    }
+
+    #[test]
+    fn encoded_slashes_are_left_alone() {
+        assert_eq!(
+            normalized_path("https://static.crates.io/crates/%2F/foo%2F%2Fbar"),
+            "/crates/%2F/foo%2F%2Fbar"
+        );
+    }
+
+    fn negotiated_backend_request(accept_encoding: Option<&str>) -> Request {
+        let config = Config::for_tests(&[]);
+        let mut request = Request::get("https://static.crates.io/index/se/rd/serde");
+        if let Some(accept_encoding) = accept_encoding {
+            request.set_header("Accept-Encoding", accept_encoding);
+        }
+
+        negotiate_content_encoding(&mut request);
+        build_backend_request(&config, &request, &config.primary_host)
+    }
+
+    #[test]
+    fn gzip_is_forwarded_to_s3() {
+        let request = negotiated_backend_request(Some("gzip, deflate"));
+
+        assert_eq!(request.get_header_str("Accept-Encoding"), Some("gzip"));
+    }
+
+    #[test]
+    fn brotli_is_preferred_over_gzip() {
+        let request = negotiated_backend_request(Some("gzip, br"));
+
+        assert_eq!(request.get_header_str("Accept-Encoding"), Some("br"));
+    }
+
+    #[test]
+    fn rejected_encodings_are_not_forwarded() {
+        let request = negotiated_backend_request(Some("br;q=0, gzip;q=0.5"));
+        assert_eq!(request.get_header_str("Accept-Encoding"), Some("gzip"));
+
+        let request = negotiated_backend_request(Some("deflate, identity"));
+        assert!(!request.contains_header("Accept-Encoding"));
+
+        let request = negotiated_backend_request(None);
+        assert!(!request.contains_header("Accept-Encoding"));
+    }
+
+    #[test]
+    fn request_id_of_client_is_passed_through() {
+        let mut request = Request::get("https://static.crates.io/crates/foo")
+            .with_header(REQUEST_ID_HEADER, "client-id_1.2");
+
+        assert_eq!(assign_request_id(&mut request), "client-id_1.2");
+        assert_eq!(
+            request.get_header_str(REQUEST_ID_HEADER),
+            Some("client-id_1.2")
+        );
+    }
+
+    #[test]
+    fn request_id_is_generated_when_missing() {
+        let mut request = Request::get("https://static.crates.io/crates/foo");
+
+        let request_id = assign_request_id(&mut request);
+
+        assert_eq!(request_id.len(), 36);
+        assert_eq!(request_id.as_bytes()[14], b'4');
+        assert_eq!(
+            request.get_header_str(REQUEST_ID_HEADER),
+            Some(request_id.as_str())
+        );
+    }
+
+    #[test]
+    fn invalid_request_id_is_replaced() {
+        let too_long = "a".repeat(MAX_REQUEST_ID_LENGTH + 1);
+
+        for request_id in ["", "id with spaces", "id/slash", too_long.as_str()] {
+            let mut request = Request::get("https://static.crates.io/crates/foo")
+                .with_header(REQUEST_ID_HEADER, request_id);
+
+            assert_ne!(assign_request_id(&mut request), request_id);
+        }
+    }
+
+    #[test]
+    fn generated_request_ids_are_unique() {
+        assert_ne!(generate_request_id(), generate_request_id());
+    }
+
+    #[test]
+    fn request_id_is_logged() {
+        let config = Config::for_tests(&[]);
+        let request = Request::get("https://static.crates.io/crates/foo");
+
+        let mut log_line = collect_request(&config, &request, "request-id", None);
+        log_line.v1.status(Some(200));
+
+        assert_eq!(json_log_line(&log_line, 1)["request_id"], "request-id");
+    }
+
+    #[test]
+    fn disallowed_methods_get_401_by_default() {
+        let response = method_not_allowed(&Config::for_tests(&[]));
+
+        assert_eq!(response.get_status(), StatusCode::UNAUTHORIZED);
+        assert!(!response.contains_header("Allow"));
+    }
+
+    #[test]
+    fn disallowed_methods_get_configured_status() {
+        let config = Config::for_tests(&[("method-not-allowed-status", "403")]);
+        let response = method_not_allowed(&config);
+
+        assert_eq!(response.get_status(), StatusCode::FORBIDDEN);
+        assert!(!response.contains_header("Allow"));
+    }
+
+    #[test]
+    fn method_not_allowed_lists_allowed_methods() {
+        let config = Config::for_tests(&[("method-not-allowed-status", "405")]);
+        let response = method_not_allowed(&config);
+
+        assert_eq!(response.get_status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.get_header_str("Allow"), Some("GET, HEAD"));
+    }
+
+    #[test]
+    fn not_modified_round_trips_with_etag() {
+        let config = failover_config(&[]);
+        let request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("If-None-Match", "\"abc\"");
+
+        let backend_request = build_backend_request(&config, &request, &config.primary_host);
+        assert_eq!(
+            backend_request.get_header_str("If-None-Match"),
+            Some("\"abc\"")
+        );
+
+        let candidates = Candidates {
+            primary_host: &config.primary_host,
+            fallback_hosts: &config.fallback_hosts,
+            skip_primary: false,
+        };
+        let hosts = PrimaryWithFallback.select(&request, &candidates);
+        let mut sent = 0;
+        let failover = try_hosts(
+            &config,
+            &candidates,
+            &hosts,
+            None,
+            &mut request_log_line(),
+            |_| {
+                sent += 1;
+                Ok(Some(
+                    Response::from_status(StatusCode::NOT_MODIFIED)
+                        .with_header("ETag", "\"abc\"")
+                        .with_header("x-amz-request-id", "4442587FB7D0A2F9"),
+                ))
+            },
+        )
+        .unwrap();
+
+        let Failover::Responded(mut response) = failover else {
+            panic!("host did not respond");
+        };
+        strip_response_headers(&config, &mut response);
+
+        assert_eq!(sent, 1);
+        assert_eq!(response.get_status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.get_header_str("ETag"), Some("\"abc\""));
+    }
+
+    #[test]
+    fn path_length_is_limited_at_the_boundary() {
+        let config = Config::for_tests(&[("max-path-length", "16")]);
+
+        assert!(!is_path_too_long(&config, "/crates/foo-1234"));
+        assert!(!is_path_too_long(&config, "/crates/foo-123"));
+        assert!(is_path_too_long(&config, "/crates/foo-12345"));
+    }
+
+    #[test]
+    fn path_length_is_measured_after_decoding() {
+        let config = Config::for_tests(&[("max-path-length", "16")]);
+
+        assert!(!is_path_too_long(&config, "/crates/foo-%31%32%33%34"));
+        assert!(is_path_too_long(&config, "/crates/foo-%31%32%33%34%35"));
+    }
+
+    #[test]
+    fn database_dump_is_redirected_to_cloudfront_by_default() {
+        let config = Config::for_tests(&[]);
+
+        assert!(redirects_to_cloudfront(&config, "/db-dump.tar.gz"));
+        assert!(!redirects_to_cloudfront(&config, "/db-dump.zip"));
+    }
+
+    #[test]
+    fn configured_suffixes_are_redirected_to_cloudfront() {
+        let config = Config::for_tests(&[(
+            "cloudfront-redirect-suffixes",
+            "db-dump.tar.gz, db-dump.zip",
+        )]);
+
+        assert!(redirects_to_cloudfront(&config, "/db-dump.tar.gz"));
+        assert!(redirects_to_cloudfront(&config, "/db-dump.zip"));
+    }
+
+    #[test]
+    fn other_paths_are_not_redirected_to_cloudfront() {
+        let config = Config::for_tests(&[]);
+
+        assert!(!redirects_to_cloudfront(
+            &config,
+            "/crates/foo/foo-1.0.0.crate"
+        ));
+        assert!(!redirects_to_cloudfront(&config, "/db-dump.tar.gz/other"));
+    }
+
+    #[test]
+    fn failures_of_primary_open_circuit_breaker() {
+        let config = failover_config(&[
+            ("primary-retries", "0"),
+            ("circuit-breaker-store", "failover-circuit-breaker"),
+            ("circuit-breaker-threshold", "2"),
+        ]);
+        let candidates = Candidates {
+            primary_host: &config.primary_host,
+            fallback_hosts: &config.fallback_hosts,
+            skip_primary: false,
+        };
+        let request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+        let hosts = PrimaryWithFallback.select(&request, &candidates);
+
+        for _ in 0..2 {
+            let mut circuit_breaker = CircuitBreaker::load(&config).unwrap();
+            assert!(!circuit_breaker.is_open());
+
+            let mut backend =
+                MockBackend::new(&[(PRIMARY, &[Some(503)]), (FALLBACK_1, &[Some(200)])]);
+            try_hosts(
+                &config,
+                &candidates,
+                &hosts,
+                Some(&mut circuit_breaker),
+                &mut request_log_line(),
+                |host| backend.send(host),
+            )
+            .unwrap();
+        }
+
+        assert!(CircuitBreaker::load(&config).unwrap().is_open());
+
+        let candidates = Candidates {
+            skip_primary: true,
+            ..candidates
+        };
+        let hosts = PrimaryWithFallback.select(&request, &candidates);
+        assert_eq!(hosts, [FALLBACK_1, FALLBACK_2]);
+    }
+
+    fn stripped_url(config: &Config, url: &str) -> (String, Option<bool>) {
+        let mut request = Request::get(url);
+        let stripped = strip_query_string(config, &mut request);
+        (request.get_url_str().to_string(), stripped)
+    }
+
+    #[test]
+    fn query_string_is_stripped_when_enabled() {
+        let config = Config::for_tests(&[("strip-query-string", "true")]);
+
+        assert_eq!(
+            stripped_url(&config, "https://static.crates.io/index/se/rd/serde?v=1"),
+            (
+                "https://static.crates.io/index/se/rd/serde".into(),
+                Some(true)
+            )
+        );
+        assert_eq!(
+            stripped_url(&config, "https://static.crates.io/index/se/rd/serde"),
+            (
+                "https://static.crates.io/index/se/rd/serde".into(),
+                Some(false)
+            )
+        );
+    }
+
+    #[test]
+    fn query_string_is_kept_when_disabled() {
+        let config = Config::for_tests(&[]);
+
+        assert_eq!(
+            stripped_url(&config, "https://static.crates.io/index/se/rd/serde?v=1"),
+            (
+                "https://static.crates.io/index/se/rd/serde?v=1".into(),
+                None
+            )
+        );
+        assert_eq!(
+            stripped_url(&config, "https://static.crates.io/index/se/rd/serde"),
+            ("https://static.crates.io/index/se/rd/serde".into(), None)
+        );
+    }
+
+    fn unavailable(config: &Config, backend: &mut MockBackend) -> Unavailable {
+        let failover = try_mock_hosts(config, backend, &mut request_log_line());
+
+        match failover.into_result(config) {
+            Ok(response) => panic!("host responded with {}", response.get_status()),
+            Err(unavailable) => unavailable,
+        }
+    }
+
+    #[test]
+    fn server_errors_of_all_hosts_are_unavailable() {
+        let config = failover_config(&[("primary-retries", "0"), ("retry-after", "120")]);
+        let mut backend = MockBackend::new(&[
+            (PRIMARY, &[Some(500)]),
+            (FALLBACK_1, &[Some(502)]),
+            (FALLBACK_2, &[Some(503)]),
+        ]);
+
+        let unavailable = unavailable(&config, &mut backend);
+
+        assert_eq!(unavailable.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            unavailable.error_detail,
+            "all hosts failed, last status code was 503"
+        );
+        assert_eq!(config.retry_after, 120);
+    }
+
+    #[test]
+    fn timeout_of_last_host_is_gateway_timeout() {
+        let config = failover_config(&[("primary-retries", "0")]);
+        let mut backend = MockBackend::new(&[
+            (PRIMARY, &[Some(503)]),
+            (FALLBACK_1, &[Some(503)]),
+            (FALLBACK_2, &[None]),
+        ]);
+
+        let unavailable = unavailable(&config, &mut backend);
+
+        assert_eq!(unavailable.status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            unavailable.error_detail,
+            "all hosts failed, last host timed out"
+        );
+    }
+
+    #[test]
+    fn head_is_sent_as_get_when_enabled() {
+        let config = Config::for_tests(&[("head-as-get", "true")]);
+        let request = Request::head("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+
+        let backend_request = build_backend_request(&config, &request, &config.primary_host);
+
+        assert_eq!(backend_request.get_method(), Method::GET);
+    }
+
+    #[test]
+    fn head_is_sent_as_head_by_default() {
+        let config = Config::for_tests(&[]);
+        let request = Request::head("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+
+        let backend_request = build_backend_request(&config, &request, &config.primary_host);
+
+        assert_eq!(backend_request.get_method(), Method::HEAD);
+    }
+
+    #[test]
+    fn stripped_body_keeps_content_length() {
+        let mut response = Response::new().with_header("Content-Length", "1024");
+
+        strip_body(&mut response);
+
+        assert!(response.try_take_body().is_none());
+        assert_eq!(response.get_header_str("Content-Length"), Some("1024"));
+    }
+
+    #[test]
+    fn head_is_logged_as_head() {
+        let config = Config::for_tests(&[("head-as-get", "true")]);
+        let request = Request::head("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+
+        let mut log_line = collect_request(&config, &request, "request-id", None);
+        log_line.v1.status(Some(200));
+
+        assert_eq!(json_log_line(&log_line, 1)["method"], "HEAD");
+    }
+
+    fn with_security_headers(config: &Config) -> Response {
+        let mut response = Ok(Response::new());
+        add_security_headers(config, &mut response);
+        response.unwrap()
+    }
+
+    #[test]
+    fn security_headers_are_added() {
+        let response = with_security_headers(&Config::for_tests(&[]));
+
+        assert_eq!(
+            response.get_header_str("Strict-Transport-Security"),
+            Some("max-age=31536000")
+        );
+        assert_eq!(
+            response.get_header_str("X-Content-Type-Options"),
+            Some("nosniff")
+        );
+        assert!(!response.contains_header("Referrer-Policy"));
+    }
+
+    #[test]
+    fn hsts_max_age_is_configurable() {
+        let config =
+            Config::for_tests(&[("hsts-max-age", "300"), ("referrer-policy", "no-referrer")]);
+        let response = with_security_headers(&config);
+
+        assert_eq!(
+            response.get_header_str("Strict-Transport-Security"),
+            Some("max-age=300")
+        );
+        assert_eq!(
+            response.get_header_str("Referrer-Policy"),
+            Some("no-referrer")
+        );
+    }
+
+    #[test]
+    fn cache_status_is_read_from_last_cache() {
+        for (x_cache, status) in [
+            ("HIT", "HIT"),
+            ("MISS, HIT", "HIT"),
+            ("HIT, MISS", "MISS"),
+            ("PASS", "PASS"),
+            ("HIT-STALE", "HIT"),
+        ] {
+            let response = Response::new().with_header("X-Cache", x_cache);
+            assert_eq!(cache_status(&response), status, "X-Cache: {x_cache}");
+        }
+
+        assert_eq!(cache_status(&Response::new()), "UNKNOWN");
+    }
+
+    #[test]
+    fn cache_status_is_logged() {
+        let config = Config::for_tests(&[]);
+        let response = Ok(Response::new().with_header("X-Cache", "MISS, HIT"));
+
+        let log_line = collect_response(&config, &mut request_log_line(), &response, "/");
+
+        assert_eq!(json_log_line(&log_line, 2)["cache_status"], "HIT");
+    }
+
+    fn moved_path_redirect(url: &str) -> Option<String> {
+        let config = Config::for_tests(&[(
+            "redirect-map",
+            "/old.json=/new.json,/moved=/target?source=moved",
+        )]);
+        let response = redirect_moved_paths(&config, &Request::get(url))?;
+
+        assert_eq!(response.get_status(), StatusCode::MOVED_PERMANENTLY);
+        response.get_header_str("Location").map(String::from)
+    }
+
+    #[test]
+    fn moved_path_is_redirected() {
+        assert_eq!(
+            moved_path_redirect("https://static.crates.io/old.json"),
+            Some("/new.json".into())
+        );
+    }
+
+    #[test]
+    fn other_paths_fall_through_to_s3() {
+        assert_eq!(
+            moved_path_redirect("https://static.crates.io/old.json/file"),
+            None
+        );
+        assert_eq!(
+            moved_path_redirect("https://static.crates.io/new.json"),
+            None
+        );
+    }
+
+    #[test]
+    fn moved_path_redirect_preserves_query_string() {
+        assert_eq!(
+            moved_path_redirect("https://static.crates.io/old.json?a=b"),
+            Some("/new.json?a=b".into())
+        );
+        assert_eq!(
+            moved_path_redirect("https://static.crates.io/moved?a=b"),
+            Some("/target?source=moved".into())
+        );
+    }
+
+    /// Send the request through the failover chain to a backend that always gives this response
+    fn respond_with(config: &Config, request: &Request, response: Response) -> Response {
+        let candidates = Candidates {
+            primary_host: &config.primary_host,
+            fallback_hosts: &config.fallback_hosts,
+            skip_primary: false,
+        };
+        let hosts = PrimaryWithFallback.select(request, &candidates);
+        let mut response = Some(response);
+
+        let failover = try_hosts(
+            config,
+            &candidates,
+            &hosts,
+            None,
+            &mut request_log_line(),
+            |_| Ok(response.take()),
+        );
+
+        let Ok(Failover::Responded(response)) = failover else {
+            panic!("host did not respond");
+        };
+        *response
+    }
+
+    #[test]
+    fn range_request_returns_partial_content() {
+        let config = Config::for_tests(&[]);
+        let request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("Range", "bytes=0-99");
+
+        let backend_request = build_backend_request(&config, &request, &config.primary_host);
+        assert_eq!(backend_request.get_header_str("Range"), Some("bytes=0-99"));
+
+        let mut response = respond_with(
+            &config,
+            &request,
+            Response::from_status(StatusCode::PARTIAL_CONTENT)
+                .with_header("Content-Range", "bytes 0-99/1024")
+                .with_header("Accept-Ranges", "bytes")
+                .with_header("Content-Length", "100"),
+        );
+        strip_response_headers(&config, &mut response);
+
+        assert_eq!(response.get_status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.get_header_str("Content-Range"),
+            Some("bytes 0-99/1024")
+        );
+        assert_eq!(response.get_header_str("Accept-Ranges"), Some("bytes"));
+        assert_eq!(response.get_header_str("Content-Length"), Some("100"));
+    }
+
+    fn immutable_cache_control(url: &str, status: StatusCode) -> Option<String> {
+        let config = Config::for_tests(&[
+            ("immutable-prefixes", "/crates/"),
+            ("immutable-max-age", "86400"),
+        ]);
+        let mut response = Response::from_status(status);
+
+        mark_immutable(&config, &Request::get(url), &mut response);
+        response.get_header_str("Cache-Control").map(String::from)
+    }
+
+    #[test]
+    fn tarballs_are_marked_immutable() {
+        assert_eq!(
+            immutable_cache_control(
+                "https://static.crates.io/crates/foo/foo-1.0.0.crate",
+                StatusCode::OK
+            ),
+            Some("public, max-age=86400, immutable".into())
+        );
+    }
+
+    #[test]
+    fn index_files_are_not_marked_immutable() {
+        assert_eq!(
+            immutable_cache_control("https://static.crates.io/index/se/rd/serde", StatusCode::OK),
+            None
+        );
+    }
+
+    #[test]
+    fn errors_are_not_marked_immutable() {
+        assert_eq!(
+            immutable_cache_control(
+                "https://static.crates.io/crates/foo/foo-1.0.0.crate",
+                StatusCode::NOT_FOUND
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn maintenance_mode_is_disabled_by_default() {
+        assert!(!Config::for_tests(&[]).maintenance_mode);
+    }
+
+    #[test]
+    fn maintenance_returns_service_unavailable() {
+        let config = Config::for_tests(&[("maintenance-mode", "true"), ("retry-after", "300")]);
+        assert!(config.maintenance_mode);
+
+        let response = maintenance_response(&config);
+
+        assert_eq!(response.get_status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.get_header_str("Retry-After"), Some("300"));
+    }
+
+    fn probed_cloudfront_host(config: &Config, is_healthy: bool) -> (String, bool) {
+        let mut probed = false;
+        let host = select_cloudfront_host(config, "/db-dump.tar.gz", |host, path| {
+            assert_eq!((host, path), ("cloudfront.example.com", "/db-dump.tar.gz"));
+            probed = true;
+            is_healthy
+        });
+
+        (host.to_string(), probed)
+    }
+
+    #[test]
+    fn healthy_primary_cloudfront_is_selected() {
+        let config = Config::for_tests(&[
+            (
+                "secondary-cloudfront-url",
+                "secondary.cloudfront.example.com",
+            ),
+            ("cloudfront-probe", "true"),
+        ]);
+
+        assert_eq!(
+            probed_cloudfront_host(&config, true),
+            ("cloudfront.example.com".into(), true)
+        );
+    }
+
+    #[test]
+    fn unhealthy_primary_cloudfront_falls_back_to_secondary() {
+        let config = Config::for_tests(&[
+            (
+                "secondary-cloudfront-url",
+                "secondary.cloudfront.example.com",
+            ),
+            ("cloudfront-probe", "true"),
+        ]);
+
+        assert_eq!(
+            probed_cloudfront_host(&config, false),
+            ("secondary.cloudfront.example.com".into(), true)
+        );
+    }
+
+    #[test]
+    fn cloudfront_is_not_probed_by_default() {
+        let config = Config::for_tests(&[(
+            "secondary-cloudfront-url",
+            "secondary.cloudfront.example.com",
+        )]);
+        assert_eq!(
+            probed_cloudfront_host(&config, false),
+            ("cloudfront.example.com".into(), false)
+        );
+
+        let config = Config::for_tests(&[("cloudfront-probe", "true")]);
+        assert_eq!(
+            probed_cloudfront_host(&config, false),
+            ("cloudfront.example.com".into(), false)
+        );
+    }
+
+    fn normalized_method(method: &str) -> Method {
+        let method = Method::from_bytes(method.as_bytes()).unwrap();
+        let mut request = Request::new(method, "https://static.crates.io/crates/foo");
+
+        normalize_method(&mut request);
+        request.get_method().clone()
+    }
+
+    #[test]
+    fn lowercase_methods_are_uppercased() {
+        assert_eq!(normalized_method("get"), Method::GET);
+        assert_eq!(normalized_method("Head"), Method::HEAD);
+    }
+
+    #[test]
+    fn mixed_case_purge_is_a_purge() {
+        assert_eq!(normalized_method("Purge"), "PURGE");
+    }
+
+    #[test]
+    fn unknown_methods_are_rejected_after_normalization() {
+        let config = Config::for_tests(&[]);
+        let method = normalized_method("brew");
+
+        assert_eq!(method, "BREW");
+        assert!(!config.allowed_methods.contains(&method));
+    }
+
+    fn preload_links(url: &str, status: StatusCode) -> Vec<String> {
+        let config = Config::for_tests(&[(
+            "preload-map",
+            "/index/config.json=/index/se/rd/serde /index/sy/n/syn",
+        )]);
+        let mut response = Response::from_status(status);
+
+        add_preload_links(&config, &Request::get(url), &mut response);
+        response
+            .get_header_all_str("Link")
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn preload_links_are_added_for_mapped_path() {
+        assert_eq!(
+            preload_links("https://static.crates.io/index/config.json", StatusCode::OK),
+            [
+                "</index/se/rd/serde>; rel=preload",
+                "</index/sy/n/syn>; rel=preload"
+            ]
+        );
+    }
+
+    #[test]
+    fn preload_links_are_absent_otherwise() {
+        assert!(
+            preload_links("https://static.crates.io/index/se/rd/serde", StatusCode::OK).is_empty()
+        );
+        assert!(preload_links(
+            "https://static.crates.io/index/config.json",
+            StatusCode::NOT_FOUND
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn ignored_headers_share_cache_key() {
+        let config = Config::for_tests(&[]);
+
+        let mut plain = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("Accept-Encoding", "gzip");
+        let mut other = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("Accept-Encoding", "gzip, deflate")
+            .with_header("User-Agent", "cargo 1.70.0 (ec8a8a0ca 2023-04-25)")
+            .with_header("Accept-Language", "en-US");
+
+        assert_eq!(
+            cache_key(&config, &mut plain),
+            cache_key(&config, &mut other)
+        );
+    }
+
+    #[test]
+    fn configured_headers_are_part_of_cache_key() {
+        let config = Config::for_tests(&[("cache-key-headers", "Accept-Encoding, Accept")]);
+
+        let mut json = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("Accept", "application/json");
+        let mut any = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("Accept", "*/*");
+        let mut gzip = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("Accept", "*/*")
+            .with_header("Accept-Encoding", "gzip");
+
+        let keys = [
+            cache_key(&config, &mut json),
+            cache_key(&config, &mut any),
+            cache_key(&config, &mut gzip),
+        ];
+        assert_ne!(keys[0], keys[1]);
+        assert_ne!(keys[1], keys[2]);
+    }
+
+    fn logged_client_ip(config: &Config, ip: Option<&str>) -> serde_json::Value {
+        let request = Request::get("https://static.crates.io/crates/foo");
+        let ip = ip.map(|ip| ip.parse().unwrap());
+
+        let mut log_line = collect_request(config, &request, "request-id", ip);
+        log_line.v1.status(Some(200));
+
+        json_log_line(&log_line, 1)["ip"].clone()
+    }
+
+    #[test]
+    fn ipv4_addresses_are_logged() {
+        let config = Config::for_tests(&[]);
+
+        assert_eq!(logged_client_ip(&config, Some("192.0.2.1")), "192.0.2.1");
+        assert_eq!(
+            logged_client_ip(&config, Some("::ffff:192.0.2.1")),
+            "192.0.2.1"
+        );
+    }
+
+    #[test]
+    fn ipv6_addresses_are_logged_in_lowercase() {
+        let config = Config::for_tests(&[]);
+
+        assert_eq!(
+            logged_client_ip(&config, Some("2001:DB8:0:0::ABCD")),
+            "2001:db8::abcd"
+        );
+    }
+
+    #[test]
+    fn missing_addresses_are_logged_as_unknown() {
+        let config = Config::for_tests(&[]);
+
+        assert_eq!(logged_client_ip(&config, None), "unknown");
+    }
+
+    fn host_map_config() -> Config {
+        Config::for_tests(&[(
+            "host-map",
+            "staging.crates.io=staging.s3.example.com staging-fallback.s3.example.com",
+        )])
+    }
+
+    fn mapped_primary_host(request: &Request) -> Option<String> {
+        let config = host_map_config();
+        lookup_mapped_hosts(&config, request).map(|(_, hosts)| hosts.primary_host.clone())
+    }
+
+    #[test]
+    fn forwarded_host_selects_mapped_hosts() {
+        let request = Request::get("https://static.crates.io/crates/foo")
+            .with_header("X-Forwarded-Host", "Staging.Crates.io");
+
+        assert_eq!(
+            mapped_primary_host(&request),
+            Some("staging.s3.example.com".into())
+        );
+
+        let config = host_map_config();
+        let (_, hosts) = lookup_mapped_hosts(&config, &request).unwrap();
+        assert_eq!(hosts.fallback_hosts, ["staging-fallback.s3.example.com"]);
+    }
+
+    #[test]
+    fn host_header_is_used_without_forwarded_host() {
+        let request = Request::get("https://static.crates.io/crates/foo")
+            .with_header("Host", "staging.crates.io");
+
+        assert_eq!(
+            mapped_primary_host(&request),
+            Some("staging.s3.example.com".into())
+        );
+    }
+
+    #[test]
+    fn unmapped_hosts_use_default_hosts() {
+        let request = Request::get("https://static.crates.io/crates/foo")
+            .with_header("Host", "static.crates.io");
+
+        assert_eq!(mapped_primary_host(&request), None);
+    }
+
+    #[test]
+    fn spoofed_hosts_use_default_hosts() {
+        let request = Request::get("https://static.crates.io/crates/foo")
+            .with_header("X-Forwarded-Host", "attacker.s3.example.com");
+
+        assert_eq!(mapped_primary_host(&request), None);
+    }
+
+    #[test]
+    fn mapped_hosts_have_distinct_cache_keys() {
+        let config = host_map_config();
+
+        let mut production = Request::get("https://static.crates.io/crates/foo");
+        let mut staging = Request::get("https://static.crates.io/crates/foo")
+            .with_header("X-Forwarded-Host", "staging.crates.io");
+
+        assert_ne!(
+            cache_key(&config, &mut production),
+            cache_key(&config, &mut staging)
+        );
+    }
+
+    #[test]
+    fn stdout_logger_logs_up_to_debug() {
+        let metadata = |level| {
+            Metadata::builder()
+                .level(level)
+                .target("request-logs")
+                .build()
+        };
+
+        assert!(StdoutLogger.enabled(&metadata(log::Level::Warn)));
+        assert!(StdoutLogger.enabled(&metadata(log::Level::Debug)));
+        assert!(!StdoutLogger.enabled(&metadata(log::Level::Trace)));
+    }
+
+    #[test]
+    fn stdout_logger_prints_records() {
+        StdoutLogger.log(
+            &Record::builder()
+                .level(log::Level::Info)
+                .target("request-logs")
+                .args(format_args!("logged without an endpoint"))
+                .build(),
+        );
+        StdoutLogger.flush();
+    }
+
+    fn root_redirect(config: &Config, url: &str) -> Option<String> {
+        let response = redirect_root_paths(config, &Request::get(url))?;
+
+        assert_eq!(response.get_status(), StatusCode::FOUND);
+        response.get_header_str("Location").map(String::from)
+    }
+
+    #[test]
+    fn root_redirect_targets_landing_page() {
+        let config = Config::for_tests(&[("root-redirect-url", "https://crates.io/")]);
+
+        assert_eq!(
+            root_redirect(&config, "https://static.crates.io/"),
+            Some("https://crates.io/".into())
+        );
+    }
+
+    #[test]
+    fn root_redirect_is_disabled_without_url() {
+        let config = Config::for_tests(&[]);
+
+        assert_eq!(root_redirect(&config, "https://static.crates.io/"), None);
+    }
+
+    #[test]
+    fn root_redirect_ignores_files() {
+        let config = Config::for_tests(&[
+            ("root-redirect-url", "https://crates.io/"),
+            ("root-redirect-paths", "/, /crates/"),
+        ]);
+
+        assert_eq!(
+            root_redirect(&config, "https://static.crates.io/crates/"),
+            Some("https://crates.io/".into())
+        );
+        assert_eq!(
+            root_redirect(
+                &config,
+                "https://static.crates.io/crates/foo/foo-1.0.0.crate"
+            ),
+            None
+        );
+    }
+
+    fn logged_accept(accept: &str) -> serde_json::Value {
+        let config = Config::for_tests(&[]);
+        let request =
+            Request::get("https://static.crates.io/crates/foo").with_header("Accept", accept);
+
+        let mut log_line = collect_request(&config, &request, "request-id", None);
+        let log_line = collect_response(&config, &mut log_line, &Ok(Response::new()), "/");
+
+        json_log_line(&log_line, 2)["accept"].clone()
+    }
+
+    #[test]
+    fn accept_header_is_forwarded() {
+        let config = Config::for_tests(&[]);
+        let request = Request::get("https://static.crates.io/crates/foo")
+            .with_header("Accept", "application/json");
+
+        let backend_request = build_backend_request(&config, &request, "s3.example.com");
+
+        assert_eq!(
+            backend_request.get_header_str("Accept"),
+            Some("application/json")
+        );
+    }
+
+    #[test]
+    fn accept_header_is_logged() {
+        assert_eq!(logged_accept("application/json"), "application/json");
+    }
+
+    #[test]
+    fn long_accept_header_is_truncated() {
+        let accept = "a".repeat(MAX_LOGGED_ACCEPT_LENGTH * 2);
+
+        assert_eq!(logged_accept(&accept), accept[..MAX_LOGGED_ACCEPT_LENGTH]);
+    }
+
+    fn request_with_header_bytes(size: usize) -> Request {
+        // The name of the header takes one byte
+        Request::get("https://static.crates.io/crates/foo").with_header("x", "a".repeat(size - 1))
+    }
+
+    #[test]
+    fn header_size_sums_names_and_values() {
+        let config = Config::for_tests(&[("max-header-bytes", "0")]);
+        let request = Request::get("https://static.crates.io/crates/foo")
+            .with_header("Accept", "*/*")
+            .with_header("X-Custom", "value");
+
+        assert_eq!(
+            oversized_header_size(&config, &request),
+            Some("accept*/*x-customvalue".len())
+        );
+    }
+
+    #[test]
+    fn header_size_limit_is_inclusive() {
+        let config = Config::for_tests(&[("max-header-bytes", "1024")]);
+
+        assert_eq!(
+            oversized_header_size(&config, &request_with_header_bytes(100)),
+            None
+        );
+        assert_eq!(
+            oversized_header_size(&config, &request_with_header_bytes(1024)),
+            None
+        );
+    }
+
+    #[test]
+    fn oversized_headers_are_rejected() {
+        let config = Config::for_tests(&[("max-header-bytes", "1024")]);
+
+        assert_eq!(
+            oversized_header_size(&config, &request_with_header_bytes(1025)),
+            Some(1025)
+        );
+    }
+
+    #[test]
+    fn purges_below_allowed_patterns_are_accepted() {
+        let config = Config::for_tests(&[("purge-allowed-paths", "/crates/**, /index/**")]);
+
+        assert!(is_purge_allowed(&config, "/crates/foo/foo-1.0.0.crate"));
+        assert!(is_purge_allowed(&config, "/index/se/rd/serde"));
+    }
+
+    #[test]
+    fn purges_outside_allowed_patterns_are_rejected() {
+        let config = Config::for_tests(&[("purge-allowed-paths", "/crates/**, /config.json")]);
+
+        assert!(!is_purge_allowed(&config, "/index/se/rd/serde"));
+        assert!(!is_purge_allowed(&config, "/crates"));
+        assert!(!is_purge_allowed(&config, "/config.json/foo"));
+        assert!(is_purge_allowed(&config, "/config.json"));
+    }
+
+    #[test]
+    fn purges_are_rejected_without_allowed_patterns() {
+        let config = Config::for_tests(&[]);
+
+        assert!(!is_purge_allowed(&config, "/crates/foo/foo-1.0.0.crate"));
+        assert!(!is_purge_allowed(&config, "/"));
+    }
+
+    #[test]
+    fn transient_errors_are_retried_on_primary() {
+        let config = failover_config(&[("primary-retries", "1")]);
+        let mut backend = MockBackend::new(&[(PRIMARY, &[Some(503), Some(200)])]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+
+        assert_eq!(responded_status(failover), 200);
+        assert_eq!(backend.sent, [PRIMARY, PRIMARY]);
+    }
+
+    #[test]
+    fn persistent_errors_exhaust_retries_and_fail_over() {
+        let config = failover_config(&[("primary-retries", "2")]);
+        let mut backend = MockBackend::new(&[
+            (PRIMARY, &[Some(500), Some(500), Some(500)]),
+            (FALLBACK_1, &[Some(200)]),
+        ]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+
+        assert_eq!(responded_status(failover), 200);
+        assert_eq!(backend.sent, [PRIMARY, PRIMARY, PRIMARY, FALLBACK_1]);
+    }
+
+    #[test]
+    fn not_implemented_is_not_retried() {
+        let config = failover_config(&[("primary-retries", "1")]);
+        let mut backend = MockBackend::new(&[(PRIMARY, &[Some(501), Some(200)])]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+
+        assert_eq!(responded_status(failover), 501);
+        assert_eq!(backend.sent, [PRIMARY]);
+    }
+
+    fn corrected_content_type(path: &str, status: StatusCode) -> Option<String> {
+        let config = Config::for_tests(&[]);
+        let request = Request::get(format!("https://static.crates.io{path}"));
+        let mut response =
+            Response::from_status(status).with_header("Content-Type", "application/octet-stream");
+
+        correct_content_type(&config, &request, &mut response);
+        response.get_header_str("Content-Type").map(String::from)
+    }
+
+    #[test]
+    fn content_type_of_crates_is_corrected() {
+        assert_eq!(
+            corrected_content_type("/crates/foo/foo-1.0.0.crate", StatusCode::OK),
+            Some("application/x-tar".into())
+        );
+    }
+
+    #[test]
+    fn content_type_of_json_files_is_corrected() {
+        assert_eq!(
+            corrected_content_type("/config.json", StatusCode::OK),
+            Some("application/json".into())
+        );
+    }
+
+    #[test]
+    fn content_type_of_unmapped_suffixes_is_kept() {
+        assert_eq!(
+            corrected_content_type("/index/se/rd/serde", StatusCode::OK),
+            Some("application/octet-stream".into())
+        );
+        assert_eq!(
+            corrected_content_type("/crates/foo/foo-1.0.0.crate", StatusCode::NOT_FOUND),
+            Some("application/octet-stream".into())
+        );
+    }
+
+    fn debug_request(token: Option<&str>) -> Request {
+        let mut request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate");
+        if let Some(token) = token {
+            request.set_header("X-Debug-Token", token);
+        }
+        request
+    }
+
+    #[test]
+    fn debug_requires_flag_and_token() {
+        let enabled = Config::for_tests(&[("debug-enabled", "true"), ("debug-token", "secret")]);
+        let disabled = Config::for_tests(&[("debug-enabled", "false"), ("debug-token", "secret")]);
+        let without_token = Config::for_tests(&[("debug-enabled", "true")]);
+
+        assert!(is_debug_request(
+            &enabled,
+            &mut debug_request(Some("secret"))
+        ));
+        assert!(!is_debug_request(
+            &enabled,
+            &mut debug_request(Some("wrong"))
+        ));
+        assert!(!is_debug_request(&enabled, &mut debug_request(None)));
+        assert!(!is_debug_request(
+            &disabled,
+            &mut debug_request(Some("secret"))
+        ));
+        assert!(!is_debug_request(
+            &without_token,
+            &mut debug_request(Some("secret"))
+        ));
+    }
+
+    #[test]
+    fn debug_token_is_removed_from_request() {
+        let config = Config::for_tests(&[("debug-enabled", "false")]);
+        let mut request = debug_request(Some("secret"));
+
+        is_debug_request(&config, &mut request);
+
+        assert!(request.get_header("X-Debug-Token").is_none());
+    }
+
+    #[test]
+    fn debug_response_describes_decisions() {
+        let config = failover_config(&[]);
+        let request =
+            Request::get("https://static.crates.io/crates/foo/foo-1.0.0%2Bbuild.crate?a=b");
+
+        let body = debug_decisions(
+            &config,
+            &request,
+            "/crates/foo/foo-1.0.0+build.crate",
+            Some(3600),
+            "cache-key",
+        );
+
+        assert_eq!(body["original_path"], "/crates/foo/foo-1.0.0+build.crate");
+        assert_eq!(body["path"], "/crates/foo/foo-1.0.0%2Bbuild.crate");
+        assert_eq!(body["query"], "a=b");
+        assert_eq!(body["primary_host"], config.primary_host.as_str());
+        assert_eq!(body["fallback_hosts"], json!([FALLBACK_1, FALLBACK_2]));
+        assert_eq!(body["ttl"], 3600);
+        assert_eq!(body["cache_key"], "cache-key");
+    }
+
+    #[test]
+    fn advertised_methods_match_allowlist() {
+        let config = Config::for_tests(&[
+            ("allowed-methods", "GET, HEAD, POST"),
+            ("cors-allowed-origins", "https://crates.io"),
+        ]);
+        let request = Request::new(Method::OPTIONS, "https://static.crates.io/crates/foo")
+            .with_header("Origin", "https://crates.io")
+            .with_header("Access-Control-Request-Method", "POST");
+
+        let preflight = answer_cors_preflight(&config, &request).unwrap();
+        let response = cors_response(&config, "https://crates.io");
+
+        for response in [preflight, response] {
+            let advertised: Vec<Method> = response
+                .get_header_str("Access-Control-Allow-Methods")
+                .unwrap()
+                .split(", ")
+                .map(|method| method.parse().unwrap())
+                .collect();
+            assert_eq!(advertised, config.allowed_methods);
+        }
+    }
+
+    #[test]
+    fn edge_duration_includes_backend_duration() {
+        let started_at = Instant::now();
+        let config = failover_config(&[]);
+        let mut backend = MockBackend::new(&[(PRIMARY, &[Some(200)])]);
+        backend.delay = Duration::from_millis(5);
+
+        let mut log_line = request_log_line();
+        let failover = try_mock_hosts(&config, &mut backend, &mut log_line);
+        let response = Ok(Response::from_status(responded_status(failover)));
+        let log_line = finish_log_line(&config, &mut log_line, &response, "/", started_at);
+
+        let log_line = json_log_line(&log_line, 1);
+        let backend_duration_ms = log_line["backend_duration_ms"].as_u64().unwrap();
+        let edge_duration_ms = log_line["edge_duration_ms"].as_u64().unwrap();
+        assert!(backend_duration_ms >= 5);
+        assert!(edge_duration_ms >= backend_duration_ms);
+    }
+
+    fn request_with_cookie() -> Request {
+        Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("Cookie", "session=secret")
+            .with_header("Authorization", "Bearer secret")
+            .with_header("User-Agent", "cargo 1.70.0")
+    }
+
+    #[test]
+    fn sensitive_headers_are_stripped_by_default() {
+        let config = Config::for_tests(&[]);
+        let mut request = request_with_cookie();
+
+        handle_sensitive_headers(&config, &mut request);
+
+        assert!(request.get_header("Cookie").is_none());
+        assert!(request.get_header("Authorization").is_none());
+        assert_eq!(request.get_header_str("User-Agent"), Some("cargo 1.70.0"));
+    }
+
+    #[test]
+    fn sensitive_headers_are_kept_when_bypassing_cache() {
+        let config = Config::for_tests(&[("sensitive-header-policy", "bypass")]);
+        let mut request = request_with_cookie();
+
+        handle_sensitive_headers(&config, &mut request);
+
+        assert_eq!(request.get_header_str("Cookie"), Some("session=secret"));
+        assert_eq!(
+            request.get_header_str("Authorization"),
+            Some("Bearer secret")
+        );
+    }
+
+    #[test]
+    fn requests_without_sensitive_headers_are_unchanged() {
+        let config = Config::for_tests(&[]);
+        let mut request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("User-Agent", "cargo 1.70.0");
+
+        handle_sensitive_headers(&config, &mut request);
+
+        assert_eq!(request.get_header_names_str(), ["user-agent"]);
+    }
+
+    #[test]
+    fn primary_timeout_fails_over() {
+        let config = failover_config(&[("primary-retries", "1")]);
+        let mut backend = MockBackend::new(&[(PRIMARY, &[None]), (FALLBACK_1, &[Some(200)])]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+
+        // Timeouts are not retried on the primary host
+        assert_eq!(responded_status(failover), 200);
+        assert_eq!(backend.sent, [PRIMARY, FALLBACK_1]);
+    }
+
+    #[test]
+    fn timeout_of_all_hosts_is_gateway_timeout() {
+        let config = failover_config(&[]);
+        let mut backend = MockBackend::new(&[
+            (PRIMARY, &[None]),
+            (FALLBACK_1, &[None]),
+            (FALLBACK_2, &[None]),
+        ]);
+
+        let unavailable = unavailable(&config, &mut backend);
+
+        assert_eq!(unavailable.status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(backend.sent, [PRIMARY, FALLBACK_1, FALLBACK_2]);
+    }
+
+    #[test]
+    fn pop_is_logged_when_available() {
+        let config = Config::for_tests(&[]);
+        let request = Request::get("https://static.crates.io/crates/foo");
+        let logged_pop = || {
+            let mut log_line = collect_request(&config, &request, "request-id", None);
+            let log_line = collect_response(&config, &mut log_line, &Ok(Response::new()), "/");
+            json_log_line(&log_line, 2)["pop"].clone()
+        };
+
+        // No other test checks the POP, so changing the variable doesn't affect them
+        std::env::set_var("FASTLY_POP", "FRA");
+        assert_eq!(logged_pop(), "FRA");
+
+        std::env::set_var("FASTLY_POP", "");
+        assert!(logged_pop().is_null());
+
+        std::env::remove_var("FASTLY_POP");
+        assert!(logged_pop().is_null());
+    }
+
+    #[test]
+    fn configured_robots_txt_is_served() {
+        let config = Config::for_tests(&[("robots-txt", "User-agent: *\nDisallow: /")]);
+        let request = Request::get("https://static.crates.io/robots.txt");
+
+        assert_eq!(
+            robots_txt_body(&config, &request).map(String::as_str),
+            Some("User-agent: *\nDisallow: /")
+        );
+    }
+
+    #[test]
+    fn robots_txt_falls_through_without_body() {
+        let config = Config::for_tests(&[]);
+        let request = Request::get("https://static.crates.io/robots.txt");
+
+        assert_eq!(robots_txt_body(&config, &request), None);
+    }
+
+    #[test]
+    fn robots_txt_body_is_only_served_for_its_path() {
+        let config = Config::for_tests(&[("robots-txt", "User-agent: *")]);
+        let request = Request::get("https://static.crates.io/crates/robots.txt");
+
+        assert_eq!(robots_txt_body(&config, &request), None);
+    }
+
+    fn well_known_config() -> Config {
+        Config::for_tests(&[
+            ("well-known-files", "security.txt"),
+            (
+                "well-known-file-security.txt",
+                "Contact: mailto:security@example.com",
+            ),
+        ])
+    }
+
+    #[test]
+    fn configured_well_known_file_is_served() {
+        let config = well_known_config();
+        let request = Request::get("https://static.crates.io/.well-known/security.txt");
+
+        assert_eq!(
+            well_known_file_body(&config, &request).map(String::as_str),
+            Some("Contact: mailto:security@example.com\n")
+        );
+    }
+
+    #[test]
+    fn unknown_well_known_files_fall_through() {
+        let config = well_known_config();
+        let request = Request::get("https://static.crates.io/.well-known/change-password");
+
+        assert_eq!(well_known_file_body(&config, &request), None);
+    }
+
+    #[test]
+    fn well_known_file_is_only_served_for_its_path() {
+        let config = well_known_config();
+        let request = Request::get("https://static.crates.io/security.txt");
+
+        assert_eq!(well_known_file_body(&config, &request), None);
+    }
+
+    fn logged_statuses(backend: &mut MockBackend) -> serde_json::Value {
+        let config = failover_config(&[("primary-retries", "0")]);
+        let mut log_line = request_log_line();
+
+        let failover = try_mock_hosts(&config, backend, &mut log_line);
+        let response = Ok(Response::from_status(responded_status(failover)));
+        let log_line = collect_response(&config, &mut log_line, &response, "/");
+
+        json_log_line(&log_line, 2)
+    }
+
+    #[test]
+    fn statuses_of_primary_and_fallback_are_logged() {
+        let mut backend = MockBackend::new(&[(PRIMARY, &[Some(503)]), (FALLBACK_1, &[Some(200)])]);
+
+        let log_line = logged_statuses(&mut backend);
+
+        assert_eq!(log_line["primary_status"], 503);
+        assert_eq!(log_line["final_status"], 200);
+        assert_eq!(log_line["used_fallback"], true);
+    }
+
+    #[test]
+    fn status_of_successful_primary_is_final_status() {
+        let mut backend = MockBackend::new(&[(PRIMARY, &[Some(200)])]);
+
+        let log_line = logged_statuses(&mut backend);
+
+        assert_eq!(log_line["primary_status"], 200);
+        assert_eq!(log_line["final_status"], 200);
+        assert_eq!(log_line["used_fallback"], false);
+    }
+
+    #[test]
+    fn responses_above_size_threshold_are_oversized() {
+        let config = Config::for_tests(&[("size-warn-threshold", "1000")]);
+        let response = Response::new().with_header("Content-Length", "1001");
+
+        assert_eq!(oversized_content_length(&config, &response), Some(1001));
+    }
+
+    #[test]
+    fn responses_within_size_threshold_are_not_oversized() {
+        let config = Config::for_tests(&[("size-warn-threshold", "1000")]);
+
+        for response in [
+            Response::new().with_header("Content-Length", "1000"),
+            Response::new(),
+        ] {
+            assert_eq!(oversized_content_length(&config, &response), None);
+        }
+    }
+
+    #[test]
+    fn size_threshold_is_disabled_by_default() {
+        let config = Config::for_tests(&[]);
+        let response = Response::new().with_header("Content-Length", "1000000000");
+
+        assert_eq!(oversized_content_length(&config, &response), None);
+    }
+
+    fn synthesized_etag(path: &str, mut response: Response) -> Option<String> {
+        let request = Request::get(format!("https://static.crates.io{path}"));
+
+        synthesize_etag(&request, &mut response);
+        response.get_header_str("ETag").map(String::from)
+    }
+
+    fn uploaded_file(content_length: &str) -> Response {
+        Response::new()
+            .with_header("Content-Length", content_length)
+            .with_header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+    }
+
+    #[test]
+    fn missing_etag_is_synthesized_deterministically() {
+        let path = "/crates/foo/foo-1.0.0.crate";
+
+        let etag = synthesized_etag(path, uploaded_file("42")).unwrap();
+
+        assert!(etag.starts_with("W/\""));
+        assert_eq!(
+            synthesized_etag(path, uploaded_file("42")),
+            Some(etag.clone())
+        );
+        assert_ne!(
+            synthesized_etag(path, uploaded_file("43")),
+            Some(etag.clone())
+        );
+        assert_ne!(
+            synthesized_etag("/crates/bar/bar-1.0.0.crate", uploaded_file("42")),
+            Some(etag)
+        );
+    }
+
+    #[test]
+    fn etag_from_s3_is_preserved() {
+        let response = uploaded_file("42").with_header("ETag", "\"abc\"");
+
+        assert_eq!(
+            synthesized_etag("/crates/foo/foo-1.0.0.crate", response),
+            Some("\"abc\"".into())
+        );
+    }
+
+    #[test]
+    fn etag_is_not_synthesized_for_errors() {
+        let response = Response::from_status(StatusCode::NOT_FOUND);
+
+        assert_eq!(
+            synthesized_etag("/crates/foo/foo-1.0.0.crate", response),
+            None
+        );
+    }
+
+    fn with_maintenance_notice(config: &Config) -> Response {
+        let mut response = Ok(Response::from_status(StatusCode::OK)
+            .with_header("Content-Type", "application/x-tar")
+            .with_header("Content-Length", "42"));
+        add_maintenance_notice(config, &mut response);
+        response.unwrap()
+    }
+
+    #[test]
+    fn maintenance_notice_is_added_when_configured() {
+        let config = Config::for_tests(&[("maintenance-notice", "Maintenance on Monday")]);
+
+        let response = with_maintenance_notice(&config);
+
+        assert_eq!(
+            response.get_header_str("X-Maintenance-Notice"),
+            Some("Maintenance on Monday")
+        );
+        assert_eq!(response.get_status(), StatusCode::OK);
+        assert_eq!(response.get_content_length(), Some(42));
+    }
+
+    #[test]
+    fn maintenance_notice_is_omitted_without_config() {
+        let response = with_maintenance_notice(&Config::for_tests(&[]));
+
+        assert!(!response.contains_header("X-Maintenance-Notice"));
+    }
+
+    fn empty_response() -> Response {
+        Response::from_status(StatusCode::OK).with_header("Content-Length", "0")
+    }
+
+    #[test]
+    fn empty_success_is_detected() {
+        assert!(is_empty_success(&empty_response()));
+        assert!(!is_empty_success(
+            &Response::from_status(StatusCode::OK).with_header("Content-Length", "42")
+        ));
+        assert!(!is_empty_success(
+            &Response::from_status(StatusCode::NOT_FOUND).with_header("Content-Length", "0")
+        ));
+        assert!(!is_empty_success(&Response::from_status(StatusCode::OK)));
+    }
+
+    #[test]
+    fn empty_responses_are_only_logged_by_default() {
+        let config = Config::for_tests(&[]);
+
+        assert!(!rejects_empty_response(&config, &empty_response()));
+    }
+
+    #[test]
+    fn empty_responses_are_rejected_when_enabled() {
+        let config = Config::for_tests(&[("reject-empty-responses", "true")]);
+
+        assert!(rejects_empty_response(&config, &empty_response()));
+        assert!(!rejects_empty_response(
+            &config,
+            &Response::from_status(StatusCode::OK).with_header("Content-Length", "42")
+        ));
+    }
+
+    #[test]
+    fn gets_with_body_are_rejected_when_enabled() {
+        let config = Config::for_tests(&[("reject-bodied-gets", "true")]);
+
+        for request in [
+            Request::get("https://static.crates.io/crates/foo").with_header("Content-Length", "42"),
+            Request::head("https://static.crates.io/crates/foo")
+                .with_header("Transfer-Encoding", "chunked"),
+        ] {
+            assert!(rejects_request_body(&config, &request));
+        }
+    }
+
+    #[test]
+    fn gets_without_body_are_allowed() {
+        let config = Config::for_tests(&[("reject-bodied-gets", "true")]);
+
+        for request in [
+            Request::get("https://static.crates.io/crates/foo"),
+            Request::get("https://static.crates.io/crates/foo").with_header("Content-Length", "0"),
+            Request::new("PURGE", "https://static.crates.io/crates/foo")
+                .with_header("Content-Length", "42"),
+        ] {
+            assert!(!rejects_request_body(&config, &request));
+        }
+    }
+
+    #[test]
+    fn gets_with_body_are_allowed_by_default() {
+        let config = Config::for_tests(&[]);
+        let request =
+            Request::get("https://static.crates.io/crates/foo").with_header("Content-Length", "42");
+
+        assert!(!rejects_request_body(&config, &request));
+    }
+
+    fn canonical_host_redirect(config: &Config, host: &str) -> Option<String> {
+        let request = Request::get(format!("https://{host}/crates/foo/foo-1.0.0.crate?a=b"))
+            .with_header("Host", host);
+        let response = redirect_to_canonical_host(config, &request)?;
+
+        assert_eq!(response.get_status(), StatusCode::MOVED_PERMANENTLY);
+        response.get_header_str("Location").map(String::from)
+    }
+
+    #[test]
+    fn other_hosts_are_redirected_to_canonical_host() {
+        let config = Config::for_tests(&[("canonical-host", "static.crates.io")]);
+
+        assert_eq!(
+            canonical_host_redirect(&config, "crates-static.example.com"),
+            Some("https://static.crates.io/crates/foo/foo-1.0.0.crate?a=b".into())
+        );
+    }
+
+    #[test]
+    fn canonical_host_is_not_redirected() {
+        let config = Config::for_tests(&[("canonical-host", "static.crates.io")]);
+
+        assert_eq!(canonical_host_redirect(&config, "static.crates.io"), None);
+        assert_eq!(canonical_host_redirect(&config, "Static.Crates.IO"), None);
+    }
+
+    #[test]
+    fn hosts_are_not_redirected_without_canonical_host() {
+        let config = Config::for_tests(&[]);
+
+        assert_eq!(
+            canonical_host_redirect(&config, "crates-static.example.com"),
+            None
+        );
+    }
+
+    #[test]
+    fn mapped_hosts_are_not_redirected() {
+        let config = Config::for_tests(&[
+            ("canonical-host", "static.crates.io"),
+            ("host-map", "staging.crates.io=staging.s3.example.com"),
+        ]);
+
+        assert_eq!(canonical_host_redirect(&config, "staging.crates.io"), None);
+    }
+
+    fn range_request() -> Request {
+        Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("Range", "bytes=100-")
+            .with_header("If-Range", "\"abc\"")
+    }
+
+    #[test]
+    fn if_range_is_forwarded_with_range() {
+        let config = Config::for_tests(&[]);
+
+        let backend_request = build_backend_request(&config, &range_request(), "s3.example.com");
+
+        assert_eq!(backend_request.get_header_str("Range"), Some("bytes=100-"));
+        assert_eq!(backend_request.get_header_str("If-Range"), Some("\"abc\""));
+    }
+
+    #[test]
+    fn matching_if_range_passes_partial_content_through() {
+        let response =
+            Response::from_status(StatusCode::PARTIAL_CONTENT).with_header("ETag", "\"abc\"");
+
+        assert!(if_range_matches(&range_request(), &response));
+    }
+
+    #[test]
+    fn failed_if_range_passes_full_file_through() {
+        let response = Response::from_status(StatusCode::OK).with_header("ETag", "\"def\"");
+
+        assert!(if_range_matches(&range_request(), &response));
+    }
+
+    #[test]
+    fn partial_content_of_changed_file_fetches_full_file() {
+        let response =
+            Response::from_status(StatusCode::PARTIAL_CONTENT).with_header("ETag", "\"def\"");
+
+        assert!(!if_range_matches(&range_request(), &response));
+
+        let full_request = without_range(&range_request());
+        assert!(!full_request.contains_header("Range"));
+        assert!(!full_request.contains_header("If-Range"));
+    }
+
+    #[test]
+    fn range_responses_do_not_fail_over() {
+        for status in [206, 200] {
+            let mut backend = MockBackend::new(&[(PRIMARY, &[Some(status)])]);
+
+            let log_line = logged_statuses(&mut backend);
+
+            assert_eq!(log_line["final_status"], status);
+            assert_eq!(backend.sent, [PRIMARY]);
+        }
+    }
+
+    fn preflight_allowed_headers(config: &Config, requested: &str) -> Option<String> {
+        let request = Request::new(Method::OPTIONS, "https://static.crates.io/crates/foo")
+            .with_header("Origin", "https://crates.io")
+            .with_header("Access-Control-Request-Method", "GET")
+            .with_header("Access-Control-Request-Headers", requested);
+
+        let response = answer_cors_preflight(config, &request).unwrap();
+        response
+            .get_header_str("Access-Control-Allow-Headers")
+            .map(String::from)
+    }
+
+    #[test]
+    fn allowed_request_headers_are_echoed() {
+        let config = Config::for_tests(&[("cors-allowed-headers", "Range, If-None-Match")]);
+
+        assert_eq!(
+            preflight_allowed_headers(&config, "range, if-none-match"),
+            Some("range, if-none-match".into())
+        );
+    }
+
+    #[test]
+    fn disallowed_request_headers_are_omitted() {
+        let config = Config::for_tests(&[("cors-allowed-headers", "Range")]);
+
+        assert_eq!(
+            preflight_allowed_headers(&config, "Range, X-Custom"),
+            Some("Range".into())
+        );
+        assert_eq!(preflight_allowed_headers(&config, "X-Custom"), None);
+    }
+
+    #[test]
+    fn request_headers_are_omitted_by_default() {
+        let config = Config::for_tests(&[]);
+
+        assert_eq!(preflight_allowed_headers(&config, "Range"), None);
+    }
+
+    fn backend_host_header(config: &Config, backend: &str) -> Option<String> {
+        let request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("Host", "static.crates.io");
+
+        let backend_request = build_backend_request(config, &request, backend);
+        backend_request.get_header_str("Host").map(String::from)
+    }
+
+    #[test]
+    fn host_header_is_overridden_per_backend() {
+        let config = failover_config(&[(
+            "backend-host-headers",
+            "primary.s3.example.com=primary.s3.amazonaws.com, \
+             fallback-1.s3.example.com=fallback.s3.amazonaws.com",
+        )]);
+
+        assert_eq!(
+            backend_host_header(&config, PRIMARY),
+            Some("primary.s3.amazonaws.com".into())
+        );
+        assert_eq!(
+            backend_host_header(&config, FALLBACK_1),
+            Some("fallback.s3.amazonaws.com".into())
+        );
+        assert_eq!(
+            backend_host_header(&config, FALLBACK_2),
+            Some("static.crates.io".into())
+        );
+    }
+
+    #[test]
+    fn host_header_is_unchanged_without_override() {
+        let config = failover_config(&[]);
+
+        assert_eq!(
+            backend_host_header(&config, PRIMARY),
+            Some("static.crates.io".into())
+        );
+    }
+
+    #[test]
+    fn invalid_host_header_is_ignored() {
+        let config =
+            failover_config(&[("backend-host-headers", "primary.s3.example.com=bad\nhost")]);
+
+        assert_eq!(
+            backend_host_header(&config, PRIMARY),
+            Some("static.crates.io".into())
+        );
+    }
+
+    #[test]
+    fn ips_in_deny_list_are_denied() {
+        let config = Config::for_tests(&[("ip-deny-list", "192.0.2.0/24, 2001:db8::/32")]);
+
+        assert!(is_denied_ip(&config, "192.0.2.7".parse().unwrap()));
+        assert!(is_denied_ip(&config, "2001:db8::7".parse().unwrap()));
+        assert!(!is_denied_ip(&config, "198.51.100.7".parse().unwrap()));
+        assert!(!is_denied_ip(&config, "2001:db9::7".parse().unwrap()));
+    }
+
+    #[test]
+    fn masked_ipv4_addresses_zero_last_octet() {
+        assert_eq!(
+            mask_ip("192.0.2.123".parse().unwrap()),
+            "192.0.2.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            mask_ip("::ffff:192.0.2.123".parse().unwrap()),
+            "192.0.2.0".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn masked_ipv6_addresses_zero_last_80_bits() {
+        assert_eq!(
+            mask_ip("2001:db8:1234:5678:9abc:def0:1234:5678".parse().unwrap()),
+            "2001:db8:1234::".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn ips_are_logged_according_to_mode() {
+        let full = Config::for_tests(&[("log-ip-mode", "full")]);
+        let masked = Config::for_tests(&[("log-ip-mode", "masked")]);
+        let none = Config::for_tests(&[("log-ip-mode", "none")]);
+
+        assert_eq!(logged_client_ip(&full, Some("192.0.2.123")), "192.0.2.123");
+        assert_eq!(logged_client_ip(&masked, Some("192.0.2.123")), "192.0.2.0");
+        assert_eq!(logged_client_ip(&masked, None), "unknown");
+        assert!(logged_client_ip(&none, Some("192.0.2.123")).is_null());
+    }
+
+    #[test]
+    fn saturated_fallback_is_unavailable() {
+        let config = failover_config(&[
+            ("primary-retries", "0"),
+            ("fallback-limit-store", "fallback-limits"),
+            ("fallback-limit", "1"),
+        ]);
+        let mut backend = MockBackend::new(&[
+            (PRIMARY, &[Some(503), Some(503)]),
+            (FALLBACK_1, &[Some(200)]),
+        ]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+        assert_eq!(responded_status(failover), 200);
+
+        let unavailable = unavailable(&config, &mut backend);
+        assert_eq!(unavailable.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            unavailable.error_detail,
+            "primary host failed, fallback cap reached"
+        );
+        assert_eq!(backend.sent, [PRIMARY, FALLBACK_1, PRIMARY]);
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_not_forwarded() {
+        let config = Config::for_tests(&[]);
+        let mut request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("User-Agent", "cargo 1.70.0")
+            .with_header("Accept-Encoding", "gzip");
+        for header in HOP_BY_HOP_HEADERS {
+            request.set_header(header, "value");
+        }
+
+        let backend_request = build_backend_request(&config, &request, "s3.example.com");
+
+        for header in HOP_BY_HOP_HEADERS {
+            assert!(!backend_request.contains_header(header), "header: {header}");
+        }
+        assert_eq!(
+            backend_request.get_header_str("User-Agent"),
+            Some("cargo 1.70.0")
+        );
+        assert_eq!(
+            backend_request.get_header_str("Accept-Encoding"),
+            Some("gzip")
+        );
+    }
+
+    #[test]
+    fn headers_listed_in_connection_are_not_forwarded() {
+        let mut request = Request::get("https://static.crates.io/crates/foo/foo-1.0.0.crate")
+            .with_header("Connection", "close, X-Custom, in valid")
+            .with_header("X-Custom", "value")
+            .with_header("X-Other", "value");
+
+        strip_hop_by_hop_headers(&mut request);
+
+        assert!(!request.contains_header("Connection"));
+        assert!(!request.contains_header("X-Custom"));
+        assert_eq!(request.get_header_str("X-Other"), Some("value"));
+    }
+
+    fn with_response_headers(config: &Config, response: Response) -> Response {
+        let mut response = Ok(response);
+        add_response_headers(config, &mut response);
+        response.unwrap()
+    }
+
+    #[test]
+    fn configured_headers_are_added_to_successful_responses() {
+        let config = Config::for_tests(&[("response-headers", "X-Robots-Tag: noindex")]);
+
+        let response = with_response_headers(&config, Response::new());
+        assert_eq!(response.get_header_str("X-Robots-Tag"), Some("noindex"));
+
+        let response = with_response_headers(&config, Response::from_status(StatusCode::NOT_FOUND));
+        assert!(!response.contains_header("X-Robots-Tag"));
+    }
+
+    #[test]
+    fn configured_headers_never_replace_other_headers() {
+        let config = Config::for_tests(&[
+            ("cors-allowed-origins", "https://crates.io"),
+            (
+                "response-headers",
+                "Vary: X-Custom, Strict-Transport-Security: max-age=0",
+            ),
+        ]);
+
+        let mut response = Ok(Response::new());
+        add_security_headers(&config, &mut response);
+        add_cors_headers(&config, &mut response, "https://crates.io");
+        add_response_headers(&config, &mut response);
+        let response = response.unwrap();
+
+        assert_ne!(
+            response.get_header_str("Strict-Transport-Security"),
+            Some("max-age=0")
+        );
+        assert_eq!(response.get_header_str("Vary"), Some("Origin"));
+        assert_eq!(
+            response.get_header_str("Access-Control-Allow-Origin"),
+            Some("https://crates.io")
+        );
+    }
+
+    fn advertised_ranges(path: &str, mut response: Response) -> Option<String> {
+        let config = Config::for_tests(&[("large-object-prefixes", "/db-dump, /crates/")]);
+        let request = Request::get(format!("https://static.crates.io{path}"));
+
+        advertise_byte_ranges(&config, &request, &mut response);
+        response.get_header_str("Accept-Ranges").map(String::from)
+    }
+
+    #[test]
+    fn large_objects_advertise_byte_ranges() {
+        assert_eq!(
+            advertised_ranges("/db-dump.tar.gz", Response::new()),
+            Some("bytes".into())
+        );
+    }
+
+    #[test]
+    fn declared_ranges_are_kept() {
+        assert_eq!(
+            advertised_ranges(
+                "/db-dump.tar.gz",
+                Response::new().with_header("Accept-Ranges", "none")
+            ),
+            Some("none".into())
+        );
+        assert_eq!(
+            advertised_ranges(
+                "/crates/foo/foo-1.0.0.crate",
+                Response::new().with_header("Accept-Ranges", "bytes")
+            ),
+            Some("bytes".into())
+        );
+    }
+
+    #[test]
+    fn other_objects_and_errors_do_not_advertise_ranges() {
+        assert_eq!(
+            advertised_ranges("/index/se/rd/serde", Response::new()),
+            None
+        );
+        assert_eq!(
+            advertised_ranges(
+                "/db-dump.tar.gz",
+                Response::from_status(StatusCode::NOT_FOUND)
+            ),
+            None
+        );
+    }
+
+    fn logged_paths(url: &str) -> (serde_json::Value, serde_json::Value) {
+        let config = Config::for_tests(&[]);
+        let mut request = Request::get(url);
+
+        let mut log_line = collect_request(&config, &request, "request-id", None);
+        rewrite_path(&mut request, &mut log_line);
+        let log_line = collect_response(&config, &mut log_line, &Ok(Response::new()), "/");
+
+        let log_line = json_log_line(&log_line, 2);
+        (
+            log_line["original_path"].clone(),
+            log_line["final_path"].clone(),
+        )
+    }
+
+    #[test]
+    fn rewritten_paths_are_logged_next_to_original_path() {
+        assert_eq!(
+            logged_paths("https://static.crates.io/crates/foo/foo-1.0.0+build.crate"),
+            (
+                "/crates/foo/foo-1.0.0+build.crate".into(),
+                "/crates/foo/foo-1.0.0%2Bbuild.crate".into()
+            )
+        );
+    }
+
+    #[test]
+    fn canonical_paths_are_logged_unchanged() {
+        assert_eq!(
+            logged_paths("https://static.crates.io/crates/foo/foo-1.0.0.crate"),
+            (
+                "/crates/foo/foo-1.0.0.crate".into(),
+                "/crates/foo/foo-1.0.0.crate".into()
+            )
+        );
+    }
+
+    #[test]
+    fn header_counts_are_logged() {
+        let config = Config::for_tests(&[]);
+        let request = Request::get("https://static.crates.io/crates/foo")
+            .with_header("Accept", "*/*")
+            .with_header("User-Agent", "cargo 1.70.0")
+            .with_header("X-Custom", "value");
+        let response = Ok(Response::new()
+            .with_header("Content-Type", "application/x-tar")
+            .with_header("ETag", "\"abc\""));
+
+        let mut log_line = collect_request(&config, &request, "request-id", None);
+        let log_line = collect_response(&config, &mut log_line, &response, "/");
+
+        let log_line = json_log_line(&log_line, 2);
+        assert_eq!(log_line["request_header_count"], 3);
+        assert_eq!(log_line["response_header_count"], 2);
+    }
+
+    #[test]
+    fn response_header_count_excludes_stripped_headers() {
+        let config = Config::for_tests(&[("stripped-response-headers", "x-amz-")]);
+        let mut response = Response::new()
+            .with_header("Content-Type", "application/x-tar")
+            .with_header("x-amz-request-id", "abc")
+            .with_header("x-amz-id-2", "def");
+        strip_response_headers(&config, &mut response);
+
+        let log_line = collect_response(&config, &mut request_log_line(), &Ok(response), "/");
+
+        assert_eq!(json_log_line(&log_line, 2)["response_header_count"], 1);
+    }
+
+    fn purge_with_content_length(content_length: &str) -> Request {
+        Request::new("PURGE", "https://static.crates.io/crates/foo")
+            .with_header("Content-Length", content_length)
+    }
+
+    #[test]
+    fn small_purge_bodies_are_allowed() {
+        let config = Config::for_tests(&[("max-purge-body-bytes", "16")]);
+
+        for content_length in ["0", "16"] {
+            let size = declared_body_size(&purge_with_content_length(content_length));
+            assert!(size.unwrap() <= config.max_purge_body_bytes);
+        }
+    }
+
+    #[test]
+    fn oversized_purge_bodies_are_rejected() {
+        let config = Config::for_tests(&[("max-purge-body-bytes", "16")]);
+
+        let size = declared_body_size(&purge_with_content_length("17"));
+
+        assert!(size.unwrap() > config.max_purge_body_bytes);
+    }
+
+    #[test]
+    fn invalid_content_length_reads_body() {
+        assert_eq!(declared_body_size(&purge_with_content_length("many")), None);
+        assert_eq!(
+            declared_body_size(&Request::new("PURGE", "https://static.crates.io/")),
+            None
+        );
+    }
+
+    fn logged_ttl(config: &Config, path: &str) -> (serde_json::Value, serde_json::Value) {
+        let mut request = Request::get(format!("https://static.crates.io{path}"));
+        let mut log_line = request_log_line();
+
+        let ttl = set_ttl(config, &mut request, &mut log_line);
+        let log_line = collect_response(config, &mut log_line, &Ok(Response::new()), path);
+
+        let log_line = json_log_line(&log_line, 2);
+        assert_eq!(log_line["ttl_applied"], json!(ttl));
+        (
+            log_line["ttl_applied"].clone(),
+            log_line["ttl_rule"].clone(),
+        )
+    }
+
+    #[test]
+    fn default_ttl_is_logged() {
+        let config = Config::for_tests(&[("static-ttl", "3600")]);
+
+        assert_eq!(
+            logged_ttl(&config, "/crates/foo/foo-1.0.0.crate"),
+            (3600.into(), "default".into())
+        );
+    }
+
+    #[test]
+    fn ttl_override_is_logged_with_its_prefix() {
+        let config = Config::for_tests(&[
+            ("ttl-overrides", "/index/=60, /index/se/=30"),
+            ("hot-paths", "/index/config.json"),
+            ("hot-path-ttl", "5"),
+        ]);
+
+        assert_eq!(
+            logged_ttl(&config, "/index/se/rd/serde"),
+            (30.into(), "/index/se/".into())
+        );
+        assert_eq!(
+            logged_ttl(&config, "/index/config.json"),
+            (5.into(), "hot-path".into())
+        );
+    }
+
+    #[test]
+    fn failover_statuses_fail_over() {
+        let config = failover_config(&[("primary-retries", "0")]);
+        let mut backend = MockBackend::new(&[(PRIMARY, &[Some(503)]), (FALLBACK_1, &[Some(200)])]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+
+        assert_eq!(responded_status(failover), 200);
+        assert_eq!(backend.sent, [PRIMARY, FALLBACK_1]);
+    }
+
+    #[test]
+    fn other_server_errors_are_returned_directly() {
+        let config = failover_config(&[("primary-retries", "0")]);
+        let mut backend = MockBackend::new(&[(PRIMARY, &[Some(501)])]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+        let response = failover.into_result(&config).ok().unwrap();
+
+        assert_eq!(response.get_status(), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(backend.sent, [PRIMARY]);
+    }
+
+    #[test]
+    fn failover_statuses_can_be_configured() {
+        let config = failover_config(&[
+            ("primary-retries", "0"),
+            ("failover-status-codes", "501, 503"),
+        ]);
+        let mut backend = MockBackend::new(&[(PRIMARY, &[Some(501)]), (FALLBACK_1, &[Some(502)])]);
+
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+
+        assert_eq!(config.failover_status_codes, [501, 503]);
+        assert_eq!(responded_status(failover), 502);
+        assert_eq!(backend.sent, [PRIMARY, FALLBACK_1]);
+    }
+
+    fn logged_user_agent(user_agent: Option<&str>) -> (serde_json::Value, serde_json::Value) {
+        let config = Config::for_tests(&[]);
+        let mut request = Request::get("https://static.crates.io/crates/foo");
+        if let Some(user_agent) = user_agent {
+            request.set_header("User-Agent", user_agent);
+        }
+
+        let mut log_line = collect_request(&config, &request, "request-id", None);
+        let log_line = collect_response(&config, &mut log_line, &Ok(Response::new()), "/");
+
+        let log_line = json_log_line(&log_line, 2);
+        (
+            log_line["user_agent"].clone(),
+            log_line["cargo_version"].clone(),
+        )
+    }
+
+    #[test]
+    fn cargo_user_agent_is_logged_with_version() {
+        assert_eq!(
+            logged_user_agent(Some("cargo/1.75.0 (1d8b05cdd 2023-11-20)")),
+            (
+                "cargo/1.75.0 (1d8b05cdd 2023-11-20)".into(),
+                "1.75.0".into()
+            )
+        );
+    }
+
+    #[test]
+    fn missing_user_agent_is_logged_as_null() {
+        let (user_agent, cargo_version) = logged_user_agent(None);
+
+        assert!(user_agent.is_null());
+        assert!(cargo_version.is_null());
+    }
+
+    #[test]
+    fn other_user_agents_have_no_cargo_version() {
+        assert_eq!(
+            logged_user_agent(Some("curl/8.4.0")),
+            ("curl/8.4.0".into(), serde_json::Value::Null)
+        );
+        assert_eq!(cargo_version("cargo/<script>"), None);
+    }
+
+    #[test]
+    fn malicious_user_agents_are_normalized() {
+        assert_eq!(
+            normalize_user_agent("cargo/1.75.0\n{\"injected\":true}\u{7f}"),
+            "cargo/1.75.0{\"injected\":true}"
+        );
+
+        let oversized = "a".repeat(MAX_LOGGED_USER_AGENT_LENGTH * 2);
+        assert_eq!(
+            logged_user_agent(Some(&oversized)).0,
+            oversized[..MAX_LOGGED_USER_AGENT_LENGTH]
+        );
+    }
+
+    #[test]
+    fn user_agent_is_not_logged_when_disabled() {
+        let config = Config::for_tests(&[("log-user-agent", "false")]);
+        let request = Request::get("https://static.crates.io/crates/foo")
+            .with_header("User-Agent", "cargo/1.75.0");
+
+        let mut log_line = collect_request(&config, &request, "request-id", None);
+        let log_line = collect_response(&config, &mut log_line, &Ok(Response::new()), "/");
+
+        assert!(json_log_line(&log_line, 2)["user_agent"].is_null());
+    }
+
+    fn gone_config() -> Config {
+        Config::for_tests(&[
+            (
+                "gone-paths",
+                "/crates/evil/evil-1.0.0.crate, /crates/removed/**",
+            ),
+            ("gone-ttl", "86400"),
+        ])
+    }
+
+    #[test]
+    fn exact_gone_paths_are_gone() {
+        assert!(is_gone_path(
+            &gone_config(),
+            "/crates/evil/evil-1.0.0.crate"
+        ));
+        assert!(!is_gone_path(
+            &gone_config(),
+            "/crates/evil/evil-1.0.1.crate"
+        ));
+    }
+
+    #[test]
+    fn paths_below_gone_prefixes_are_gone() {
+        assert!(is_gone_path(
+            &gone_config(),
+            "/crates/removed/removed-0.1.0.crate"
+        ));
+        assert!(!is_gone_path(
+            &gone_config(),
+            "/crates/removed-not/foo.crate"
+        ));
+    }
+
+    #[test]
+    fn other_paths_fall_through() {
+        assert!(!is_gone_path(&gone_config(), "/crates/foo/foo-1.0.0.crate"));
+        assert!(!is_gone_path(
+            &Config::for_tests(&[]),
+            "/crates/evil/evil-1.0.0.crate"
+        ));
+    }
+
+    #[test]
+    fn gone_responses_are_cacheable() {
+        let response = gone_response(&gone_config());
+
+        assert_eq!(response.get_status(), StatusCode::GONE);
+        assert_eq!(
+            response.get_header_str("Cache-Control"),
+            Some("public, max-age=86400")
+        );
+        assert_eq!(
+            response.get_header_str("Surrogate-Control"),
+            Some("max-age=86400")
+        );
+    }
+
+    /// Try the hosts with a fallback before the primary host, like the nearest region does
+    fn try_reordered_hosts(config: &Config, backend: &mut MockBackend) -> serde_json::Value {
+        let candidates = Candidates {
+            primary_host: &config.primary_host,
+            fallback_hosts: &config.fallback_hosts,
+            skip_primary: false,
+        };
+        let hosts = [
+            &config.fallback_hosts[0],
+            &config.primary_host,
+            &config.fallback_hosts[1],
+        ];
+        let mut log_line = request_log_line();
+
+        let failover = try_hosts(config, &candidates, &hosts, None, &mut log_line, |host| {
+            backend.send(host)
+        })
+        .unwrap();
+        let response = Ok(Response::from_status(responded_status(failover)));
+        let log_line = collect_response(config, &mut log_line, &response, "/");
+
+        json_log_line(&log_line, 2)
+    }
+
+    #[test]
+    fn reordered_fallback_is_not_the_primary() {
+        let config = failover_config(&[("primary-retries", "1")]);
+        let mut backend = MockBackend::new(&[(FALLBACK_1, &[Some(200)])]);
+
+        let log_line = try_reordered_hosts(&config, &mut backend);
+
+        assert_eq!(backend.sent, [FALLBACK_1]);
+        assert_eq!(log_line["backend_host"], FALLBACK_1);
+        assert_eq!(log_line["used_fallback"], true);
+        assert!(log_line["primary_status"].is_null());
+    }
+
+    #[test]
+    fn reordered_primary_is_retried_and_logged() {
+        let config = failover_config(&[("primary-retries", "1")]);
+        let mut backend = MockBackend::new(&[
+            (FALLBACK_1, &[Some(503)]),
+            (PRIMARY, &[Some(503), Some(200)]),
+        ]);
+
+        let log_line = try_reordered_hosts(&config, &mut backend);
+
+        assert_eq!(backend.sent, [FALLBACK_1, PRIMARY, PRIMARY]);
+        assert_eq!(log_line["backend_host"], PRIMARY);
+        assert_eq!(log_line["used_fallback"], false);
+        assert_eq!(log_line["primary_status"], 200);
+    }
+
+    #[test]
+    fn reordered_primary_ignores_fallback_cap() {
+        let config = failover_config(&[
+            ("primary-retries", "0"),
+            ("fallback-limit-store", "fallback-limits"),
+            ("fallback-limit", "1"),
+        ]);
+        let mut backend = MockBackend::new(&[
+            (PRIMARY, &[Some(503), Some(200)]),
+            (FALLBACK_1, &[Some(200), Some(503)]),
+        ]);
+
+        // Use up the fallback cap with a request that fails over
+        let failover = try_mock_hosts(&config, &mut backend, &mut request_log_line());
+        assert_eq!(responded_status(failover), 200);
+
+        let log_line = try_reordered_hosts(&config, &mut backend);
+
+        assert_eq!(backend.sent, [PRIMARY, FALLBACK_1, FALLBACK_1, PRIMARY]);
+        assert_eq!(log_line["final_status"], 200);
+    }
+
+    #[test]
+    fn sampling_depends_on_salt() {
+        let unsalted = Config::for_tests(&[("log-sample-rate", "0.5")]);
+        let salted =
+            Config::for_tests(&[("log-sample-rate", "0.5"), ("log-sample-salt", "secret")]);
+        let response = Ok(Response::from_status(StatusCode::OK));
+
+        let differs = (0..100).any(|index| {
+            let id = format!("request-id-{index}");
+            is_sampled(&unsalted, &id, &response) != is_sampled(&salted, &id, &response)
+        });
+
+        assert!(differs);
+    }
 }
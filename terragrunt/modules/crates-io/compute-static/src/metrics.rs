@@ -0,0 +1,118 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use log::{info, warn};
+
+use crate::config::Config;
+
+// The names of the counters are stable, since dashboards depend on them
+
+/// A request to a host in S3 failed with a server error or timed out
+pub const BACKEND_FAILURE: &str = "backend.failure";
+/// A response was served from a fallback host
+pub const BACKEND_FALLBACK: &str = "backend.fallback";
+/// A request was rejected because of its method
+pub const REQUEST_REJECTED_METHOD: &str = "request.rejected.method";
+/// A response was served from Fastly's cache
+pub const CACHE_HIT: &str = "cache.hit";
+
+thread_local! {
+    // Every instance of the function handles a single request, so the counters belong to it
+    static COUNTERS: RefCell<BTreeMap<&'static str, u64>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Increment a counter
+pub fn increment(counter: &'static str) {
+    COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        let count = counters.entry(counter).or_default();
+        *count = count.saturating_add(1);
+    });
+}
+
+/// Get the counter for the class of a status code
+///
+/// The counters are named `response.status.1xx` to `response.status.5xx`.
+pub fn status_class_counter(status: u16) -> &'static str {
+    match status {
+        100..=199 => "response.status.1xx",
+        200..=299 => "response.status.2xx",
+        300..=399 => "response.status.3xx",
+        400..=499 => "response.status.4xx",
+        _ => "response.status.5xx",
+    }
+}
+
+/// Send the counters to the metrics endpoint
+///
+/// The Compute@Edge SDK does not support custom counters in Fastly's real-time stats. Instead, the
+/// counters of a request are collected in memory and sent as a single line of JSON to a dedicated
+/// logging endpoint, which feeds them into the dashboards without parsing the request logs.
+///
+/// The counters are reset afterwards. Nothing is sent if no endpoint has been configured, or if no
+/// counter has been incremented.
+pub fn flush(config: &Config) {
+    let counters = COUNTERS.with(|counters| std::mem::take(&mut *counters.borrow_mut()));
+
+    let Some(endpoint) = &config.metrics_endpoint else {
+        return;
+    };
+
+    if counters.is_empty() {
+        return;
+    }
+
+    match serde_json::to_string(&counters) {
+        Ok(json_line) => info!(target: endpoint, "{json_line}"),
+        Err(error) => warn!("failed to serialize metrics: {error}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counters() -> BTreeMap<&'static str, u64> {
+        COUNTERS.with(|counters| counters.borrow().clone())
+    }
+
+    #[test]
+    fn increment_counts_per_counter() {
+        increment(BACKEND_FAILURE);
+        increment(BACKEND_FAILURE);
+        increment(CACHE_HIT);
+
+        assert_eq!(
+            counters(),
+            BTreeMap::from([(BACKEND_FAILURE, 2), (CACHE_HIT, 1)])
+        );
+    }
+
+    #[test]
+    fn increment_saturates() {
+        COUNTERS.with(|counters| counters.borrow_mut().insert(BACKEND_FALLBACK, u64::MAX));
+
+        increment(BACKEND_FALLBACK);
+
+        assert_eq!(counters()[BACKEND_FALLBACK], u64::MAX);
+    }
+
+    #[test]
+    fn status_codes_are_counted_by_class() {
+        assert_eq!(status_class_counter(101), "response.status.1xx");
+        assert_eq!(status_class_counter(200), "response.status.2xx");
+        assert_eq!(status_class_counter(304), "response.status.3xx");
+        assert_eq!(status_class_counter(404), "response.status.4xx");
+        assert_eq!(status_class_counter(503), "response.status.5xx");
+        assert_eq!(status_class_counter(600), "response.status.5xx");
+    }
+
+    #[test]
+    fn flush_resets_counters() {
+        increment(REQUEST_REJECTED_METHOD);
+
+        flush(&Config::for_tests(&[]));
+
+        assert!(counters().is_empty());
+    }
+}
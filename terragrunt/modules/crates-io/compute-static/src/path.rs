@@ -0,0 +1,301 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+
+/// Decode percent-encoded characters
+///
+/// Sequences that are not valid percent-encodings are kept as they are.
+pub fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match decode_escape(bytes, index) {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    decoded
+}
+
+/// Canonicalize the percent-encoding of a path for S3
+///
+/// The same key in S3 can be requested with different encodings of the path, for example `~` and
+/// `%7E`, or `+` and `%2B`. The canonical form decodes unreserved characters, and encodes every
+/// other character with uppercase hexadecimal digits. Slashes are kept as they are, whether they
+/// are encoded or not, since they separate the segments of the path. Valid escape sequences are
+/// decoded before they are encoded again, which means that already encoded paths are never encoded
+/// twice.
+///
+/// Percent signs that are not part of a valid escape sequence are encoded as `%25`. Keeping them
+/// would make the function unstable, since decoding a character can create a new escape sequence
+/// with a preceding percent sign, for example `%%41a` to `%Aa`. Canonicalizing a canonical path
+/// therefore always returns the same path.
+///
+/// The path is processed as raw bytes, so arbitrary sequences never cause a panic. Paths that only
+/// contain unreserved characters and slashes, which includes most paths of crates and index files,
+/// are returned without allocating.
+pub fn canonicalize(path: &str) -> Cow<'_, str> {
+    let bytes = path.as_bytes();
+
+    if bytes
+        .iter()
+        .all(|&byte| is_unreserved(byte) || byte == b'/')
+    {
+        return Cow::Borrowed(path);
+    }
+
+    let mut canonical = String::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let (byte, escaped) = match decode_escape(bytes, index) {
+            Some(byte) => (byte, true),
+            None => (bytes[index], false),
+        };
+
+        if is_unreserved(byte) || (!escaped && byte == b'/') {
+            canonical.push(char::from(byte));
+        } else {
+            // Writing to a string never fails
+            let _ = write!(canonical, "%{byte:02X}");
+        }
+
+        index += if escaped { 3 } else { 1 };
+    }
+
+    Cow::Owned(canonical)
+}
+
+/// Check if the decoded path is valid UTF-8
+///
+/// Percent-encodings can represent arbitrary bytes, which are not always valid UTF-8.
+pub fn decodes_to_utf8(path: &str) -> bool {
+    !path.contains('%') || std::str::from_utf8(&percent_decode(path)).is_ok()
+}
+
+/// Check if the decoded path contains a `..` segment
+///
+/// Backslashes are treated as separators as well, since some systems resolve them like slashes.
+pub fn has_traversal_segment(path: &str) -> bool {
+    percent_decode(path)
+        .split(|&byte| matches!(byte, b'/' | b'\\'))
+        .any(|segment| segment == b"..")
+}
+
+/// Decode the escape sequence at the given index, if there is a valid one
+fn decode_escape(bytes: &[u8], index: usize) -> Option<u8> {
+    if bytes.get(index) != Some(&b'%') {
+        return None;
+    }
+
+    let hex = bytes.get(index + 1..index + 3)?;
+    if !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+
+    u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+/// Check if a character is unreserved and never needs to be encoded
+///
+/// See more: https://www.rfc-editor.org/rfc/rfc3986#section-2.3
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of random paths that the properties are checked against
+    const ITERATIONS: usize = 20_000;
+
+    /// Characters that are likely to hit edge cases, which are mixed with arbitrary bytes
+    const INTERESTING: &[&str] = &[
+        "%", "%2", "%2B", "%2b", "%2F", "%25", "%7E", "%C3%A9", "%FF", "%zz", "+", "/", "//", "\\",
+        "..", "~", " ", "é", "🦀", "a", "Z", "9",
+    ];
+
+    /// Generator for pseudo-random paths with the xorshift algorithm
+    ///
+    /// The seed is fixed, so that failures can be reproduced.
+    struct Paths(u64);
+
+    impl Paths {
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn next_path(&mut self) -> String {
+            let length = self.next_u64() % 16;
+            let mut bytes = Vec::new();
+
+            for _ in 0..length {
+                let choice = self.next_u64();
+                if choice.is_multiple_of(2) {
+                    let index = (choice >> 1) as usize % INTERESTING.len();
+                    bytes.extend_from_slice(INTERESTING[index].as_bytes());
+                } else {
+                    bytes.push((choice >> 8) as u8);
+                }
+            }
+
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+    }
+
+    fn for_random_paths(check: impl Fn(&str)) {
+        let mut paths = Paths(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..ITERATIONS {
+            check(&paths.next_path());
+        }
+    }
+
+    #[test]
+    fn canonicalize_is_idempotent() {
+        for_random_paths(|path| {
+            let canonical = canonicalize(path);
+            assert_eq!(canonicalize(&canonical), canonical, "path: {path:?}");
+        });
+    }
+
+    #[test]
+    fn canonicalize_preserves_decoded_path() {
+        for_random_paths(|path| {
+            let canonical = canonicalize(path);
+            assert_eq!(
+                percent_decode(&canonical),
+                percent_decode(path),
+                "path: {path:?}"
+            );
+        });
+    }
+
+    #[test]
+    fn canonicalize_returns_ascii() {
+        for_random_paths(|path| assert!(canonicalize(path).is_ascii(), "path: {path:?}"));
+    }
+
+    #[test]
+    fn checks_never_panic() {
+        for_random_paths(|path| {
+            decodes_to_utf8(path);
+            has_traversal_segment(path);
+        });
+    }
+
+    #[test]
+    fn canonicalize_encodes_plus() {
+        assert_eq!(
+            canonicalize("/crates/foo/foo-1.0.0+build.crate"),
+            "/crates/foo/foo-1.0.0%2Bbuild.crate"
+        );
+        assert_eq!(
+            canonicalize("/crates/foo/foo-1.0.0%2bbuild.crate"),
+            "/crates/foo/foo-1.0.0%2Bbuild.crate"
+        );
+    }
+
+    #[test]
+    fn canonicalize_decodes_unreserved_characters() {
+        assert_eq!(canonicalize("/%7Efoo/%41"), "/~foo/A");
+    }
+
+    #[test]
+    fn canonicalize_keeps_encoded_slashes() {
+        assert_eq!(canonicalize("/a%2fb/c"), "/a%2Fb/c");
+    }
+
+    #[test]
+    fn canonicalize_encodes_stray_percent_signs() {
+        assert_eq!(canonicalize("/%%41a"), "/%25Aa");
+        assert_eq!(canonicalize("/100%"), "/100%25");
+    }
+
+    #[test]
+    fn canonicalize_borrows_unreserved_paths() {
+        assert!(matches!(
+            canonicalize("/index/se/rd/serde"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn percent_decode_keeps_invalid_sequences() {
+        assert_eq!(percent_decode("%41%zz%4"), b"A%zz%4");
+    }
+
+    #[test]
+    fn canonicalize_encodes_spaces() {
+        assert_eq!(canonicalize("/crates/foo bar"), "/crates/foo%20bar");
+        assert_eq!(canonicalize("/crates/foo%20bar"), "/crates/foo%20bar");
+    }
+
+    #[test]
+    fn canonicalize_keeps_tildes() {
+        assert_eq!(canonicalize("/crates/~foo"), "/crates/~foo");
+    }
+
+    #[test]
+    fn canonicalize_keeps_encoded_paths() {
+        for path in [
+            "/crates/foo/foo-1.0.0%2Bbuild.crate",
+            "/crates/caf%C3%A9",
+            "/index/se/rd/serde",
+        ] {
+            assert_eq!(canonicalize(path), path);
+        }
+    }
+
+    #[test]
+    fn decodes_to_utf8_accepts_valid_paths() {
+        assert!(decodes_to_utf8("/crates/foo/foo-1.0.0.crate"));
+        assert!(decodes_to_utf8("/caf%C3%A9"));
+    }
+
+    #[test]
+    fn decodes_to_utf8_keeps_invalid_percent_encodings() {
+        assert!(decodes_to_utf8("/100%zz"));
+        assert_eq!(canonicalize("/100%zz"), "/100%25zz");
+    }
+
+    #[test]
+    fn decodes_to_utf8_rejects_invalid_bytes() {
+        assert!(!decodes_to_utf8("/caf%FF"));
+        assert!(!decodes_to_utf8("/%C3"));
+        assert_eq!(canonicalize("/caf%ff"), "/caf%FF");
+    }
+
+    #[test]
+    fn traversal_segments_are_found() {
+        assert!(has_traversal_segment("/crates/../secret"));
+        assert!(has_traversal_segment("/crates/.."));
+        assert!(has_traversal_segment("/crates\\..\\secret"));
+    }
+
+    #[test]
+    fn encoded_traversal_segments_are_found() {
+        assert!(has_traversal_segment("/crates/%2e%2e/secret"));
+        assert!(has_traversal_segment("/crates/.%2E/secret"));
+        assert!(has_traversal_segment("/crates/..%2Fsecret"));
+    }
+
+    #[test]
+    fn dots_in_file_names_are_allowed() {
+        assert!(!has_traversal_segment("/crates/foo/foo..bar-1.0.0.crate"));
+        assert!(!has_traversal_segment("/crates/.../foo"));
+        assert!(!has_traversal_segment("/crates/./foo"));
+    }
+}
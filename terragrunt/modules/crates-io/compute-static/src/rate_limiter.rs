@@ -0,0 +1,181 @@
+use std::net::IpAddr;
+
+use time::OffsetDateTime;
+
+use crate::config::{Config, RateLimit};
+use crate::shared_state::{SharedState, Stored};
+
+/// Token bucket of a client
+///
+/// The bucket is saved as a string in the format `<tokens>,<unix timestamp of the last update>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_at: i64,
+}
+
+impl Stored for Bucket {
+    fn parse(value: &str) -> Option<Self> {
+        let (tokens, updated_at) = value.split_once(',')?;
+
+        Some(Self {
+            tokens: tokens.parse().ok()?,
+            updated_at: updated_at.parse().ok()?,
+        })
+    }
+
+    fn serialize(&self) -> String {
+        format!("{},{}", self.tokens, self.updated_at)
+    }
+}
+
+impl Bucket {
+    pub fn full(limit: RateLimit, now: i64) -> Self {
+        Self {
+            tokens: f64::from(limit.requests),
+            updated_at: now,
+        }
+    }
+
+    /// Refill the bucket for the time that has passed, and take a token if there is one
+    ///
+    /// The bucket holds at most the number of requests of the limit, and is refilled evenly over
+    /// the window of the limit. Returns the new bucket and whether a token was taken.
+    pub fn take(self, limit: RateLimit, now: i64) -> (Self, bool) {
+        let capacity = f64::from(limit.requests);
+        let refill_per_second = capacity / f64::from(limit.window.max(1));
+        let elapsed = now.saturating_sub(self.updated_at).max(0) as f64;
+
+        let tokens = (self.tokens + elapsed * refill_per_second).min(capacity);
+
+        if tokens >= 1.0 {
+            let bucket = Self {
+                tokens: tokens - 1.0,
+                updated_at: now,
+            };
+            (bucket, true)
+        } else {
+            let bucket = Self {
+                tokens,
+                updated_at: now,
+            };
+            (bucket, false)
+        }
+    }
+}
+
+/// Check the rate limit of a client for a class of paths
+///
+/// Cacheable files are served from Fastly's cache, which can handle any volume of traffic. Paths
+/// that always reach the service, like purges and health checks, are limited per client IP with a
+/// token bucket. The buckets are shared between all instances of the function, so clients might
+/// get a few more requests through than the limit allows.
+///
+/// Returns the number of seconds after which the client can retry if it has been limited.
+pub fn check(config: &Config, class: &str, ip: Option<IpAddr>) -> Option<u32> {
+    let store_name = config.rate_limit_store.as_ref()?;
+    let limit = *config.rate_limits.get(class)?;
+    let ip = ip?;
+
+    if limit.requests == 0 {
+        return None;
+    }
+
+    let mut store = SharedState::open(store_name, "the rate limiter")?;
+
+    let key = format!("{class}/{}", ip.to_canonical());
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    let (bucket, allowed) = store
+        .load(&key)
+        .unwrap_or_else(|| Bucket::full(limit, now))
+        .take(limit, now);
+
+    store.save(&key, &bucket);
+
+    if allowed {
+        None
+    } else {
+        // The time until the next token is added to the bucket
+        Some(limit.window.max(1).div_ceil(limit.requests))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        requests: 2,
+        window: 60,
+    };
+
+    fn client() -> Option<IpAddr> {
+        Some("192.0.2.1".parse().unwrap())
+    }
+
+    #[test]
+    fn bucket_takes_tokens_until_empty() {
+        let bucket = Bucket::full(LIMIT, 0);
+
+        let (bucket, allowed) = bucket.take(LIMIT, 0);
+        assert!(allowed);
+        let (bucket, allowed) = bucket.take(LIMIT, 0);
+        assert!(allowed);
+        let (_, allowed) = bucket.take(LIMIT, 0);
+        assert!(!allowed);
+    }
+
+    #[test]
+    fn bucket_is_refilled_over_window() {
+        let empty = Bucket {
+            tokens: 0.0,
+            updated_at: 0,
+        };
+
+        assert!(!empty.take(LIMIT, 29).1);
+        assert!(empty.take(LIMIT, 30).1);
+
+        let (refilled, _) = empty.take(LIMIT, 600);
+        assert_eq!(refilled.tokens, 1.0);
+    }
+
+    #[test]
+    fn bucket_round_trips_through_store() {
+        let bucket = Bucket {
+            tokens: 1.5,
+            updated_at: 1_700_000_000,
+        };
+
+        assert_eq!(Bucket::parse(&bucket.serialize()), Some(bucket));
+        assert_eq!(Bucket::parse("1.5"), None);
+        assert_eq!(Bucket::parse("many,1700000000"), None);
+    }
+
+    #[test]
+    fn clients_over_limit_must_retry_later() {
+        let config = Config::for_tests(&[
+            ("rate-limit-store", "rate-limits"),
+            ("rate-limits", "purge=2/60"),
+        ]);
+
+        assert_eq!(check(&config, "purge", client()), None);
+        assert_eq!(check(&config, "purge", client()), None);
+        assert_eq!(check(&config, "purge", client()), Some(30));
+
+        // Other clients and classes have their own buckets
+        assert_eq!(
+            check(&config, "purge", Some("192.0.2.2".parse().unwrap())),
+            None
+        );
+        assert_eq!(check(&config, "health-check", client()), None);
+    }
+
+    #[test]
+    fn clients_are_not_limited_without_store() {
+        let config = Config::for_tests(&[("rate-limits", "purge=1/60")]);
+
+        assert_eq!(check(&config, "purge", client()), None);
+        assert_eq!(check(&config, "purge", client()), None);
+    }
+}
@@ -0,0 +1,92 @@
+#[cfg(not(test))]
+use fastly::object_store::ObjectStore;
+#[cfg(not(test))]
+use log::warn;
+
+/// Value that is saved as a string in an object store
+pub trait Stored: Sized {
+    fn parse(value: &str) -> Option<Self>;
+
+    fn serialize(&self) -> String;
+}
+
+/// State that is shared between all instances of the function
+///
+/// Instances of the function don't share any memory, so state that must outlive a request, like
+/// counters and timestamps, is saved in an object store. The object store has no atomic operations
+/// and is eventually consistent. Concurrent requests in other locations can overwrite each other's
+/// updates, and they can see an update a few requests late. Features that are built on shared state
+/// must tolerate this, for example by treating their limits as soft limits.
+///
+/// Errors of the object store are logged, and never fail the request.
+pub struct SharedState {
+    #[cfg(not(test))]
+    store: ObjectStore,
+    #[cfg(not(test))]
+    purpose: &'static str,
+    #[cfg(test)]
+    store: String,
+}
+
+#[cfg(not(test))]
+impl SharedState {
+    /// Open the object store with the given name
+    ///
+    /// The purpose describes the feature that uses the state in warnings. Returns `None` if the
+    /// object store does not exist or cannot be opened.
+    pub fn open(name: &str, purpose: &'static str) -> Option<Self> {
+        match ObjectStore::open(name) {
+            Ok(Some(store)) => Some(Self { store, purpose }),
+            Ok(None) => {
+                warn!("object store {name} for {purpose} does not exist");
+                None
+            }
+            Err(error) => {
+                warn!("failed to open object store for {purpose}: {error}");
+                None
+            }
+        }
+    }
+
+    /// Load the value under the given key
+    ///
+    /// Returns `None` if the value is missing, cannot be read, or is malformed.
+    pub fn load<T: Stored>(&self, key: &str) -> Option<T> {
+        self.store
+            .lookup(key)
+            .ok()
+            .flatten()
+            .and_then(|body| T::parse(&body.into_string()))
+    }
+
+    /// Save the value under the given key
+    pub fn save<T: Stored>(&mut self, key: &str, value: &T) {
+        if let Err(error) = self.store.insert(key, value.serialize()) {
+            warn!("failed to save {key} for {}: {error}", self.purpose);
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static STORES: std::cell::RefCell<std::collections::HashMap<(String, String), String>> =
+        std::cell::RefCell::default();
+}
+
+/// Object stores are not available in tests, so the state is kept in memory of the current thread
+#[cfg(test)]
+impl SharedState {
+    pub fn open(name: &str, _purpose: &'static str) -> Option<Self> {
+        Some(Self { store: name.into() })
+    }
+
+    pub fn load<T: Stored>(&self, key: &str) -> Option<T> {
+        let key = (self.store.clone(), key.to_string());
+        STORES.with(|stores| T::parse(stores.borrow().get(&key)?))
+    }
+
+    pub fn save<T: Stored>(&mut self, key: &str, value: &T) {
+        let key = (self.store.clone(), key.to_string());
+        STORES.with(|stores| stores.borrow_mut().insert(key, value.serialize()));
+    }
+}
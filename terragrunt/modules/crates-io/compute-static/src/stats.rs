@@ -0,0 +1,302 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use log::{info, warn};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::config::Config;
+use crate::log_line::{Envelope, LogLine};
+use crate::shared_state::{SharedState, Stored};
+
+// Key in the object store under which the aggregated latencies are saved
+const STATS_KEY: &str = "backend-latency";
+
+/// Aggregated latencies of requests to the backend
+///
+/// The aggregate is saved as a string in the format `<count>,<sum>,<min>,<max>`, with all
+/// durations in milliseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Aggregate {
+    pub request_count: u64,
+    pub sum_ms: u64,
+    pub min_ms: u64,
+    pub max_ms: u64,
+}
+
+impl Stored for Aggregate {
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(',').map(|part| part.parse().ok());
+
+        let aggregate = Self {
+            request_count: parts.next()??,
+            sum_ms: parts.next()??,
+            min_ms: parts.next()??,
+            max_ms: parts.next()??,
+        };
+
+        match parts.next() {
+            Some(_) => None,
+            None => Some(aggregate),
+        }
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "{},{},{},{}",
+            self.request_count, self.sum_ms, self.min_ms, self.max_ms
+        )
+    }
+}
+
+impl Aggregate {
+    /// Add the duration of a sample to the aggregate
+    ///
+    /// Each sample stands for the given number of requests, which are counted with the same
+    /// duration.
+    pub fn record(self, duration_ms: u64, request_count: u64) -> Self {
+        let sum_ms = duration_ms.saturating_mul(request_count);
+
+        if self.request_count == 0 {
+            return Self {
+                request_count,
+                sum_ms,
+                min_ms: duration_ms,
+                max_ms: duration_ms,
+            };
+        }
+
+        Self {
+            request_count: self.request_count.saturating_add(request_count),
+            sum_ms: self.sum_ms.saturating_add(sum_ms),
+            min_ms: self.min_ms.min(duration_ms),
+            max_ms: self.max_ms.max(duration_ms),
+        }
+    }
+
+    pub fn avg_ms(&self) -> u64 {
+        self.sum_ms
+            .checked_div(self.request_count)
+            .unwrap_or_default()
+    }
+}
+
+/// Log line with the latencies of the backend since the last stats line
+#[derive(Debug, Serialize)]
+pub struct StatsLine {
+    #[serde(with = "time::serde::rfc3339")]
+    date_time: OffsetDateTime,
+    request_count: u64,
+    min_backend_duration_ms: u64,
+    max_backend_duration_ms: u64,
+    avg_backend_duration_ms: u64,
+}
+
+impl From<Aggregate> for StatsLine {
+    fn from(aggregate: Aggregate) -> Self {
+        Self {
+            date_time: OffsetDateTime::now_utc(),
+            request_count: aggregate.request_count,
+            min_backend_duration_ms: aggregate.min_ms,
+            max_backend_duration_ms: aggregate.max_ms,
+            avg_backend_duration_ms: aggregate.avg_ms(),
+        }
+    }
+}
+
+/// Record the duration of a request to the backend
+///
+/// The aggregate is shared between all instances of the function. Once the configured number of
+/// requests has been recorded, a stats line is sent to the service logs and the aggregate is reset.
+///
+/// The aggregate is saved under a single key, and the object store only supports a low rate of
+/// writes to the same key. Recording every request would get most writes throttled, so only one
+/// in `stats_sample_interval` requests is picked at random and recorded for all of them.
+pub fn record_backend_duration(config: &Config, duration_ms: u64) {
+    let sample_interval = config.stats_sample_interval.max(1);
+
+    if sample_interval > 1 && !random().is_multiple_of(u64::from(sample_interval)) {
+        return;
+    }
+
+    record_sample(config, duration_ms, u64::from(sample_interval));
+}
+
+/// Record a sample of the backend durations
+///
+/// The sample stands for the given number of requests. The counts of the stats are estimates, since
+/// they are extrapolated from the samples. Concurrent samples in other locations can also overwrite
+/// each other's updates, and then go missing from the stats.
+fn record_sample(config: &Config, duration_ms: u64, request_count: u64) {
+    let Some(store_name) = config.stats_store.as_ref() else {
+        return;
+    };
+
+    if config.stats_interval == 0 {
+        return;
+    }
+
+    let Some(mut store) = SharedState::open(store_name, "the stats") else {
+        return;
+    };
+
+    let aggregate = store
+        .load::<Aggregate>(STATS_KEY)
+        .unwrap_or_default()
+        .record(duration_ms, request_count);
+
+    let aggregate = if aggregate.request_count >= u64::from(config.stats_interval) {
+        match Envelope::from(LogLine::Stats(aggregate.into())).to_json_line() {
+            Ok(json_line) => info!(target: &config.service_logs_endpoint, "{json_line}"),
+            Err(error) => warn!("failed to serialize stats: {error}"),
+        }
+
+        Aggregate::default()
+    } else {
+        aggregate
+    };
+
+    store.save(STATS_KEY, &aggregate);
+}
+
+/// Generate a random number with the randomly seeded hasher of the standard library
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregate(durations: &[u64]) -> Aggregate {
+        durations
+            .iter()
+            .fold(Aggregate::default(), |aggregate, duration| {
+                aggregate.record(*duration, 1)
+            })
+    }
+
+    #[test]
+    fn aggregate_tracks_count_sum_min_and_max() {
+        assert_eq!(
+            aggregate(&[40, 10, 100, 30]),
+            Aggregate {
+                request_count: 4,
+                sum_ms: 180,
+                min_ms: 10,
+                max_ms: 100,
+            }
+        );
+    }
+
+    #[test]
+    fn first_duration_sets_min() {
+        assert_eq!(aggregate(&[25]).min_ms, 25);
+    }
+
+    #[test]
+    fn average_is_rounded_down() {
+        assert_eq!(aggregate(&[40, 10, 100, 30]).avg_ms(), 45);
+        assert_eq!(aggregate(&[1, 2]).avg_ms(), 1);
+    }
+
+    #[test]
+    fn average_of_empty_aggregate_is_zero() {
+        assert_eq!(Aggregate::default().avg_ms(), 0);
+    }
+
+    #[test]
+    fn sum_saturates() {
+        assert_eq!(aggregate(&[u64::MAX, 1]).sum_ms, u64::MAX);
+    }
+
+    #[test]
+    fn aggregate_round_trips_through_store() {
+        let aggregate = aggregate(&[40, 10, 100, 30]);
+
+        assert_eq!(aggregate.serialize(), "4,180,10,100");
+        assert_eq!(Aggregate::parse(&aggregate.serialize()), Some(aggregate));
+    }
+
+    #[test]
+    fn malformed_aggregate_is_ignored() {
+        for value in ["", "4,180,10", "4,180,10,100,1", "a,180,10,100"] {
+            assert_eq!(Aggregate::parse(value), None, "value: {value:?}");
+        }
+    }
+
+    #[test]
+    fn stats_line_reports_aggregate() {
+        let line = StatsLine::from(aggregate(&[40, 10, 100, 30]));
+
+        assert_eq!(line.request_count, 4);
+        assert_eq!(line.min_backend_duration_ms, 10);
+        assert_eq!(line.max_backend_duration_ms, 100);
+        assert_eq!(line.avg_backend_duration_ms, 45);
+    }
+
+    #[test]
+    fn samples_count_for_multiple_requests() {
+        let aggregate = Aggregate::default().record(40, 10).record(10, 10);
+
+        assert_eq!(
+            aggregate,
+            Aggregate {
+                request_count: 20,
+                sum_ms: 500,
+                min_ms: 10,
+                max_ms: 40,
+            }
+        );
+        assert_eq!(aggregate.avg_ms(), 25);
+    }
+
+    #[test]
+    fn samples_are_reset_after_interval() {
+        let config = Config::for_tests(&[("stats-store", "stats"), ("stats-interval", "20")]);
+        let store = SharedState::open("stats", "the stats").unwrap();
+
+        record_sample(&config, 10, 10);
+        assert_eq!(
+            store.load(STATS_KEY),
+            Some(Aggregate::default().record(10, 10))
+        );
+
+        record_sample(&config, 20, 10);
+        assert_eq!(store.load(STATS_KEY), Some(Aggregate::default()));
+    }
+
+    #[test]
+    fn only_some_requests_are_sampled() {
+        let config = Config::for_tests(&[
+            ("stats-store", "stats"),
+            ("stats-interval", "1000000"),
+            ("stats-sample-interval", "10"),
+        ]);
+        let store = SharedState::open("stats", "the stats").unwrap();
+
+        for _ in 0..1000 {
+            record_backend_duration(&config, 10);
+        }
+
+        let aggregate = store.load::<Aggregate>(STATS_KEY).unwrap();
+        assert_eq!(aggregate.request_count % 10, 0);
+        assert!(aggregate.request_count > 0 && aggregate.request_count < 10_000);
+    }
+
+    #[test]
+    fn aggregate_is_reset_after_interval() {
+        let config = Config::for_tests(&[
+            ("stats-store", "stats"),
+            ("stats-interval", "2"),
+            ("stats-sample-interval", "1"),
+        ]);
+        let store = SharedState::open("stats", "the stats").unwrap();
+
+        record_backend_duration(&config, 10);
+        assert_eq!(store.load(STATS_KEY), Some(aggregate(&[10])));
+
+        record_backend_duration(&config, 20);
+        assert_eq!(store.load(STATS_KEY), Some(Aggregate::default()));
+    }
+}
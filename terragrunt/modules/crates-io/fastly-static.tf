@@ -96,6 +96,7 @@ resource "fastly_service_dictionary_items" "compute_static" {
   manage_items  = true
 
   items = {
+    "config-version" : "2"
     "cloudfront-url" : local.cloudfront_domain_name
     "s3-primary-host" : local.primary_host_name
     "s3-fallback-host" : local.fallback_host_name